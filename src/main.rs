@@ -2,13 +2,47 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
 use portable_pty::{native_pty_system, CommandBuilder, MasterPty, PtySize};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{Read, Write};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::sync::{Arc, Mutex, PoisonError};
-use tauri::{Manager, State, Window};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager, State, Window};
+use termos::{
+    find_project_file, search_tabs, AppError, AppState, BundleSection, ChoiceCache, CommandButton,
+    CommandReview, ConfigBundle, ConfigDiff, Discovery, DiscoveryCache, ImportItem,
+    ImportResolution, ImportSummary, Job, JobManager, JobOutputLine, Keybindings, LineLinks,
+    MacroAction, MacroExecutor, OutputStream, PaletteMatch, Profile, Project, Scrollback,
+    SearchQuery, SearchResults, Shell, SpawnRequest, SplitDirection, TabContext, TabSearchResults,
+    TargetResolution, Template, TemplateVariable, TerminalConfig, TerminalTheme, ThemeFormat,
+    UsageState, VariablePrompt,
+};
 use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::oneshot;
+
+/// How often the config file is checked for changes
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest a template `from` command may run
+const CHOICE_COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the output of a template `from` command is reused
+const CHOICE_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// How often tabs are checked for a project file and matching theme rules
+const TAB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an editor command may take to report a failure. Editors still
+/// running after this are left to keep running.
+const EDITOR_START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bytes of an editor's stderr kept for the error message
+const EDITOR_STDERR_BYTES: u64 = 64 * 1024;
 
 /// PTY-related errors
 #[derive(Debug, Error)]
@@ -36,6 +70,21 @@ enum PtyError {
 
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    App(#[from] AppError),
+
+    #[error("Variable {0} doesn't load its choices from a command")]
+    NoChoiceCommand(String),
+
+    #[error("Choice command `{command}` failed: {message}")]
+    ChoiceCommand { command: String, message: String },
+
+    #[error("Editor command `{command}` failed: {message}")]
+    Editor { command: String, message: String },
+
+    #[error("Not saving settings: {0} has errors. Fix the file or reset it first")]
+    ConfigNotLoaded(String),
 }
 
 impl<T> From<PoisonError<T>> for PtyError {
@@ -44,13 +93,6 @@ impl<T> From<PoisonError<T>> for PtyError {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Button {
-    id: String,
-    name: String,
-    command: String,
-}
-
 #[derive(Clone, Serialize)]
 struct TabData {
     tab_id: String,
@@ -62,18 +104,552 @@ struct TabClosed {
     tab_id: String,
 }
 
+/// Sent when the backend opens a tab on its own, e.g. for a button's target
+#[derive(Clone, Serialize)]
+struct TabCreated {
+    tab_id: String,
+    name: Option<String>,
+    split_from: Option<String>,
+    direction: Option<SplitDirection>,
+}
+
+/// Sent when a macro button's steps have all run, or it stopped early
+#[derive(Clone, Serialize)]
+struct MacroFinished {
+    tab_id: String,
+    button_id: String,
+    error: Option<String>,
+}
+
+/// Sent when a tab moves into or out of a project, or its project file changes
+#[derive(Clone, Serialize)]
+struct TabProjectChanged {
+    tab_id: String,
+    project: Option<Project>,
+}
+
+/// Sent when a theme rule starts or stops matching a tab. Without a theme
+/// the tab goes back to the terminal's theme.
+#[derive(Clone, Serialize)]
+struct TabThemeChanged {
+    tab_id: String,
+    theme: Option<TerminalTheme>,
+}
+
+/// Receivers of a tab's output besides the frontend
+type OutputTaps = Arc<Mutex<Vec<mpsc::Sender<String>>>>;
+
 struct TabPty {
     writer: Arc<Mutex<Box<dyn Write + Send>>>,
-    _master: Box<dyn MasterPty + Send>,  // Keep master alive
-    _child: Box<dyn std::any::Any + Send>,  // Keep child process alive
+    output_taps: OutputTaps,
+    /// Everything the tab printed, for searching
+    scrollback: Arc<Mutex<Scrollback>>,
+    /// Process ID of the tab's shell, used to find its working directory
+    pid: Option<u32>,
+    /// Name of the profile the tab was opened with, for theme rules
+    profile: String,
+    /// Number of times the tab printed something, so polling can skip idle tabs
+    output_count: Arc<AtomicU64>,
+    /// Shell the tab runs, which commands typed into it are quoted for
+    shell: Shell,
+    master: Box<dyn MasterPty + Send>, // Keep master alive, and find the foreground process
+    _child: Box<dyn std::any::Any + Send>, // Keep child process alive
 }
 
 struct TabsState {
     tabs: Arc<Mutex<HashMap<String, TabPty>>>,
     next_tab_num: AtomicU32,
+    /// Tabs created for named-tab buttons, by name
+    named_tabs: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Clone, Serialize)]
+struct ConfigChanged {
+    diff: ConfigDiff,
+    state: AppState,
+}
+
+#[derive(Clone, Serialize)]
+struct ConfigError {
+    message: String,
+    line: Option<usize>,
+    column: Option<usize>,
+}
+
+impl From<&PtyError> for ConfigError {
+    fn from(err: &PtyError) -> Self {
+        match err {
+            PtyError::App(AppError::ConfigParse {
+                line,
+                column,
+                message,
+            }) => ConfigError {
+                message: message.clone(),
+                line: Some(*line),
+                column: Some(*column),
+            },
+            other => ConfigError {
+                message: other.to_string(),
+                line: None,
+                column: None,
+            },
+        }
+    }
+}
+
+struct ChoicesState {
+    cache: Mutex<ChoiceCache>,
+}
+
+/// Where a button's command ended up
+#[derive(Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RunOutcome {
+    Tab { tab_id: String },
+    Job { job_id: String },
+}
+
+/// Sent for every line a background job prints
+#[derive(Clone, Serialize)]
+struct JobOutput {
+    job_id: String,
+    line: JobOutputLine,
+}
+
+struct DiscoveryState {
+    cache: Mutex<DiscoveryCache>,
+}
+
+struct JobsState {
+    manager: Arc<Mutex<JobManager>>,
+    /// Signals that stop running jobs, by job ID
+    cancels: Arc<Mutex<HashMap<String, oneshot::Sender<()>>>>,
+}
+
+struct ConfigState {
+    path: PathBuf,
+    /// Where button usage and remembered template values are saved
+    usage_path: PathBuf,
+    state: Arc<Mutex<AppState>>,
+    /// Cleared while the file on disk can't be parsed, so saving settings
+    /// doesn't replace the user's edits with whatever is in memory
+    loaded: Arc<AtomicBool>,
+    /// Why the file couldn't be read at startup, sent once the page loads
+    startup_error: Mutex<Option<ConfigError>>,
+}
+
+/// Resolves the config file location. `TERMOS_CONFIG` overrides the
+/// platform default so the file can live in a dotfiles repository.
+fn config_path() -> PathBuf {
+    if let Ok(path) = std::env::var("TERMOS_CONFIG") {
+        return PathBuf::from(path);
+    }
+    let base = std::env::var("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("APPDATA").map(PathBuf::from))
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to get config directory (XDG_CONFIG_HOME/APPDATA/HOME): {e}, using current dir");
+            PathBuf::from(".")
+        });
+    base.join("termos").join("config.toml")
+}
+
+/// Resolves the usage file location. Usage changes on every run, so it
+/// lives in the data directory rather than next to the config file.
+fn usage_path() -> PathBuf {
+    let base = std::env::var("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("LOCALAPPDATA").map(PathBuf::from))
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".local").join("share")))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to get data directory (XDG_DATA_HOME/LOCALAPPDATA/HOME): {e}, using current dir");
+            PathBuf::from(".")
+        });
+    base.join("termos").join("usage.json")
+}
+
+/// Reads the config file, falling back to defaults only when it doesn't
+/// exist, and applies the saved button usage to it
+fn read_config(path: &Path, usage_path: &Path) -> Result<AppState, PtyError> {
+    let mut state = match std::fs::read_to_string(path) {
+        Ok(source) => AppState::from_toml(&source)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => AppState::default(),
+        Err(e) => return Err(e.into()),
+    };
+    // Usage is only a convenience, so a damaged file doesn't stop loading
+    match std::fs::read_to_string(usage_path) {
+        Ok(source) => match UsageState::from_json(&source) {
+            Ok(usage) => state.restore_usage(&usage),
+            Err(e) => eprintln!("Failed to parse usage {}: {e}", usage_path.display()),
+        },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => eprintln!("Failed to read usage {}: {e}", usage_path.display()),
+    }
+    Ok(state)
+}
+
+/// Saves the config file and button usage, unless the config file has
+/// errors the user hasn't fixed or reset yet
+fn write_config(config: &ConfigState, state: &AppState) -> Result<(), PtyError> {
+    write_usage(config, state)?;
+    if let Some(parent) = config.path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&config.path, state.to_toml()?)?;
+    Ok(())
+}
+
+/// Saves only the button usage, leaving the config file untouched
+fn write_usage(config: &ConfigState, state: &AppState) -> Result<(), PtyError> {
+    // Until the config has loaded, the buttons in memory aren't the user's
+    if !config.loaded.load(Ordering::SeqCst) {
+        return Err(PtyError::ConfigNotLoaded(config.path.display().to_string()));
+    }
+    if let Some(parent) = config.usage_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&config.usage_path, state.usage().to_json()?)?;
+    Ok(())
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Polls the config file and re-parses it whenever it changes on disk
+fn watch_config(
+    app: AppHandle,
+    path: PathBuf,
+    usage_path: PathBuf,
+    state: Arc<Mutex<AppState>>,
+    loaded: Arc<AtomicBool>,
+) {
+    std::thread::spawn(move || {
+        let mut last_modified = modified_time(&path);
+        loop {
+            std::thread::sleep(CONFIG_POLL_INTERVAL);
+            let modified = modified_time(&path);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            let new_state = match read_config(&path, &usage_path) {
+                Ok(new_state) => new_state,
+                Err(e) => {
+                    eprintln!("Failed to reload config {}: {e}", path.display());
+                    loaded.store(false, Ordering::SeqCst);
+                    if let Err(e) = app.emit_all("config-error", ConfigError::from(&e)) {
+                        eprintln!("Failed to emit config-error: {e}");
+                    }
+                    continue;
+                }
+            };
+
+            let diff = match state.lock() {
+                Ok(mut guard) => {
+                    let diff = guard.diff(&new_state);
+                    *guard = new_state.clone();
+                    loaded.store(true, Ordering::SeqCst);
+                    diff
+                }
+                Err(_) => {
+                    eprintln!("Failed to acquire lock for config reload");
+                    continue;
+                }
+            };
+
+            if diff.is_empty() {
+                continue;
+            }
+            if diff.terminal_config_changed {
+                emit_terminal_config(&app, &new_state.terminal_config);
+            }
+            if let Err(e) = app.emit_all(
+                "config-changed",
+                ConfigChanged {
+                    diff,
+                    state: new_state,
+                },
+            ) {
+                eprintln!("Failed to emit config-changed: {e}");
+            }
+        }
+    });
+}
+
+/// Current working directory of a process, where the platform exposes it
+fn process_cwd(pid: u32) -> Option<PathBuf> {
+    if cfg!(target_os = "linux") {
+        std::fs::read_link(format!("/proc/{pid}/cwd")).ok()
+    } else if cfg!(target_os = "macos") {
+        let output = std::process::Command::new("lsof")
+            .args(["-a", "-p", &pid.to_string(), "-d", "cwd", "-Fn"])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .find_map(|line| line.strip_prefix('n'))
+            .map(PathBuf::from)
+    } else {
+        None
+    }
+}
+
+/// Working directory of a tab's shell, if it can be found
+fn tab_cwd(
+    tabs: &Mutex<HashMap<String, TabPty>>,
+    tab_id: &str,
+) -> Result<Option<PathBuf>, PtyError> {
+    let pid = tabs
+        .lock()?
+        .get(tab_id)
+        .ok_or_else(|| PtyError::TabNotFound(tab_id.to_owned()))?
+        .pid;
+    Ok(pid.and_then(process_cwd))
+}
+
+/// Command line of a process, with the program's directory left out
+fn process_command_line(pid: u32) -> Option<String> {
+    let command_line = if cfg!(target_os = "linux") {
+        let raw = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+        String::from_utf8_lossy(&raw)
+            .split('\0')
+            .filter(|arg| !arg.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ")
+    } else if cfg!(target_os = "macos") {
+        let output = std::process::Command::new("ps")
+            .args(["-o", "command=", "-p", &pid.to_string()])
+            .output()
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    } else {
+        return None;
+    };
+    let (program, args) = command_line.split_once(' ').unwrap_or((&command_line, ""));
+    let program = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    Some(format!("{program} {args}").trim_end().to_string())
+}
+
+/// Process ID of whatever runs in the foreground of a tab, e.g. `ssh`
+#[cfg(unix)]
+fn foreground_pid(master: &dyn MasterPty) -> Option<u32> {
+    master
+        .process_group_leader()
+        .and_then(|pid| u32::try_from(pid).ok())
+}
+
+#[cfg(not(unix))]
+fn foreground_pid(_master: &dyn MasterPty) -> Option<u32> {
+    None
+}
+
+/// Collects what theme rules are matched against for a tab. Lookups spawn
+/// `ps` and `lsof` on macOS, so the last context in `watched` is reused
+/// until the tab prints something, and the foreground process is only
+/// looked up again when it changes.
+fn tab_context(
+    tabs: &Mutex<HashMap<String, TabPty>>,
+    tab_id: &str,
+    watched: &mut WatchedTab,
+) -> Result<TabContext, PtyError> {
+    let (pid, foreground, profile, output_count) = {
+        let tabs = tabs.lock()?;
+        let tab = tabs
+            .get(tab_id)
+            .ok_or_else(|| PtyError::TabNotFound(tab_id.to_owned()))?;
+        (
+            tab.pid,
+            foreground_pid(tab.master.as_ref()).or(tab.pid),
+            tab.profile.clone(),
+            tab.output_count.load(Ordering::Relaxed),
+        )
+    };
+    let process = match &watched.context {
+        Some(context) if watched.foreground == foreground => {
+            if watched.output_count == output_count {
+                return Ok(context.clone());
+            }
+            context.process.clone()
+        }
+        _ => foreground.and_then(process_command_line),
+    };
+    let context = TabContext {
+        process,
+        cwd: pid
+            .and_then(process_cwd)
+            .map(|cwd| cwd.to_string_lossy().into_owned()),
+        profile: Some(profile),
+    };
+    watched.context = Some(context.clone());
+    watched.foreground = foreground;
+    watched.output_count = output_count;
+    Ok(context)
+}
+
+/// Loads the project file for a directory, if it is inside a project
+fn load_project(
+    dir: Option<&Path>,
+    trusted_projects: &BTreeMap<String, String>,
+) -> Result<Option<Project>, PtyError> {
+    let Some(path) = dir.and_then(find_project_file) else {
+        return Ok(None);
+    };
+    Ok(Some(load_project_file(&path, trusted_projects)?))
+}
+
+fn load_project_file(
+    path: &Path,
+    trusted_projects: &BTreeMap<String, String>,
+) -> Result<Project, PtyError> {
+    let source = std::fs::read_to_string(path)?;
+    Ok(Project::parse(path, &source, trusted_projects)?)
+}
+
+/// Loads the project file for a tab's working directory, if it is inside a project
+fn tab_project(
+    tabs: &Mutex<HashMap<String, TabPty>>,
+    tab_id: &str,
+    trusted_projects: &BTreeMap<String, String>,
+) -> Result<Option<Project>, PtyError> {
+    load_project(tab_cwd(tabs, tab_id)?.as_deref(), trusted_projects)
+}
+
+/// What was last sent to the frontend about a tab, and what it was worked out from
+#[derive(Default)]
+struct WatchedTab {
+    project: Option<Project>,
+    /// The working directory `project_file` was found from
+    cwd: Option<String>,
+    project_file: Option<PathBuf>,
+    /// The project file `project` was loaded from, when it was modified and
+    /// the hash it is trusted with
+    project_source: Option<(PathBuf, Option<SystemTime>, Option<String>)>,
+    theme: Option<TerminalTheme>,
+    context: Option<TabContext>,
+    /// Process ID of the foreground process `context` was collected for
+    foreground: Option<u32>,
+    /// The tab's `output_count` when `context` was collected
+    output_count: u64,
 }
 
-fn spawn_tab(tab_id: String, window: Window, tabs: Arc<Mutex<HashMap<String, TabPty>>>) -> Result<(), PtyError> {
+/// Polls each tab's working directory and foreground process. Tells the
+/// frontend when the project a tab is inside changes, so the sidebar can
+/// show its buttons, and when a theme rule starts or stops matching it.
+fn watch_tabs(
+    app: AppHandle,
+    tabs: Arc<Mutex<HashMap<String, TabPty>>>,
+    state: Arc<Mutex<AppState>>,
+) {
+    let home_dir = home_dir();
+    let mut watched: HashMap<String, WatchedTab> = HashMap::new();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(TAB_POLL_INTERVAL);
+        let tab_ids: Vec<String> = match tabs.lock() {
+            Ok(tabs) => tabs.keys().cloned().collect(),
+            Err(_) => {
+                eprintln!("Failed to acquire lock for tab polling");
+                continue;
+            }
+        };
+        let trusted_projects = match state.lock() {
+            Ok(state) => state.trusted_projects.clone(),
+            Err(_) => {
+                eprintln!("Failed to acquire lock for tab polling");
+                continue;
+            }
+        };
+
+        watched.retain(|tab_id, _| tab_ids.contains(tab_id));
+        for tab_id in tab_ids {
+            let tab = watched.entry(tab_id.clone()).or_default();
+            let context = match tab_context(&tabs, &tab_id, tab) {
+                Ok(context) => context,
+                Err(PtyError::TabNotFound(_)) => continue,
+                Err(e) => {
+                    eprintln!("Failed to inspect tab {tab_id}: {e}");
+                    continue;
+                }
+            };
+            if tab.cwd != context.cwd {
+                tab.cwd = context.cwd.clone();
+                tab.project_file = context
+                    .cwd
+                    .as_deref()
+                    .map(Path::new)
+                    .and_then(find_project_file);
+            }
+            // Only read the project file again when it has changed or been trusted
+            let source = tab.project_file.as_ref().map(|path| {
+                let trusted = trusted_projects.get(path.to_string_lossy().as_ref());
+                (path.clone(), modified_time(path), trusted.cloned())
+            });
+            if tab.project_source != source {
+                tab.project_source = source;
+                let project = tab.project_file.as_deref().and_then(|path| {
+                    load_project_file(path, &trusted_projects)
+                        .map_err(|e| eprintln!("Failed to load project for tab {tab_id}: {e}"))
+                        .ok()
+                });
+                if tab.project != project {
+                    tab.project = project.clone();
+                    if let Err(e) = app.emit_all(
+                        "project-changed",
+                        TabProjectChanged {
+                            tab_id: tab_id.clone(),
+                            project,
+                        },
+                    ) {
+                        eprintln!("Failed to emit project-changed for tab {tab_id}: {e}");
+                    }
+                }
+            }
+            let theme = match state.lock() {
+                Ok(state) => state.tab_theme(&context, &home_dir).unwrap_or_else(|e| {
+                    eprintln!("Failed to match theme rules for tab {tab_id}: {e}");
+                    None
+                }),
+                Err(_) => {
+                    eprintln!("Failed to acquire lock for tab polling");
+                    continue;
+                }
+            };
+
+            if tab.theme != theme {
+                tab.theme = theme.clone();
+                if let Err(e) = app.emit_all(
+                    "tab-theme-changed",
+                    TabThemeChanged {
+                        tab_id: tab_id.clone(),
+                        theme,
+                    },
+                ) {
+                    eprintln!("Failed to emit tab-theme-changed for tab {tab_id}: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// Sends new terminal settings to every open tab so they apply without a restart
+fn emit_terminal_config(app: &AppHandle, config: &TerminalConfig) {
+    if let Err(e) = app.emit_all("terminal-config-changed", config.clone()) {
+        eprintln!("Failed to emit terminal-config-changed: {e}");
+    }
+}
+
+/// Opens a tab running the profile's shell. `scrollback` is how many lines
+/// of its output are kept for searching.
+fn spawn_tab(
+    tab_id: String,
+    window: Window,
+    tabs: Arc<Mutex<HashMap<String, TabPty>>>,
+    profile: &Profile,
+    scrollback: usize,
+    output_tap: Option<mpsc::Sender<String>>,
+) -> Result<(), PtyError> {
     let pty_system = native_pty_system();
     let pair = pty_system
         .openpty(PtySize {
@@ -85,15 +661,14 @@ fn spawn_tab(tab_id: String, window: Window, tabs: Arc<Mutex<HashMap<String, Tab
         .map_err(|e| PtyError::Open(e.into()))?;
 
     // Get user's home directory for working directory
-    let home_dir = std::env::var("USERPROFILE")
-        .or_else(|_| std::env::var("HOME"))
-        .unwrap_or_else(|e| {
-            eprintln!("Failed to get home directory (USERPROFILE/HOME): {e}, using current dir");
-            ".".to_string()
-        });
+    let home_dir = home_dir();
 
     // Spawn shell as login shell to inherit user's PATH
-    let mut cmd = if cfg!(windows) {
+    let mut cmd = if let Some(shell) = &profile.shell {
+        let mut cmd = CommandBuilder::new(shell);
+        cmd.args(&profile.args);
+        cmd
+    } else if cfg!(windows) {
         // Use cmd.exe for now - more reliable than PowerShell in PTY
         let mut cmd = CommandBuilder::new("cmd.exe");
         cmd.cwd(&home_dir);
@@ -103,34 +678,71 @@ fn spawn_tab(tab_id: String, window: Window, tabs: Arc<Mutex<HashMap<String, Tab
         cmd.arg("-l");
         cmd
     };
+    if let Some(cwd) = &profile.cwd {
+        cmd.cwd(expand_home(cwd, &home_dir));
+    }
     cmd.env("TERM", "xterm-256color");
+    for (key, value) in &profile.env {
+        cmd.env(key, value);
+    }
 
-    let child = pair.slave.spawn_command(cmd).map_err(|e| PtyError::Spawn(e.into()))?;
+    let child = pair
+        .slave
+        .spawn_command(cmd)
+        .map_err(|e| PtyError::Spawn(e.into()))?;
+    let pid = child.process_id();
 
     // Get writer and reader before storing
-    let writer = pair.master.take_writer().map_err(|e| PtyError::Writer(e.into()))?;
-    let mut reader = pair.master.try_clone_reader().map_err(|e| PtyError::Reader(e.into()))?;
+    let writer = pair
+        .master
+        .take_writer()
+        .map_err(|e| PtyError::Writer(e.into()))?;
+    let mut reader = pair
+        .master
+        .try_clone_reader()
+        .map_err(|e| PtyError::Reader(e.into()))?;
 
     // Store writer, master, and child process - all must stay alive!
+    let output_taps: OutputTaps = Arc::new(Mutex::new(output_tap.into_iter().collect()));
+    let scrollback = Arc::new(Mutex::new(Scrollback::new(scrollback)));
+    let output_count = Arc::new(AtomicU64::new(0));
     {
         let mut tabs_guard = tabs.lock()?;
-        tabs_guard.insert(tab_id.clone(), TabPty {
-            writer: Arc::new(Mutex::new(writer)),
-            _master: pair.master,
-            _child: Box::new(child),
-        });
+        tabs_guard.insert(
+            tab_id.clone(),
+            TabPty {
+                writer: Arc::new(Mutex::new(writer)),
+                output_taps: output_taps.clone(),
+                scrollback: scrollback.clone(),
+                pid,
+                profile: profile.name.clone(),
+                output_count: output_count.clone(),
+                shell: profile.shell_kind(),
+                master: pair.master,
+                _child: Box::new(child),
+            },
+        );
     }
 
     // Read from PTY and send to frontend with tab_id
     let tab_id_clone = tab_id.clone();
     let tabs_clone = tabs.clone();
     std::thread::spawn(move || {
-
         let mut buf = [0u8; 8192];
         loop {
             match reader.read(&mut buf) {
                 Ok(n) if n > 0 => {
                     let data = String::from_utf8_lossy(&buf[..n]).to_string();
+                    output_count.fetch_add(1, Ordering::Relaxed);
+                    // A panic elsewhere can't leave the lines half updated, so
+                    // keep recording into a poisoned scrollback
+                    scrollback
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .push(&data, now_ms());
+                    if let Ok(mut taps) = output_taps.lock() {
+                        taps.retain(|tap| tap.send(data.clone()).is_ok());
+                    }
                     if let Err(e) = window.emit(
                         "terminal-data",
                         TabData {
@@ -156,7 +768,12 @@ fn spawn_tab(tab_id: String, window: Window, tabs: Arc<Mutex<HashMap<String, Tab
         } else {
             eprintln!("Failed to acquire lock for tab cleanup: {}", tab_id_clone);
         }
-        if let Err(e) = window.emit("tab-closed", TabClosed { tab_id: tab_id_clone.clone() }) {
+        if let Err(e) = window.emit(
+            "tab-closed",
+            TabClosed {
+                tab_id: tab_id_clone.clone(),
+            },
+        ) {
             eprintln!("Failed to emit tab-closed for tab {}: {e}", tab_id_clone);
         }
     });
@@ -164,16 +781,64 @@ fn spawn_tab(tab_id: String, window: Window, tabs: Arc<Mutex<HashMap<String, Tab
     Ok(())
 }
 
-#[tauri::command]
-fn create_tab(window: Window, state: State<TabsState>) -> Result<String, String> {
-    create_tab_inner(window, state).map_err(|e| e.to_string())
+/// The user's home directory, or `.` if it isn't set
+fn home_dir() -> String {
+    std::env::var("USERPROFILE")
+        .or_else(|_| std::env::var("HOME"))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to get home directory (USERPROFILE/HOME): {e}, using current dir");
+            ".".to_string()
+        })
+}
+
+/// Expands a leading `~` to the home directory
+fn expand_home(path: &str, home_dir: &str) -> PathBuf {
+    match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+            PathBuf::from(format!("{home_dir}{rest}"))
+        }
+        _ => PathBuf::from(path),
+    }
 }
 
-fn create_tab_inner(window: Window, state: State<TabsState>) -> Result<String, PtyError> {
+fn next_tab_id(state: &TabsState) -> String {
     let num = state.next_tab_num.fetch_add(1, Ordering::Relaxed);
-    let tab_id = format!("tab-{num}");
+    format!("tab-{num}")
+}
 
-    spawn_tab(tab_id.clone(), window, state.tabs.clone())?;
+#[tauri::command]
+fn create_tab(
+    profile: Option<String>,
+    cwd: Option<String>,
+    window: Window,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<String, String> {
+    create_tab_inner(profile, cwd, window, state, config).map_err(|e| e.to_string())
+}
+
+fn create_tab_inner(
+    profile: Option<String>,
+    cwd: Option<String>,
+    window: Window,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<String, PtyError> {
+    let (profile, scrollback) = {
+        let app_state = config.state.lock()?;
+        let profile = app_state.spawn_profile(profile.as_deref(), cwd.as_deref())?;
+        (profile, app_state.terminal_config.scrollback as usize)
+    };
+    let tab_id = next_tab_id(&state);
+
+    spawn_tab(
+        tab_id.clone(),
+        window,
+        state.tabs.clone(),
+        &profile,
+        scrollback,
+        None,
+    )?;
     Ok(tab_id)
 }
 
@@ -193,52 +858,1642 @@ fn send_to_tab(tab_id: String, data: String, state: State<TabsState>) -> Result<
     send_to_tab_inner(tab_id, data, state).map_err(|e| e.to_string())
 }
 
-fn send_to_tab_inner(tab_id: String, data: String, state: State<TabsState>) -> Result<(), PtyError> {
-    let tabs = state.tabs.lock()?;
-    let tab = tabs.get(&tab_id).ok_or_else(|| PtyError::TabNotFound(tab_id))?;
+fn send_to_tab_inner(
+    tab_id: String,
+    data: String,
+    state: State<TabsState>,
+) -> Result<(), PtyError> {
+    write_to_tab(&state.tabs, &tab_id, &data)
+}
+
+/// Starts copying a tab's output to `tap` until the tab closes or the receiver is dropped
+fn add_output_tap(
+    tabs: &Mutex<HashMap<String, TabPty>>,
+    tab_id: &str,
+    tap: mpsc::Sender<String>,
+) -> Result<(), PtyError> {
+    let tabs = tabs.lock()?;
+    let tab = tabs
+        .get(tab_id)
+        .ok_or_else(|| PtyError::TabNotFound(tab_id.to_owned()))?;
+    tab.output_taps.lock()?.push(tap);
+    Ok(())
+}
+
+fn write_to_tab(
+    tabs: &Mutex<HashMap<String, TabPty>>,
+    tab_id: &str,
+    data: &str,
+) -> Result<(), PtyError> {
+    let tabs = tabs.lock()?;
+    let tab = tabs
+        .get(tab_id)
+        .ok_or_else(|| PtyError::TabNotFound(tab_id.to_owned()))?;
     let mut writer = tab.writer.lock()?;
     writer.write_all(data.as_bytes())?;
     writer.flush()?;
     Ok(())
 }
 
+/// Renders a button's command, as it would be sent for the current tabs,
+/// so it can be reviewed before it runs
 #[tauri::command]
-fn resize_terminal(_cols: u16, _rows: u16) -> Result<(), String> {
-    Ok(())
+fn review_button(
+    button_id: String,
+    values: HashMap<String, String>,
+    active_tab_id: Option<String>,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<CommandReview, String> {
+    review_button_inner(button_id, values, active_tab_id, state, config).map_err(|e| e.to_string())
+}
+
+fn review_button_inner(
+    button_id: String,
+    values: HashMap<String, String>,
+    active_tab_id: Option<String>,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<CommandReview, PtyError> {
+    let button = find_button(&config, &state, &button_id, active_tab_id.as_deref())?;
+    let app_state = config.state.lock()?;
+    let run = plan_button_run(
+        &app_state,
+        &state,
+        &button,
+        &values,
+        active_tab_id.as_deref(),
+    )?;
+    Ok(run.review)
 }
 
+/// Looks up one of the user's buttons, or a button from the project the
+/// active tab is in. Project files are re-read so a button only runs with
+/// the exact file contents the user trusted. The config lock isn't held
+/// while the tab's directory is looked up and the file is read.
+fn find_button(
+    config: &ConfigState,
+    state: &TabsState,
+    button_id: &str,
+    active_tab_id: Option<&str>,
+) -> Result<CommandButton, PtyError> {
+    if !Project::is_project_button(button_id) {
+        return config
+            .state
+            .lock()?
+            .button_manager
+            .get_button(button_id)
+            .cloned()
+            .ok_or_else(|| AppError::ButtonNotFound(button_id.to_owned()).into());
+    }
+    let trusted_projects = config.state.lock()?.trusted_projects.clone();
+    let project = match active_tab_id {
+        Some(tab_id) => tab_project(&state.tabs, tab_id, &trusted_projects)?,
+        None => None,
+    };
+    let project = project.ok_or_else(|| AppError::ButtonNotFound(button_id.to_owned()))?;
+    Ok(project.runnable_button(button_id)?.clone())
+}
+
+/// The shell running in a tab
+fn tab_shell(tabs: &Mutex<HashMap<String, TabPty>>, tab_id: &str) -> Result<Shell, PtyError> {
+    let tabs = tabs.lock()?;
+    let tab = tabs
+        .get(tab_id)
+        .ok_or_else(|| PtyError::TabNotFound(tab_id.to_owned()))?;
+    Ok(tab.shell)
+}
+
+/// A button run worked out up to sending the command or spawning its tab or job
+struct ButtonRun {
+    name: String,
+    /// What to send or run. The review shows it with secret values masked.
+    command: String,
+    review: CommandReview,
+    resolution: TargetResolution,
+    /// Settings for the tab or job to spawn, if the target needs one
+    profile: Option<Profile>,
+    /// Steps to run instead of sending the command, for macro buttons
+    executor: Option<MacroExecutor>,
+}
+
+/// Resolves a button's target and renders its command for wherever it runs.
+/// Commands typed into an existing tab get the button's cwd and env as a
+/// prefix; spawned tabs and jobs get them as process settings instead.
+fn plan_button_run(
+    app_state: &AppState,
+    state: &TabsState,
+    button: &CommandButton,
+    values: &HashMap<String, String>,
+    active_tab_id: Option<&str>,
+) -> Result<ButtonRun, PtyError> {
+    let resolution = {
+        let tabs = state.tabs.lock()?;
+        let named_tabs = state.named_tabs.lock()?;
+        button.target.resolve(active_tab_id, |name| {
+            named_tabs
+                .get(name)
+                .filter(|id| tabs.contains_key(*id))
+                .cloned()
+        })?
+    };
+    if button.is_macro() && matches!(resolution, TargetResolution::Background) {
+        return Err(AppError::MacroInBackground.into());
+    }
+    let secrets = button.secret_variables()?;
+    let env = button.render_env(values)?;
+    let (command, shown, profile) = match &resolution {
+        TargetResolution::Existing(tab_id) => {
+            let shell = tab_shell(&state.tabs, tab_id)?;
+            let shown_env = button.preview_env(values, &secrets)?;
+            (
+                typed_command(button, values, &env, shell, None)?,
+                typed_command(button, values, &shown_env, shell, Some(&secrets))?,
+                None,
+            )
+        }
+        TargetResolution::Spawn(request) => {
+            let profile = app_state.button_profile(button, Some(request), env)?;
+            let template = button.template()?;
+            let shell = profile.shell_kind();
+            (
+                template.render_for_shell(values, shell)?,
+                template.preview_for_shell(values, shell, &secrets)?,
+                Some(profile),
+            )
+        }
+        TargetResolution::Background => {
+            let profile = app_state.button_profile(button, None, env)?;
+            let template = button.template()?;
+            let shell = profile.shell_kind();
+            (
+                template.render_for_shell(values, shell)?,
+                template.preview_for_shell(values, shell, &secrets)?,
+                Some(profile),
+            )
+        }
+    };
+
+    let (shown, executor) = if button.is_macro() {
+        // Steps are typed into the tab, so they're quoted for the shell it runs
+        let shell = match (&resolution, &profile) {
+            (TargetResolution::Existing(tab_id), _) => tab_shell(&state.tabs, tab_id)?,
+            (_, Some(profile)) => profile.shell_kind(),
+            (_, None) => Shell::platform_default(),
+        };
+        let executor = MacroExecutor::new(&button.steps, values, shell, &secrets)?;
+        (executor.preview(), Some(executor))
+    } else {
+        (shown, None)
+    };
+
+    Ok(ButtonRun {
+        name: button.name.clone(),
+        command,
+        review: app_state.review_command(shown, Some(button))?,
+        resolution,
+        profile,
+        executor,
+    })
+}
+
+/// Renders a button's command to type into a tab running `shell`, prefixed
+/// with its cwd and `env`. With `secrets`, their values are masked.
+fn typed_command(
+    button: &CommandButton,
+    values: &HashMap<String, String>,
+    env: &BTreeMap<String, String>,
+    shell: Shell,
+    secrets: Option<&HashSet<String>>,
+) -> Result<String, PtyError> {
+    let template = button.template()?;
+    let render = |shell| match secrets {
+        Some(secrets) => template.preview_for_shell(values, shell, secrets),
+        None => template.render_for_shell(values, shell),
+    };
+    let command = match &button.shell {
+        // Run the button's shell from the tab's, with the command as its argument
+        Some(program) => {
+            let button_shell = Shell::from_program(program).unwrap_or_else(Shell::platform_default);
+            let mut words = vec![shell.quote(program)?];
+            for arg in Shell::command_args(program) {
+                words.push(shell.quote(arg)?);
+            }
+            words.push(shell.quote(&render(button_shell)?)?);
+            words.join(" ")
+        }
+        None => render(shell)?,
+    };
+    Ok(shell.prefix(button.cwd.as_deref(), env, &command)?)
+}
+
+/// Checks an ad-hoc command, e.g. from the palette, against the danger patterns
 #[tauri::command]
-fn load_buttons() -> Result<String, String> {
-    Ok("[]".to_string())
+fn review_command(command: String, config: State<ConfigState>) -> Result<CommandReview, String> {
+    review_command_inner(command, config).map_err(|e| e.to_string())
 }
 
+fn review_command_inner(
+    command: String,
+    config: State<ConfigState>,
+) -> Result<CommandReview, PtyError> {
+    Ok(config.state.lock()?.review_command(command, None)?)
+}
+
+/// Sends an ad-hoc command to a tab once it has been confirmed, if it needs to be
 #[tauri::command]
-fn save_buttons(buttons: String) -> Result<(), String> {
-    save_buttons_inner(buttons).map_err(|e| e.to_string())
+fn run_command(
+    tab_id: String,
+    command: String,
+    confirmation: Option<String>,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<(), String> {
+    run_command_inner(tab_id, command, confirmation, state, config).map_err(|e| e.to_string())
 }
 
-fn save_buttons_inner(buttons: String) -> Result<(), PtyError> {
-    serde_json::from_str::<Vec<Button>>(&buttons)?;
-    Ok(())
+fn run_command_inner(
+    tab_id: String,
+    command: String,
+    confirmation: Option<String>,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<(), PtyError> {
+    let review = config.state.lock()?.review_command(command, None)?;
+    review.check(confirmation.as_deref())?;
+    write_to_tab(&state.tabs, &tab_id, &format!("{}\n", review.command))
 }
 
-fn main() {
-    tauri::Builder::default()
-        .setup(|app| {
-            app.manage(TabsState {
-                tabs: Arc::new(Mutex::new(HashMap::new())),
-                next_tab_num: AtomicU32::new(1),
-            });
+/// Renders a button's command and sends it to the tab its target selects,
+/// spawning that tab first if needed. Background buttons start a job instead.
+/// `confirmation` is what the user answered to the button's review.
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
+fn run_button(
+    button_id: String,
+    values: HashMap<String, String>,
+    active_tab_id: Option<String>,
+    confirmation: Option<String>,
+    window: Window,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+    jobs: State<JobsState>,
+) -> Result<RunOutcome, String> {
+    run_button_inner(
+        button_id,
+        values,
+        active_tab_id,
+        confirmation,
+        window,
+        state,
+        config,
+        jobs,
+    )
+    .map_err(|e| e.to_string())
+}
 
-            Ok(())
+#[allow(clippy::too_many_arguments)]
+fn run_button_inner(
+    button_id: String,
+    values: HashMap<String, String>,
+    active_tab_id: Option<String>,
+    confirmation: Option<String>,
+    window: Window,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+    jobs: State<JobsState>,
+) -> Result<RunOutcome, PtyError> {
+    let button = find_button(&config, &state, &button_id, active_tab_id.as_deref())?;
+    let run = {
+        let mut app_state = config.state.lock()?;
+        let run = plan_button_run(
+            &app_state,
+            &state,
+            &button,
+            &values,
+            active_tab_id.as_deref(),
+        )?;
+        run.review.check(confirmation.as_deref())?;
+
+        // Project buttons live in the repository, so their usage isn't stored
+        if !Project::is_project_button(&button_id) {
+            app_state.button_manager.record_use(&button_id, now_ms())?;
+            app_state
+                .button_manager
+                .record_values(&button_id, &values)?;
+            // Usage only affects ranking, so failing to save it mustn't stop the run
+            if let Err(e) = write_usage(&config, &app_state) {
+                eprintln!("Failed to save usage for button {button_id}: {e}");
+            }
+        }
+        run
+    };
+    let command = run.command;
+    let profile = run
+        .profile
+        .unwrap_or_else(|| Profile::new("default".to_string()));
+    let (output_tap, output) = match run.executor {
+        Some(_) => {
+            let (tap, output) = mpsc::channel();
+            (Some(tap), Some(output))
+        }
+        None => (None, None),
+    };
+
+    let tab_id = match run.resolution {
+        TargetResolution::Existing(tab_id) => {
+            if let Some(tap) = output_tap {
+                add_output_tap(&state.tabs, &tab_id, tap)?;
+            }
+            tab_id
+        }
+        TargetResolution::Spawn(request) => {
+            let tab_id = next_tab_id(&state);
+            let scrollback = config.state.lock()?.terminal_config.scrollback as usize;
+            spawn_tab(
+                tab_id.clone(),
+                window.clone(),
+                state.tabs.clone(),
+                &profile,
+                scrollback,
+                output_tap,
+            )?;
+            announce_tab(&window, &state, &tab_id, request)?;
+            tab_id
+        }
+        TargetResolution::Background => {
+            let job_id = start_job(
+                window.app_handle(),
+                &jobs,
+                run.name,
+                command,
+                run.review.command,
+                &profile,
+                Some(button_id),
+            )?;
+            return Ok(RunOutcome::Job { job_id });
+        }
+    };
+
+    if let (Some(executor), Some(output)) = (run.executor, output) {
+        let tabs = state.tabs.clone();
+        let tab_id = tab_id.clone();
+        std::thread::spawn(move || {
+            let error = run_macro(executor, &tab_id, &tabs, output)
+                .err()
+                .map(|e| e.to_string());
+            let finished = MacroFinished {
+                tab_id: tab_id.clone(),
+                button_id,
+                error,
+            };
+            if let Err(e) = window.emit("macro-finished", finished) {
+                eprintln!("Failed to emit macro-finished for tab {tab_id}: {e}");
+            }
+        });
+    } else {
+        write_to_tab(&state.tabs, &tab_id, &format!("{command}\n"))?;
+    }
+    Ok(RunOutcome::Tab { tab_id })
+}
+
+/// Drives a macro with a tab's output until it finishes, fails or the tab closes
+fn run_macro(
+    mut executor: MacroExecutor,
+    tab_id: &str,
+    tabs: &Mutex<HashMap<String, TabPty>>,
+    output: mpsc::Receiver<String>,
+) -> Result<(), PtyError> {
+    loop {
+        match executor.next(Instant::now())? {
+            MacroAction::Send(text) => write_to_tab(tabs, tab_id, &text)?,
+            MacroAction::Wait(deadline) => {
+                match output.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(data) => executor.push_output(&data),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        return Err(PtyError::TabNotFound(tab_id.to_owned()))
+                    }
+                }
+            }
+            MacroAction::Finished => return Ok(()),
+        }
+    }
+}
+
+/// Registers a backend-spawned tab under its name and tells the frontend about it
+fn announce_tab(
+    window: &Window,
+    state: &TabsState,
+    tab_id: &str,
+    request: SpawnRequest,
+) -> Result<(), PtyError> {
+    if let Some(name) = &request.name {
+        state
+            .named_tabs
+            .lock()?
+            .insert(name.clone(), tab_id.to_owned());
+    }
+    let (split_from, direction) = request.split_from.unzip();
+    if let Err(e) = window.emit(
+        "tab-created",
+        TabCreated {
+            tab_id: tab_id.to_owned(),
+            name: request.name,
+            split_from,
+            direction,
+        },
+    ) {
+        eprintln!("Failed to emit tab-created for tab {tab_id}: {e}");
+    }
+    Ok(())
+}
+
+/// Loads the project the tab is currently inside, if any
+#[tauri::command]
+fn get_tab_project(
+    tab_id: String,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<Option<Project>, String> {
+    get_tab_project_inner(tab_id, state, config).map_err(|e| e.to_string())
+}
+
+fn get_tab_project_inner(
+    tab_id: String,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<Option<Project>, PtyError> {
+    let trusted_projects = config.state.lock()?.trusted_projects.clone();
+    tab_project(&state.tabs, &tab_id, &trusted_projects)
+}
+
+/// Searches everything a tab has printed, without ANSI escape codes
+#[tauri::command]
+fn search_tab(
+    tab_id: String,
+    query: SearchQuery,
+    state: State<TabsState>,
+) -> Result<SearchResults, String> {
+    search_tab_inner(tab_id, query, state).map_err(|e| e.to_string())
+}
+
+fn search_tab_inner(
+    tab_id: String,
+    query: SearchQuery,
+    state: State<TabsState>,
+) -> Result<SearchResults, PtyError> {
+    let scrollback = {
+        let tabs = state.tabs.lock()?;
+        let tab = tabs.get(&tab_id).ok_or(PtyError::TabNotFound(tab_id))?;
+        tab.scrollback.clone()
+    };
+    let results = scrollback.lock()?.search(&query)?;
+    Ok(results)
+}
+
+/// Searches the output of every open tab, grouped by tab
+#[tauri::command]
+fn search_all_tabs(
+    query: SearchQuery,
+    state: State<TabsState>,
+) -> Result<Vec<TabSearchResults>, String> {
+    search_all_tabs_inner(query, state).map_err(|e| e.to_string())
+}
+
+fn search_all_tabs_inner(
+    query: SearchQuery,
+    state: State<TabsState>,
+) -> Result<Vec<TabSearchResults>, PtyError> {
+    // Release the tabs lock before searching, so output keeps flowing
+    let scrollbacks: Vec<(String, Arc<Mutex<Scrollback>>)> = state
+        .tabs
+        .lock()?
+        .iter()
+        .map(|(tab_id, tab)| (tab_id.clone(), tab.scrollback.clone()))
+        .collect();
+    let tabs: Vec<(String, &Mutex<Scrollback>)> = scrollbacks
+        .iter()
+        .map(|(tab_id, scrollback)| (tab_id.clone(), scrollback.as_ref()))
+        .collect();
+    Ok(search_tabs(&tabs, &query)?)
+}
+
+/// Finds URLs and file references in the tab's output lines numbered
+/// `start_line..end_line`. Relative paths are resolved against the tab's
+/// current working directory.
+#[tauri::command]
+fn find_links(
+    tab_id: String,
+    start_line: usize,
+    end_line: usize,
+    state: State<TabsState>,
+) -> Result<Vec<LineLinks>, String> {
+    find_links_inner(tab_id, start_line, end_line, state).map_err(|e| e.to_string())
+}
+
+fn find_links_inner(
+    tab_id: String,
+    start_line: usize,
+    end_line: usize,
+    state: State<TabsState>,
+) -> Result<Vec<LineLinks>, PtyError> {
+    let scrollback = state
+        .tabs
+        .lock()?
+        .get(&tab_id)
+        .ok_or_else(|| PtyError::TabNotFound(tab_id.clone()))?
+        .scrollback
+        .clone();
+    let lines = scrollback.lock()?.line_range(start_line..end_line);
+    let cwd = tab_cwd(&state.tabs, &tab_id)?;
+    Ok(lines.find_links(cwd.as_deref(), &home_dir()))
+}
+
+/// Opens a file in the editor from the `editor_command` setting
+#[tauri::command]
+async fn open_path(
+    path: String,
+    line: Option<u32>,
+    column: Option<u32>,
+    config: State<'_, ConfigState>,
+) -> Result<(), String> {
+    open_path_inner(path, line, column, config)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+async fn open_path_inner(
+    path: String,
+    line: Option<u32>,
+    column: Option<u32>,
+    config: State<'_, ConfigState>,
+) -> Result<(), PtyError> {
+    if !Path::new(&path).is_file() {
+        let message = format!("{path} is not a file");
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, message).into());
+    }
+    let command =
+        config
+            .state
+            .lock()?
+            .editor_command_for(&path, line, column, Shell::platform_default())?;
+    let failed = |message: String| PtyError::Editor {
+        command: command.clone(),
+        message,
+    };
+    let mut child = shell_command(&command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // Keep reading stderr after we stop waiting, so an editor that is still
+    // running isn't killed by writing to a closed pipe
+    let (stderr_tx, stderr_rx) = oneshot::channel();
+    if let Some(mut stderr) = child.stderr.take() {
+        tauri::async_runtime::spawn(async move {
+            let mut start = Vec::new();
+            let _ = (&mut stderr)
+                .take(EDITOR_STDERR_BYTES)
+                .read_to_end(&mut start)
+                .await;
+            let _ = stderr_tx.send(start);
+            let _ = tokio::io::copy(&mut stderr, &mut tokio::io::sink()).await;
+        });
+    }
+    match tokio::time::timeout(EDITOR_START_TIMEOUT, child.wait()).await {
+        Ok(status) => {
+            let status = status?;
+            if !status.success() {
+                // Something the editor started may still hold stderr open
+                let stderr = tokio::time::timeout(EDITOR_START_TIMEOUT, stderr_rx)
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .unwrap_or_default();
+                let stderr = String::from_utf8_lossy(&stderr);
+                let message = stderr
+                    .lines()
+                    .map(str::trim)
+                    .find(|line| !line.is_empty())
+                    .map(str::to_owned)
+                    .unwrap_or_else(|| status.to_string());
+                return Err(failed(message));
+            }
+            Ok(())
+        }
+        // Editors that wait for the file to be closed are still running
+        Err(_) => Ok(()),
+    }
+}
+
+/// Trusts a project file after the user reviewed it. `hash` is the hash of
+/// the contents they saw; if the file has changed since, it isn't trusted.
+#[tauri::command]
+fn trust_project(
+    path: String,
+    hash: String,
+    config: State<ConfigState>,
+) -> Result<Project, String> {
+    trust_project_inner(path, hash, config).map_err(|e| e.to_string())
+}
+
+fn trust_project_inner(
+    path: String,
+    hash: String,
+    config: State<ConfigState>,
+) -> Result<Project, PtyError> {
+    let source = std::fs::read_to_string(&path)?;
+    let mut app_state = config.state.lock()?;
+    let mut project = Project::parse(Path::new(&path), &source, &app_state.trusted_projects)?;
+    if project.hash != hash {
+        return Err(AppError::ProjectChanged(path).into());
+    }
+    app_state.trust_project(&project);
+    write_config(&config, &app_state)?;
+    project.trusted = true;
+    Ok(project)
+}
+
+/// Suggests buttons for the Makefile targets, npm scripts, just recipes and
+/// Cargo aliases in `dir`, or in the tab's working directory
+#[tauri::command]
+fn discover_commands(
+    dir: Option<String>,
+    tab_id: Option<String>,
+    state: State<TabsState>,
+    discovery: State<DiscoveryState>,
+) -> Result<Discovery, String> {
+    discover_commands_inner(dir, tab_id, state, discovery).map_err(|e| e.to_string())
+}
+
+fn discover_commands_inner(
+    dir: Option<String>,
+    tab_id: Option<String>,
+    state: State<TabsState>,
+    discovery: State<DiscoveryState>,
+) -> Result<Discovery, PtyError> {
+    let dir = match (dir, tab_id) {
+        (Some(dir), _) => Some(expand_home(&dir, &home_dir())),
+        (None, Some(tab_id)) => tab_cwd(&state.tabs, &tab_id)?,
+        (None, None) => None,
+    };
+    Ok(match dir {
+        Some(dir) => discovery.cache.lock()?.discover(&dir),
+        None => Discovery::default(),
+    })
+}
+
+/// Adds a suggested button from `discover_commands` to the user's buttons
+#[tauri::command]
+fn adopt_command(button: CommandButton, config: State<ConfigState>) -> Result<String, String> {
+    adopt_command_inner(button, config).map_err(|e| e.to_string())
+}
+
+fn adopt_command_inner(
+    button: CommandButton,
+    config: State<ConfigState>,
+) -> Result<String, PtyError> {
+    let mut app_state = config.state.lock()?;
+    let id = app_state.button_manager.adopt_button(&button)?;
+    write_config(&config, &app_state)?;
+    Ok(id)
+}
+
+#[tauri::command]
+fn resize_terminal(_cols: u16, _rows: u16) -> Result<(), String> {
+    Ok(())
+}
+
+#[tauri::command]
+fn load_buttons(config: State<ConfigState>) -> Result<String, String> {
+    load_buttons_inner(config).map_err(|e| e.to_string())
+}
+
+fn load_buttons_inner(config: State<ConfigState>) -> Result<String, PtyError> {
+    let state = config.state.lock()?;
+    Ok(state.button_manager.to_json()?)
+}
+
+#[tauri::command]
+fn save_buttons(buttons: String, config: State<ConfigState>) -> Result<(), String> {
+    save_buttons_inner(buttons, config).map_err(|e| e.to_string())
+}
+
+fn save_buttons_inner(buttons: String, config: State<ConfigState>) -> Result<(), PtyError> {
+    let mut state = config.state.lock()?;
+    state.button_manager = termos::ButtonManager::from_json(&buttons)?;
+    write_config(&config, &state)
+}
+
+#[tauri::command]
+fn record_button_use(button_id: String, config: State<ConfigState>) -> Result<(), String> {
+    record_button_use_inner(button_id, config).map_err(|e| e.to_string())
+}
+
+fn record_button_use_inner(button_id: String, config: State<ConfigState>) -> Result<(), PtyError> {
+    let mut state = config.state.lock()?;
+    state.button_manager.record_use(&button_id, now_ms())?;
+    write_usage(&config, &state)
+}
+
+/// Ranks the buttons for a command palette query
+#[tauri::command]
+fn search_palette(query: String, config: State<ConfigState>) -> Result<Vec<PaletteMatch>, String> {
+    search_palette_inner(query, config).map_err(|e| e.to_string())
+}
+
+fn search_palette_inner(
+    query: String,
+    config: State<ConfigState>,
+) -> Result<Vec<PaletteMatch>, PtyError> {
+    Ok(config.state.lock()?.button_manager.search(&query, now_ms()))
+}
+
+/// Gets every keyboard shortcut, built-in and per button, and what it does
+#[tauri::command]
+fn get_keybindings(config: State<ConfigState>) -> Result<Keybindings, String> {
+    get_keybindings_inner(config).map_err(|e| e.to_string())
+}
+
+fn get_keybindings_inner(config: State<ConfigState>) -> Result<Keybindings, PtyError> {
+    Ok(config.state.lock()?.button_manager.keybindings()?)
+}
+
+/// Sets or clears the keyboard shortcut that runs a button
+#[tauri::command]
+fn set_button_accelerator(
+    button_id: String,
+    accelerator: Option<String>,
+    config: State<ConfigState>,
+) -> Result<(), String> {
+    set_button_accelerator_inner(button_id, accelerator, config).map_err(|e| e.to_string())
+}
+
+fn set_button_accelerator_inner(
+    button_id: String,
+    accelerator: Option<String>,
+    config: State<ConfigState>,
+) -> Result<(), PtyError> {
+    let mut state = config.state.lock()?;
+    state
+        .button_manager
+        .set_accelerator(&button_id, accelerator.as_deref())?;
+    write_config(&config, &state)
+}
+
+/// Lists the user's themes followed by the built-in ones
+#[tauri::command]
+fn list_themes(config: State<ConfigState>) -> Result<Vec<TerminalTheme>, String> {
+    list_themes_inner(config).map_err(|e| e.to_string())
+}
+
+fn list_themes_inner(config: State<ConfigState>) -> Result<Vec<TerminalTheme>, PtyError> {
+    let state = config.state.lock()?;
+    let builtin = TerminalTheme::builtin_names()
+        .into_iter()
+        .filter(|name| {
+            !state
+                .themes
+                .iter()
+                .any(|t| t.name.eq_ignore_ascii_case(name))
+        })
+        .filter_map(TerminalTheme::builtin);
+    Ok(state.themes.iter().cloned().chain(builtin).collect())
+}
+
+/// Imports a theme file from iTerm2, Alacritty, Windows Terminal or base16.
+/// Without a `format`, it is guessed from the file. A user theme with the
+/// same name is only replaced when `replace` is set.
+#[tauri::command]
+fn import_theme(
+    path: String,
+    format: Option<ThemeFormat>,
+    replace: Option<bool>,
+    config: State<ConfigState>,
+) -> Result<TerminalTheme, String> {
+    import_theme_inner(path, format, replace.unwrap_or(false), config).map_err(|e| e.to_string())
+}
+
+fn import_theme_inner(
+    path: String,
+    format: Option<ThemeFormat>,
+    replace: bool,
+    config: State<ConfigState>,
+) -> Result<TerminalTheme, PtyError> {
+    let path = PathBuf::from(path);
+    let source = std::fs::read_to_string(&path)?;
+    let format = format
+        .or_else(|| ThemeFormat::detect(&path, &source))
+        .ok_or_else(|| {
+            AppError::InvalidDocument(format!("unknown theme format: {}", path.display()))
+        })?;
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let theme = TerminalTheme::import(&source, format, &name)?;
+
+    let mut state = config.state.lock()?;
+    state.add_theme(theme.clone(), replace)?;
+    write_config(&config, &state)?;
+    Ok(theme)
+}
+
+/// Writes a theme to a file in another terminal's format
+#[tauri::command]
+fn export_theme(
+    name: String,
+    format: ThemeFormat,
+    path: String,
+    config: State<ConfigState>,
+) -> Result<(), String> {
+    export_theme_inner(name, format, path, config).map_err(|e| e.to_string())
+}
+
+fn export_theme_inner(
+    name: String,
+    format: ThemeFormat,
+    path: String,
+    config: State<ConfigState>,
+) -> Result<(), PtyError> {
+    let exported = config.state.lock()?.theme(&name)?.export(format)?;
+    std::fs::write(path, exported)?;
+    Ok(())
+}
+
+/// Switches the terminal to a user or built-in theme by name
+#[tauri::command]
+fn set_theme(
+    name: String,
+    app: AppHandle,
+    config: State<ConfigState>,
+) -> Result<TerminalTheme, String> {
+    set_theme_inner(name, app, config).map_err(|e| e.to_string())
+}
+
+fn set_theme_inner(
+    name: String,
+    app: AppHandle,
+    config: State<ConfigState>,
+) -> Result<TerminalTheme, PtyError> {
+    let mut state = config.state.lock()?;
+    state.set_theme(&name)?;
+    write_config(&config, &state)?;
+    emit_terminal_config(&app, &state.terminal_config);
+    Ok(state.terminal_config.theme.clone())
+}
+
+/// Replaces the terminal settings once they pass validation
+#[tauri::command]
+fn update_terminal_config(
+    terminal_config: TerminalConfig,
+    app: AppHandle,
+    config: State<ConfigState>,
+) -> Result<(), String> {
+    update_terminal_config_inner(terminal_config, app, config).map_err(|e| e.to_string())
+}
+
+fn update_terminal_config_inner(
+    terminal_config: TerminalConfig,
+    app: AppHandle,
+    config: State<ConfigState>,
+) -> Result<(), PtyError> {
+    terminal_config.validate()?;
+    let mut state = config.state.lock()?;
+    state.terminal_config = terminal_config;
+    write_config(&config, &state)?;
+    emit_terminal_config(&app, &state.terminal_config);
+    Ok(())
+}
+
+#[tauri::command]
+fn button_prompt(
+    button_id: String,
+    active_tab_id: Option<String>,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<Vec<VariablePrompt>, String> {
+    button_prompt_inner(button_id, active_tab_id, state, config).map_err(|e| e.to_string())
+}
+
+fn button_prompt_inner(
+    button_id: String,
+    active_tab_id: Option<String>,
+    state: State<TabsState>,
+    config: State<ConfigState>,
+) -> Result<Vec<VariablePrompt>, PtyError> {
+    let button = find_button(&config, &state, &button_id, active_tab_id.as_deref())?;
+    Ok(config.state.lock()?.button_manager.prompt_for(&button)?)
+}
+
+#[tauri::command]
+fn record_button_values(
+    button_id: String,
+    values: HashMap<String, String>,
+    config: State<ConfigState>,
+) -> Result<(), String> {
+    record_button_values_inner(button_id, values, config).map_err(|e| e.to_string())
+}
+
+fn record_button_values_inner(
+    button_id: String,
+    values: HashMap<String, String>,
+    config: State<ConfigState>,
+) -> Result<(), PtyError> {
+    let mut state = config.state.lock()?;
+    state.button_manager.record_values(&button_id, &values)?;
+    write_usage(&config, &state)
+}
+
+#[tauri::command]
+fn parse_template(template: String) -> Result<Vec<TemplateVariable>, String> {
+    Template::parse(&template)
+        .map(|t| t.variables().to_vec())
+        .map_err(|e| e.to_string())
+}
+
+/// Renders a template with each value quoted for `shell`, defaulting to the
+/// shell that tabs run on this platform
+#[tauri::command]
+fn render_template(
+    template: String,
+    values: HashMap<String, String>,
+    shell: Option<Shell>,
+) -> Result<String, String> {
+    render_template_inner(template, values, shell).map_err(|e| e.to_string())
+}
+
+fn render_template_inner(
+    template: String,
+    values: HashMap<String, String>,
+    shell: Option<Shell>,
+) -> Result<String, PtyError> {
+    let shell = shell.unwrap_or_else(Shell::platform_default);
+    Ok(Template::parse(&template)?.render_for_shell(&values, shell)?)
+}
+
+/// Builds a non-interactive invocation of the shell that tabs use
+fn shell_command(command: &str) -> tokio::process::Command {
+    if cfg!(windows) {
+        let mut cmd = tokio::process::Command::new("cmd.exe");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = tokio::process::Command::new("zsh");
+        cmd.arg("-l").arg("-c").arg(command);
+        cmd
+    }
+}
+
+/// Builds the process for a job from the profile's shell, environment and
+/// working directory
+fn job_command(command: &str, profile: &Profile) -> tokio::process::Command {
+    let mut cmd = match &profile.shell {
+        Some(shell) => {
+            let mut cmd = tokio::process::Command::new(shell);
+            cmd.args(&profile.args)
+                .args(Shell::command_args(shell))
+                .arg(command);
+            cmd
+        }
+        None => shell_command(command),
+    };
+    cmd.envs(&profile.env);
+    if let Some(cwd) = &profile.cwd {
+        cmd.current_dir(expand_home(cwd, &home_dir()));
+    }
+    cmd
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Queues a job for `command` and runs it on the async runtime. The job
+/// list shows `shown`, the command with secret values masked.
+fn start_job(
+    app: AppHandle,
+    jobs: &JobsState,
+    name: String,
+    command: String,
+    shown: String,
+    profile: &Profile,
+    button_id: Option<String>,
+) -> Result<String, PtyError> {
+    let process = job_command(&command, profile);
+    let job_id = jobs
+        .manager
+        .lock()?
+        .create(name, shown, button_id, now_ms());
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    jobs.cancels.lock()?.insert(job_id.clone(), cancel_tx);
+    emit_job_updated(&app, &jobs.manager, &job_id);
+
+    let manager = jobs.manager.clone();
+    let cancels = jobs.cancels.clone();
+    let id = job_id.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Err(e) = run_job_process(&app, &manager, &id, process, cancel_rx).await {
+            eprintln!("Job {id} failed: {e}");
+            if let Ok(mut manager) = manager.lock() {
+                let _ = manager.append_output(&id, OutputStream::Stderr, e.to_string(), now_ms());
+                let _ = manager.finish(&id, None, now_ms());
+            }
+        }
+        if let Ok(mut cancels) = cancels.lock() {
+            cancels.remove(&id);
+        }
+        emit_job_updated(&app, &manager, &id);
+    });
+
+    Ok(job_id)
+}
+
+/// Runs a job's command to completion, or until it is cancelled
+async fn run_job_process(
+    app: &AppHandle,
+    manager: &Arc<Mutex<JobManager>>,
+    job_id: &str,
+    mut process: tokio::process::Command,
+    cancel: oneshot::Receiver<()>,
+) -> Result<(), PtyError> {
+    if manager.lock()?.start(job_id, now_ms()).is_err() {
+        // Cancelled before it got going
+        return Ok(());
+    }
+    emit_job_updated(app, manager, job_id);
+
+    // In a group of its own, so cancelling also stops what the command started
+    #[cfg(unix)]
+    process.process_group(0);
+    let mut child = process
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let tree = ProcessTree::new(&child);
+
+    let mut readers = Vec::new();
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(tauri::async_runtime::spawn(capture_output(
+            app.clone(),
+            manager.clone(),
+            job_id.to_owned(),
+            OutputStream::Stdout,
+            stdout,
+        )));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(tauri::async_runtime::spawn(capture_output(
+            app.clone(),
+            manager.clone(),
+            job_id.to_owned(),
+            OutputStream::Stderr,
+            stderr,
+        )));
+    }
+
+    tokio::select! {
+        status = child.wait() => {
+            let exit_code = status?.code();
+            for reader in readers {
+                let _ = reader.await;
+            }
+            manager.lock()?.finish(job_id, exit_code, now_ms())?;
+        }
+        _ = cancel => {
+            tree.kill();
+            child.kill().await?;
+            // Anything that escaped the group could keep the pipes open forever
+            for reader in readers {
+                reader.abort();
+            }
+            manager.lock()?.finish(job_id, None, now_ms())?;
+        }
+    }
+    Ok(())
+}
+
+/// A job's process and everything it started: its process group on Unix,
+/// a job object on Windows
+struct ProcessTree {
+    #[cfg(unix)]
+    group: Option<u32>,
+    #[cfg(windows)]
+    job: Option<windows_sys::Win32::Foundation::HANDLE>,
+}
+
+impl ProcessTree {
+    #[cfg(unix)]
+    fn new(child: &tokio::process::Child) -> Self {
+        // The job's process leads its group, so the group ID is its PID
+        Self { group: child.id() }
+    }
+
+    #[cfg(windows)]
+    fn new(child: &tokio::process::Child) -> Self {
+        use windows_sys::Win32::Foundation::CloseHandle;
+        use windows_sys::Win32::System::JobObjects::{
+            AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+            SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+            JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+        };
+
+        let Some(process) = child.raw_handle() else {
+            return Self { job: None };
+        };
+        // SAFETY: the handles are valid for the calls, and the job handle is
+        // closed on failure here or when the tree is dropped
+        unsafe {
+            let job = CreateJobObjectW(std::ptr::null(), std::ptr::null());
+            if job == 0 {
+                return Self { job: None };
+            }
+            let mut limits: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = std::mem::zeroed();
+            limits.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            SetInformationJobObject(
+                job,
+                JobObjectExtendedLimitInformation,
+                &limits as *const _ as *const std::ffi::c_void,
+                std::mem::size_of::<JOBOBJECT_EXTENDED_LIMIT_INFORMATION>() as u32,
+            );
+            if AssignProcessToJobObject(job, process as _) == 0 {
+                CloseHandle(job);
+                return Self { job: None };
+            }
+            Self { job: Some(job) }
+        }
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn new(_child: &tokio::process::Child) -> Self {
+        Self {}
+    }
+
+    /// Kills every process in the tree
+    fn kill(&self) {
+        #[cfg(unix)]
+        if let Some(group) = self.group {
+            // SAFETY: killpg has no memory safety requirements
+            unsafe {
+                libc::killpg(group as libc::pid_t, libc::SIGKILL);
+            }
+        }
+        #[cfg(windows)]
+        if let Some(job) = self.job {
+            // SAFETY: the handle stays open until the tree is dropped
+            unsafe {
+                windows_sys::Win32::System::JobObjects::TerminateJobObject(job, 1);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        if let Some(job) = self.job {
+            // SAFETY: the handle was opened by `new` and isn't used after this
+            unsafe {
+                windows_sys::Win32::Foundation::CloseHandle(job);
+            }
+        }
+    }
+}
+
+/// Records each line read from a job's pipe and forwards it to the frontend
+async fn capture_output(
+    app: AppHandle,
+    manager: Arc<Mutex<JobManager>>,
+    job_id: String,
+    stream: OutputStream,
+    pipe: impl AsyncRead + Unpin,
+) {
+    let mut reader = BufReader::new(pipe);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&buf)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_owned();
+                let line = match manager.lock() {
+                    Ok(mut manager) => manager.append_output(&job_id, stream, text, now_ms()),
+                    Err(_) => break,
+                };
+                if let Ok(line) = line {
+                    let event = JobOutput {
+                        job_id: job_id.clone(),
+                        line,
+                    };
+                    if let Err(e) = app.emit_all("job-output", event) {
+                        eprintln!("Failed to emit job-output for job {job_id}: {e}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tells the frontend a job's status changed
+fn emit_job_updated(app: &AppHandle, manager: &Mutex<JobManager>, job_id: &str) {
+    let job = match manager.lock() {
+        Ok(manager) => manager.get(job_id).cloned(),
+        Err(_) => return,
+    };
+    if let Some(job) = job {
+        if let Err(e) = app.emit_all("job-updated", job) {
+            eprintln!("Failed to emit job-updated for job {job_id}: {e}");
+        }
+    }
+}
+
+/// Lists background jobs, oldest first
+#[tauri::command]
+fn list_jobs(jobs: State<JobsState>) -> Result<Vec<Job>, String> {
+    Ok(jobs
+        .manager
+        .lock()
+        .map_err(|e| PtyError::from(e).to_string())?
+        .list()
+        .to_vec())
+}
+
+/// Gets a job's captured output, starting at line `from_line`
+#[tauri::command]
+fn get_job_output(
+    job_id: String,
+    from_line: Option<usize>,
+    jobs: State<JobsState>,
+) -> Result<Vec<JobOutputLine>, String> {
+    get_job_output_inner(job_id, from_line, jobs).map_err(|e| e.to_string())
+}
+
+fn get_job_output_inner(
+    job_id: String,
+    from_line: Option<usize>,
+    jobs: State<JobsState>,
+) -> Result<Vec<JobOutputLine>, PtyError> {
+    Ok(jobs
+        .manager
+        .lock()?
+        .output(&job_id, from_line.unwrap_or(0))?)
+}
+
+/// Stops a queued or running job
+#[tauri::command]
+fn cancel_job(job_id: String, app: AppHandle, jobs: State<JobsState>) -> Result<(), String> {
+    cancel_job_inner(job_id, app, jobs).map_err(|e| e.to_string())
+}
+
+fn cancel_job_inner(
+    job_id: String,
+    app: AppHandle,
+    jobs: State<JobsState>,
+) -> Result<(), PtyError> {
+    jobs.manager.lock()?.cancel(&job_id, now_ms())?;
+    if let Some(cancel) = jobs.cancels.lock()?.remove(&job_id) {
+        let _ = cancel.send(());
+    }
+    emit_job_updated(&app, &jobs.manager, &job_id);
+    Ok(())
+}
+
+/// Runs the `from` command of one of a button's template variables and
+/// returns its output lines as choices. The command is taken from the
+/// button, so only commands the user configured or trusted ever run.
+#[tauri::command]
+async fn load_choices(
+    button_id: String,
+    variable: String,
+    active_tab_id: Option<String>,
+    refresh: bool,
+    state: State<'_, TabsState>,
+    config: State<'_, ConfigState>,
+    choices: State<'_, ChoicesState>,
+) -> Result<Vec<String>, String> {
+    load_choices_inner(
+        button_id,
+        variable,
+        active_tab_id,
+        refresh,
+        state,
+        config,
+        choices,
+    )
+    .await
+    .map_err(|e| e.to_string())
+}
+
+async fn load_choices_inner(
+    button_id: String,
+    variable: String,
+    active_tab_id: Option<String>,
+    refresh: bool,
+    state: State<'_, TabsState>,
+    config: State<'_, ConfigState>,
+    choices: State<'_, ChoicesState>,
+) -> Result<Vec<String>, PtyError> {
+    let button = find_button(&config, &state, &button_id, active_tab_id.as_deref())?;
+    let command = button
+        .variables()?
+        .into_iter()
+        .find(|v| v.name == variable)
+        .and_then(|v| v.from)
+        .ok_or(PtyError::NoChoiceCommand(variable))?;
+    // The choices are loaded before the user fills anything in, so env values
+    // that need variables without defaults are left out
+    let env = button
+        .env
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = Template::parse(value).ok()?.render(&HashMap::new()).ok()?;
+            Some((name.clone(), value))
+        })
+        .collect();
+    let profile = config.state.lock()?.button_profile(&button, None, env)?;
+    let cwd = profile.cwd.clone();
+    {
+        let mut cache = choices.cache.lock()?;
+        if refresh {
+            cache.invalidate(&command, cwd.as_deref());
+        } else if let Some(cached) = cache.get(&command, cwd.as_deref(), Instant::now()) {
+            return Ok(cached.to_vec());
+        }
+    }
+
+    let failed = |message: String| PtyError::ChoiceCommand {
+        command: command.clone(),
+        message,
+    };
+    let output = tokio::time::timeout(
+        CHOICE_COMMAND_TIMEOUT,
+        job_command(&command, &profile)
+            .stdin(Stdio::null())
+            .kill_on_drop(true)
+            .output(),
+    )
+    .await
+    .map_err(|_| {
+        failed(format!(
+            "timed out after {}s",
+            CHOICE_COMMAND_TIMEOUT.as_secs()
+        ))
+    })??;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty())
+            .map(str::to_owned)
+            .unwrap_or_else(|| output.status.to_string());
+        return Err(failed(message));
+    }
+
+    let parsed = termos::parse_choice_output(&String::from_utf8_lossy(&output.stdout));
+    if parsed.is_empty() {
+        return Err(failed("no output".to_string()));
+    }
+    choices
+        .cache
+        .lock()?
+        .insert(command, cwd, parsed.clone(), Instant::now());
+    Ok(parsed)
+}
+
+#[tauri::command]
+fn load_config(config: State<ConfigState>) -> Result<AppState, String> {
+    load_config_inner(config).map_err(|e| e.to_string())
+}
+
+fn load_config_inner(config: State<ConfigState>) -> Result<AppState, PtyError> {
+    let loaded = read_config(&config.path, &config.usage_path)?;
+    let mut state = config.state.lock()?;
+    *state = loaded.clone();
+    config.loaded.store(true, Ordering::SeqCst);
+    Ok(loaded)
+}
+
+/// Replaces a config file that can't be parsed with the defaults. The
+/// broken file is kept next to it with a `.bak` extension.
+#[tauri::command]
+fn reset_config(app: AppHandle, config: State<ConfigState>) -> Result<AppState, String> {
+    reset_config_inner(app, config).map_err(|e| e.to_string())
+}
+
+fn reset_config_inner(app: AppHandle, config: State<ConfigState>) -> Result<AppState, PtyError> {
+    if config.path.exists() {
+        std::fs::rename(&config.path, config.path.with_extension("toml.bak"))?;
+    }
+    let defaults = AppState::default();
+    let mut state = config.state.lock()?;
+    let diff = state.diff(&defaults);
+    *state = defaults.clone();
+    config.loaded.store(true, Ordering::SeqCst);
+    write_config(&config, &state)?;
+    if diff.terminal_config_changed {
+        emit_terminal_config(&app, &defaults.terminal_config);
+    }
+    if let Err(e) = app.emit_all(
+        "config-changed",
+        ConfigChanged {
+            diff,
+            state: defaults.clone(),
+        },
+    ) {
+        eprintln!("Failed to emit config-changed: {e}");
+    }
+    Ok(defaults)
+}
+
+#[tauri::command]
+fn get_config_path(config: State<ConfigState>) -> String {
+    config.path.display().to_string()
+}
+
+#[tauri::command]
+fn export_config(
+    path: String,
+    sections: Vec<BundleSection>,
+    config: State<ConfigState>,
+) -> Result<(), String> {
+    export_config_inner(path, sections, config).map_err(|e| e.to_string())
+}
+
+fn export_config_inner(
+    path: String,
+    sections: Vec<BundleSection>,
+    config: State<ConfigState>,
+) -> Result<(), PtyError> {
+    let bundle = config.state.lock()?.export_bundle(&sections);
+    std::fs::write(path, bundle.to_json()?)?;
+    Ok(())
+}
+
+#[tauri::command]
+fn preview_import(path: String, config: State<ConfigState>) -> Result<Vec<ImportItem>, String> {
+    preview_import_inner(path, config).map_err(|e| e.to_string())
+}
+
+fn preview_import_inner(
+    path: String,
+    config: State<ConfigState>,
+) -> Result<Vec<ImportItem>, PtyError> {
+    let bundle = ConfigBundle::from_json(&std::fs::read_to_string(path)?)?;
+    let state = config.state.lock()?;
+    Ok(state.preview_import(&bundle))
+}
+
+#[tauri::command]
+fn apply_import(
+    path: String,
+    resolutions: Vec<ImportResolution>,
+    app: AppHandle,
+    config: State<ConfigState>,
+) -> Result<ImportSummary, String> {
+    apply_import_inner(path, resolutions, app, config).map_err(|e| e.to_string())
+}
+
+fn apply_import_inner(
+    path: String,
+    resolutions: Vec<ImportResolution>,
+    app: AppHandle,
+    config: State<ConfigState>,
+) -> Result<ImportSummary, PtyError> {
+    let bundle = ConfigBundle::from_json(&std::fs::read_to_string(path)?)?;
+    let mut state = config.state.lock()?;
+    let previous = state.terminal_config.clone();
+    let summary = state.apply_import(&bundle, &resolutions)?;
+    write_config(&config, &state)?;
+    if state.terminal_config != previous {
+        emit_terminal_config(&app, &state.terminal_config);
+    }
+    Ok(summary)
+}
+
+fn main() {
+    tauri::Builder::default()
+        .setup(|app| {
+            app.manage(TabsState {
+                tabs: Arc::new(Mutex::new(HashMap::new())),
+                next_tab_num: AtomicU32::new(1),
+                named_tabs: Mutex::new(HashMap::new()),
+            });
+
+            app.manage(ChoicesState {
+                cache: Mutex::new(ChoiceCache::new(CHOICE_CACHE_TTL)),
+            });
+
+            app.manage(DiscoveryState {
+                cache: Mutex::new(DiscoveryCache::new()),
+            });
+
+            app.manage(JobsState {
+                manager: Arc::new(Mutex::new(JobManager::new())),
+                cancels: Arc::new(Mutex::new(HashMap::new())),
+            });
+
+            let path = config_path();
+            let usage_path = usage_path();
+            let (initial, startup_error) = match read_config(&path, &usage_path) {
+                Ok(initial) => (initial, None),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to load config {}: {e}, using defaults until it is fixed",
+                        path.display()
+                    );
+                    (AppState::default(), Some(ConfigError::from(&e)))
+                }
+            };
+            let loaded = Arc::new(AtomicBool::new(startup_error.is_none()));
+            let state = Arc::new(Mutex::new(initial));
+            watch_config(
+                app.handle(),
+                path.clone(),
+                usage_path.clone(),
+                state.clone(),
+                loaded.clone(),
+            );
+            let tabs = app.state::<TabsState>().tabs.clone();
+            watch_tabs(app.handle(), tabs, state.clone());
+            app.manage(ConfigState {
+                path,
+                usage_path,
+                state,
+                loaded,
+                startup_error: Mutex::new(startup_error),
+            });
+
+            Ok(())
+        })
+        .on_page_load(|window, _| {
+            // The frontend can only hear about a broken config file once
+            // its page has loaded
+            let config = window.state::<ConfigState>();
+            let startup_error = match config.startup_error.lock() {
+                Ok(mut error) => error.take(),
+                Err(_) => {
+                    eprintln!("Failed to acquire lock for config error");
+                    None
+                }
+            };
+            if let Some(error) = startup_error {
+                if let Err(e) = window.emit("config-error", error) {
+                    eprintln!("Failed to emit config-error: {e}");
+                }
+            }
         })
         .invoke_handler(tauri::generate_handler![
             create_tab,
             close_tab,
             send_to_tab,
+            review_button,
+            review_command,
+            run_command,
+            run_button,
+            get_tab_project,
+            search_tab,
+            search_all_tabs,
+            find_links,
+            open_path,
+            trust_project,
+            discover_commands,
+            adopt_command,
             resize_terminal,
             load_buttons,
-            save_buttons
+            save_buttons,
+            record_button_use,
+            search_palette,
+            get_keybindings,
+            set_button_accelerator,
+            list_themes,
+            set_theme,
+            update_terminal_config,
+            import_theme,
+            export_theme,
+            button_prompt,
+            record_button_values,
+            parse_template,
+            render_template,
+            load_choices,
+            list_jobs,
+            get_job_output,
+            cancel_job,
+            load_config,
+            reset_config,
+            get_config_path,
+            export_config,
+            preview_import,
+            apply_import
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");